@@ -1,51 +1,566 @@
+use crate::auth::lockout::{
+    clear_failures, describe_lock, lock_message, lock_state, record_failure, LockState,
+};
+use crate::auth::roles::{role_permissions, ADMIN_ROLE};
+use crate::auth::totp::{check_second_factor, enroll};
+use crate::console_println;
 use crate::file_manager::file_manager::audit_handler::write_audit_event;
-use crate::file_manager::file_manager::user_file_handler;
+use crate::file_manager::file_manager::user_file_handler::{self, DEFAULT_USERS};
+use crate::menu::console::remote_prompt;
 use crate::structs::soc_structs::multithread::FileMutexes;
-use crate::structs::soc_structs::AuditEventType;
-use futures::lock::Mutex;
-use pnet::packet::MutPacketData;
+use crate::structs::soc_structs::{
+    AuditEventType, OperatorAuth, PasswordPolicy, Permission, UserAccount,
+};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Local;
+use clap::ArgMatches;
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
 use sha2::{Digest, Sha256};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::mem;
+
+// attempts to enter new password when temporary or default one must be changed
+const PASSWORD_CHANGE_ATTEMPTS: u32 = 3;
+const TEMPORARY_PASSWORD_LENGTH: usize = 12;
+
+// Argon2id with random salt in PHC string format: "$argon2id$v=19$m=...,t=...,p=...$salt$hash".
+// Parameters and salt are stored with the hash, so they can be changed later without breaking old hashes
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).expect("Salt encoding error.");
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Password hashing error.")
+        .to_string()
+}
+
+// users files of old versions keep unsalted SHA-256 in hex
+fn is_legacy_hash(pass_hash: &str) -> bool {
+    pass_hash.len() == 64 && pass_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn verify_password(password: &str, pass_hash: &str) -> bool {
+    if is_legacy_hash(pass_hash) {
+        return format!("{:x}", Sha256::digest(password.as_bytes())) == pass_hash.to_lowercase();
+    }
+
+    match PasswordHash::new(pass_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => {
+            console_println!("Wrong password hash format in user file");
+            false
+        }
+    }
+}
+
+// given to new users and on password reset, user must change it on the first logon
+pub fn temporary_password() -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(TEMPORARY_PASSWORD_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn is_default_password(username: &str, password: &str) -> bool {
+    DEFAULT_USERS
+        .iter()
+        .any(|(name, default_password, _)| *name == username && *default_password == password)
+}
+
+// Input is hidden on terminal. Piped input is read as a plain line, so scripts keep working.
+// None - input is closed
+pub fn read_secret(prompt: &str) -> Option<String> {
+    // remote console client hides input itself
+    if let Some(secret) = remote_prompt(prompt, true) {
+        return secret.map(|secret| secret.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password(prompt).ok();
+    }
+
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+    let mut secret = String::new();
+    match io::stdin().read_line(&mut secret) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(secret.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
+// Password from command line is seen by other users in process list and stays in shell history,
+// so it is only kept for old scripts. None - password can't be read.
+// Arguments: "password" (optional), "password_env" and "password_fd"
+pub fn logon_password(matches: &ArgMatches, prompt: &str) -> Option<String> {
+    if let Some(password) = matches.try_get_one::<String>("password").ok().flatten() {
+        console_println!("Warning: password in command line can be seen by other users. Use prompt, --password-env or --password-fd instead.");
+        return Some(password.clone());
+    }
+
+    if let Some(variable) = matches.get_one::<String>("password_env") {
+        return match std::env::var(variable) {
+            Ok(password) => Some(password),
+            Err(e) => {
+                console_println!("Can't read password from {}: {}", variable, e);
+                None
+            }
+        };
+    }
+
+    if let Some(fd) = matches.get_one::<u32>("password_fd") {
+        let mut password = String::new();
+        return match File::open(format!("/dev/fd/{}", fd))
+            .and_then(|file| BufReader::new(file).read_line(&mut password))
+        {
+            Ok(_) => Some(password.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => {
+                console_println!("Can't read password from descriptor {}: {}", fd, e);
+                None
+            }
+        };
+    }
+
+    read_secret(prompt)
+}
+
+// Err - requirement which is not met
+pub fn check_password_policy(password: &str, policy: &PasswordPolicy) -> Result<(), String> {
+    let min_length = policy.min_length.max(1);
+    if password.chars().count() < min_length {
+        return Err(format!("password must be at least {} characters long", min_length));
+    }
+
+    let classes = [
+        password.chars().any(char::is_lowercase),
+        password.chars().any(char::is_uppercase),
+        password.chars().any(char::is_numeric),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < policy.classes {
+        return Err(format!(
+            "password must contain at least {} of: lowercase letters, uppercase letters, digits, other symbols",
+            policy.classes
+        ));
+    }
+
+    Ok(())
+}
+
+// passwords of older users files have no change time, they are counted from the next logon
+pub fn password_expired(user: &UserAccount, policy: &PasswordPolicy) -> bool {
+    policy.expiry_days > 0
+        && user.password_changed != 0
+        && Local::now().timestamp() - user.password_changed > policy.expiry_days * 24 * 3600
+}
+
+pub fn account_expired(user: &UserAccount) -> bool {
+    user.expires != 0 && Local::now().timestamp() >= user.expires
+}
+
+// time of logon or failed one is kept in users file, unknown users have nowhere to keep it
+fn record_logon_time(users_file: &str, username: &str, success: bool, file_mutexes: &FileMutexes) {
+    let now = Local::now().timestamp();
+    user_file_handler::update_user(users_file, file_mutexes, username, |user| {
+        if success {
+            user.last_logon = now;
+        } else {
+            user.last_failed_logon = now;
+        }
+    });
+}
+
+// current password and policy.history previous ones
+fn is_reused(password: &str, user: &UserAccount, policy: &PasswordPolicy) -> bool {
+    verify_password(password, &user.pass_hash)
+        || user
+            .password_history
+            .iter()
+            .take(policy.history)
+            .any(|pass_hash| verify_password(password, pass_hash))
+}
+
+// Asks for new password twice. None - user failed to enter acceptable one.
+pub fn read_new_password(user: &UserAccount, policy: &PasswordPolicy) -> Option<String> {
+    for _ in 0..PASSWORD_CHANGE_ATTEMPTS {
+        let password = read_secret("New password: ")?;
+        let repeated = read_secret("Repeat new password: ")?;
+
+        if password != repeated {
+            console_println!("Passwords don't match. Try again.");
+        } else if let Err(reason) = check_password_policy(&password, policy) {
+            console_println!("Weak password: {}. Try again.", reason);
+        } else if is_default_password(&user.name, &password) || is_reused(&password, user, policy) {
+            console_println!(
+                "New password must differ from the current one and {} previous ones. Try again.",
+                policy.history
+            );
+        } else {
+            return Some(password);
+        }
+    }
+
+    None
+}
+
+// Previous hash goes to password history, which is kept as long as policy requires.
+pub fn set_new_password(
+    users_file: &str,
+    username: &str,
+    password: &str,
+    policy: &PasswordPolicy,
+    file_mutexes: &FileMutexes,
+) -> bool {
+    let pass_hash = hash_password(password);
+
+    user_file_handler::update_user(users_file, file_mutexes, username, |user| {
+        let previous = mem::replace(&mut user.pass_hash, pass_hash);
+        user.password_history.insert(0, previous);
+        user.password_history.truncate(policy.history);
+        user.must_change_password = false;
+        user.password_changed = Local::now().timestamp();
+    })
+}
 
 pub fn authenticate(
-    username: &String,
-    password: &String,
-    users_file: &String,
+    username: &str,
+    password: &str,
+    operator_auth: &OperatorAuth,
     host: String,
     file_mutexes: &FileMutexes,
     log_file: String,
     audit_status: bool,
-) -> (bool, String, bool) {
+) -> (bool, String, String) {
+    let (users_file, lockout_file, policy) = (
+        &operator_auth.users_file,
+        &operator_auth.lockout_file,
+        &operator_auth.policy,
+    );
     let user_map = user_file_handler::get_user_map(users_file);
-    let mut hasher = Sha256::new();
-    hasher.update(password);
-    let pass_hash = hasher.finalize();
 
-    if !user_map.contains_key(username) || user_map[username].0 != format!("{:x}", pass_hash) {
+    if user_map.contains_key(username) {
+        match lock_state(lockout_file, username, file_mutexes) {
+            LockState::Locked(locked_until) => {
+                write_audit_event(
+                    host,
+                    username.to_string(),
+                    AuditEventType::FailLogon,
+                    format!("Account is locked {}", describe_lock(locked_until)),
+                    file_mutexes,
+                    &log_file,
+                    audit_status,
+                );
+                console_println!("Account is locked {}. Goodbye.", describe_lock(locked_until));
+                return (false, "".to_string(), "".to_string());
+            }
+            LockState::Expired => {
+                write_audit_event(
+                    host.clone(),
+                    username.to_string(),
+                    AuditEventType::UserUnlock,
+                    "Lockout period is over. Target account - ".to_string() + username,
+                    file_mutexes,
+                    &log_file,
+                    audit_status,
+                );
+            }
+            LockState::Unlocked => {}
+        }
+    }
+
+    let verified = match user_map.get(username) {
+        Some(user) => verify_password(password, &user.pass_hash),
+        None => {
+            // unknown user takes as long as the known one
+            let _ = hash_password(password);
+            false
+        }
+    };
+
+    if !verified {
         write_audit_event(
-            SystemTime::now(),
-            host,
-            username.clone(),
+            host.clone(),
+            username.to_string(),
             AuditEventType::FailLogon,
             "Authentication failure".to_string(),
-            &file_mutexes,
+            file_mutexes,
+            &log_file,
+            audit_status,
+        );
+
+        // unknown users are not counted, otherwise anyone could fill the file
+        let locked = if user_map.contains_key(username) {
+            record_logon_time(users_file, username, false, file_mutexes);
+            record_failure(lockout_file, username, &policy.lockout, file_mutexes)
+        } else {
+            None
+        };
+        if let Some(locked_until) = locked {
+            write_audit_event(
+                host,
+                username.to_string(),
+                AuditEventType::UserLock,
+                lock_message(&policy.lockout, locked_until, username),
+                file_mutexes,
+                &log_file,
+                audit_status,
+            );
+        }
+        console_println!("Wrong credentials. Goodbye.");
+        return (false, "".to_string(), "".to_string());
+    }
+
+    let user = &user_map[username];
+    if user.disabled {
+        write_audit_event(
+            host,
+            username.to_string(),
+            AuditEventType::FailLogon,
+            "User account is disabled".to_string(),
+            file_mutexes,
+            &log_file,
+            audit_status,
+        );
+        console_println!("User account is disabled. Goodbye.");
+        return (false, "".to_string(), "".to_string());
+    }
+    if account_expired(user) {
+        write_audit_event(
+            host,
+            username.to_string(),
+            AuditEventType::FailLogon,
+            "User account is expired".to_string(),
+            file_mutexes,
+            &log_file,
+            audit_status,
+        );
+        console_println!("User account is expired. Goodbye.");
+        return (false, "".to_string(), "".to_string());
+    }
+
+    // wrong code counts as failed logon: password alone must not be enough to guess it
+    if !user.totp_secret.is_empty() {
+        let result = match read_secret("Authentication code or recovery code: ") {
+            Some(input) => check_second_factor(users_file, file_mutexes, username, &input),
+            None => Err("code is not entered".to_string()),
+        };
+        match result {
+            Ok(message) => {
+                write_audit_event(
+                    host.clone(),
+                    username.to_string(),
+                    AuditEventType::MfaSuccess,
+                    message,
+                    file_mutexes,
+                    &log_file,
+                    audit_status,
+                );
+            }
+            Err(reason) => {
+                write_audit_event(
+                    host.clone(),
+                    username.to_string(),
+                    AuditEventType::MfaFail,
+                    format!("Second factor failure: {}", reason),
+                    file_mutexes,
+                    &log_file,
+                    audit_status,
+                );
+                record_logon_time(users_file, username, false, file_mutexes);
+                if let Some(locked_until) = record_failure(lockout_file, username, &policy.lockout, file_mutexes) {
+                    write_audit_event(
+                        host,
+                        username.to_string(),
+                        AuditEventType::UserLock,
+                        lock_message(&policy.lockout, locked_until, username),
+                        file_mutexes,
+                        &log_file,
+                        audit_status,
+                    );
+                }
+                console_println!("Wrong authentication code. Goodbye.");
+                return (false, "".to_string(), "".to_string());
+            }
+        }
+    } else if policy.totp_required && user.role == ADMIN_ROLE {
+        console_println!(
+            "Two-factor authentication is required for {} role. It must be set up before work.",
+            ADMIN_ROLE
+        );
+        let enrolled = enroll(users_file, file_mutexes, username, &host);
+        write_audit_event(
+            host.clone(),
+            username.to_string(),
+            if enrolled {
+                AuditEventType::MfaEnroll
+            } else {
+                AuditEventType::MfaFail
+            },
+            if enrolled {
+                "Second factor set up on logon".to_string()
+            } else {
+                "Second factor is not set up".to_string()
+            },
+            file_mutexes,
             &log_file,
             audit_status,
         );
-        println!("Wrong credentials. Goodbye.");
-        return (false, "".to_string(), false);
+        if !enrolled {
+            console_println!("Two-factor authentication is not set up. Goodbye.");
+            return (false, "".to_string(), "".to_string());
+        }
     }
 
+    clear_failures(lockout_file, username, file_mutexes);
+    let mut logon_message = "User authenticated".to_string();
+    let mut new_password = None;
+
+    let change_reason = if user.must_change_password || is_default_password(username, password) {
+        Some("Temporary or default")
+    } else if password_expired(user, &policy.password) {
+        Some("Expired")
+    } else {
+        None
+    };
+
+    if let Some(reason) = change_reason {
+        console_println!(
+            "{} password of user {}. It must be changed before work.",
+            reason, username
+        );
+        new_password = read_new_password(user, &policy.password);
+
+        if new_password.is_none() {
+            write_audit_event(
+                host,
+                username.to_string(),
+                AuditEventType::FailLogon,
+                format!("{} password is not changed", reason),
+                file_mutexes,
+                &log_file,
+                audit_status,
+            );
+            console_println!("Password is not changed. Goodbye.");
+            return (false, "".to_string(), "".to_string());
+        }
+    } else if is_legacy_hash(&user.pass_hash) || user.password_changed == 0 {
+        // the only moment when plain password is known, so old hash is replaced right now
+        let upgrade = is_legacy_hash(&user.pass_hash);
+        let pass_hash = if upgrade {
+            hash_password(password)
+        } else {
+            user.pass_hash.clone()
+        };
+        let saved = user_file_handler::update_user(users_file, file_mutexes, username, |user| {
+            user.pass_hash = pass_hash;
+            if user.password_changed == 0 {
+                user.password_changed = Local::now().timestamp();
+            }
+        });
+        if saved && upgrade {
+            logon_message += ". Password hash upgraded to Argon2id";
+        }
+    }
+
+    record_logon_time(users_file, username, true, file_mutexes);
     write_audit_event(
-        SystemTime::now(),
-        host,
-        username.clone(),
+        host.clone(),
+        username.to_string(),
         AuditEventType::UserLogon,
-        "User authenticated".to_string(),
-        &file_mutexes,
+        logon_message,
+        file_mutexes,
         &log_file,
         audit_status,
     );
-    (true, username.to_string(), user_map[username].1)
+
+    if let (Some(new_password), Some(reason)) = (new_password, change_reason) {
+        if !set_new_password(users_file, username, &new_password, &policy.password, file_mutexes) {
+            console_println!("Password is not changed. Goodbye.");
+            return (false, "".to_string(), "".to_string());
+        }
+        write_audit_event(
+            host,
+            username.to_string(),
+            AuditEventType::PassChange,
+            format!("{} password changed", reason),
+            file_mutexes,
+            &log_file,
+            audit_status,
+        );
+        console_println!("Password changed.");
+    }
+
+    (true, username.to_string(), user.role.clone())
+}
+
+// Operator from sensor init message. Failures are counted like console ones, second factor is
+// not asked: sensors work unattended. Err - (reason, locked_until if the account has just been locked)
+pub fn authenticate_operator(
+    username: &str,
+    password: &str,
+    operator_auth: &OperatorAuth,
+    file_mutexes: &FileMutexes,
+) -> Result<(), (String, Option<i64>)> {
+    let user = user_file_handler::load_users(&operator_auth.users_file)
+        .into_iter()
+        .find(|user| user.name == username);
+
+    if user.is_some() {
+        if let LockState::Locked(locked_until) = lock_state(&operator_auth.lockout_file, username, file_mutexes) {
+            return Err((format!("account is locked {}", describe_lock(locked_until)), None));
+        }
+    }
+
+    let user = match user {
+        Some(user) if verify_password(password, &user.pass_hash) => user,
+        Some(_) => {
+            record_logon_time(&operator_auth.users_file, username, false, file_mutexes);
+            let locked = record_failure(&operator_auth.lockout_file, username, &operator_auth.policy.lockout, file_mutexes);
+            return Err(("wrong operator name or password".to_string(), locked));
+        }
+        None => {
+            // unknown user takes as long as the known one
+            let _ = hash_password(password);
+            return Err(("wrong operator name or password".to_string(), None));
+        }
+    };
+
+    if user.disabled {
+        return Err(("account is disabled".to_string(), None));
+    }
+    if account_expired(&user) {
+        return Err(("account is expired".to_string(), None));
+    }
+    // sensor can't enter a code, the password alone must not open such an account
+    if !user.totp_secret.is_empty() || (operator_auth.policy.totp_required && user.role == ADMIN_ROLE) {
+        return Err((
+            "account requires second factor, it can't be used by sensors".to_string(),
+            None,
+        ));
+    }
+    if !role_permissions(&operator_auth.roles_file, &user.role).contains(&Permission::SensorOperator) {
+        return Err((
+            format!("role {} has no '{}' permission", user.role, Permission::SensorOperator),
+            None,
+        ));
+    }
+    // sensor can't change password itself
+    if user.must_change_password || password_expired(&user, &operator_auth.policy.password) {
+        return Err((
+            "password must be changed in management server console first".to_string(),
+            None,
+        ));
+    }
+
+    clear_failures(&operator_auth.lockout_file, username, file_mutexes);
+    record_logon_time(&operator_auth.users_file, username, true, file_mutexes);
+    Ok(())
 }
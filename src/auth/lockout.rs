@@ -0,0 +1,130 @@
+use chrono::{DateTime, Local};
+
+use crate::console_println;
+use crate::file_manager::file_manager::lockout_handler::{load_lockouts, save_lockouts};
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{LockoutPolicy, LogonFailures};
+
+pub enum LockState {
+    Unlocked,
+    // locked_until as in LogonFailures
+    Locked(i64),
+    // lock is over and removed right now
+    Expired,
+}
+
+fn is_locked(entry: &LogonFailures, now: i64) -> bool {
+    entry.locked_until == -1 || entry.locked_until > now
+}
+
+pub fn describe_lock(locked_until: i64) -> String {
+    if locked_until == -1 {
+        return "until admin unlocks it".to_string();
+    }
+
+    match DateTime::from_timestamp(locked_until, 0) {
+        Some(time) => format!(
+            "until {}",
+            time.with_timezone(&Local).format("%d-%m-%Y %H:%M:%S")
+        ),
+        None => format!("until {}", locked_until),
+    }
+}
+
+// audit message of UserLock
+pub fn lock_message(policy: &LockoutPolicy, locked_until: i64, username: &str) -> String {
+    format!(
+        "Account locked after {} failed logons within {} seconds, {}. Target account - {}",
+        policy.attempts,
+        policy.window_secs,
+        describe_lock(locked_until),
+        username
+    )
+}
+
+pub fn lock_state(lockout_file: &str, username: &str, file_mutexes: &FileMutexes) -> LockState {
+    let now = Local::now().timestamp();
+    let _lockout_lock = file_mutexes.lockout_mutex.lock().unwrap();
+    let mut lockouts = load_lockouts(lockout_file);
+
+    let (locked_until, locked) = match lockouts.iter().find(|entry| entry.user == username) {
+        Some(entry) if entry.locked_until != 0 => (entry.locked_until, is_locked(entry, now)),
+        _ => return LockState::Unlocked,
+    };
+    if locked {
+        return LockState::Locked(locked_until);
+    }
+
+    lockouts.retain(|entry| entry.user != username);
+    save_lockouts(lockout_file, &lockouts);
+    LockState::Expired
+}
+
+// Counts failed logon of existing user.
+// Returns locked_until if the account has just been locked.
+pub fn record_failure(
+    lockout_file: &str,
+    username: &str,
+    policy: &LockoutPolicy,
+    file_mutexes: &FileMutexes,
+) -> Option<i64> {
+    if policy.attempts == 0 {
+        return None;
+    }
+
+    let now = Local::now().timestamp();
+    let _lockout_lock = file_mutexes.lockout_mutex.lock().unwrap();
+    let mut lockouts = load_lockouts(lockout_file);
+    let index = match lockouts.iter().position(|entry| entry.user == username) {
+        Some(index) => index,
+        None => {
+            lockouts.push(LogonFailures {
+                user: username.to_string(),
+                failures: 0,
+                first_failure: now,
+                locked_until: 0,
+            });
+            lockouts.len() - 1
+        }
+    };
+
+    let entry = &mut lockouts[index];
+    // failures older than window are forgotten
+    if now - entry.first_failure > policy.window_secs {
+        entry.failures = 0;
+        entry.first_failure = now;
+    }
+    entry.failures += 1;
+
+    let mut locked = None;
+    if entry.failures >= policy.attempts && !is_locked(entry, now) {
+        entry.locked_until = if policy.duration_secs == 0 {
+            -1
+        } else {
+            now + policy.duration_secs
+        };
+        locked = Some(entry.locked_until);
+    }
+
+    if !save_lockouts(lockout_file, &lockouts) {
+        console_println!("Failed logon of {} is not saved", username);
+    }
+    locked
+}
+
+// after successful logon and on unlock by admin.
+// Returns true if the account was locked.
+pub fn clear_failures(lockout_file: &str, username: &str, file_mutexes: &FileMutexes) -> bool {
+    let now = Local::now().timestamp();
+    let _lockout_lock = file_mutexes.lockout_mutex.lock().unwrap();
+    let mut lockouts = load_lockouts(lockout_file);
+
+    let was_locked = match lockouts.iter().find(|entry| entry.user == username) {
+        Some(entry) => is_locked(entry, now),
+        None => return false,
+    };
+    lockouts.retain(|entry| entry.user != username);
+    save_lockouts(lockout_file, &lockouts);
+
+    was_locked
+}
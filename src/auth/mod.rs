@@ -1 +1,8 @@
+// crate::auth::auth::... paths are used across the crate and binaries
+#[allow(clippy::module_inception)]
 pub mod auth;
+pub mod lockout;
+pub mod roles;
+pub mod session;
+pub mod totp;
+pub mod user_manager;
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::auth::account_expired;
+use crate::console_println;
+use crate::file_manager::file_manager::user_file_handler::{get_user_map, load_protected, save_protected};
+use crate::file_manager::file_manager::audit_handler::write_audit_event;
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{AuditEventType, Permission, SessionStatus};
+
+pub const ADMIN_ROLE: &str = "administrator";
+// role of users from old users files without admin flag
+pub const BASIC_ROLE: &str = "analyst";
+// role of sensor accounts, roles files of older versions get it from 'users sign'
+pub const SENSOR_OPERATOR_ROLE: &str = "sensor_operator";
+
+// written to roles file by 'users init', changed by 'users roles --import'
+const DEFAULT_ROLES: [(&str, &[Permission]); 5] = [
+    (ADMIN_ROLE, &Permission::CONSOLE),
+    (
+        "security_auditor",
+        &[
+            Permission::EventsView,
+            Permission::SensorsView,
+            Permission::AuditView,
+            Permission::AuditControl,
+            Permission::RulesView,
+            Permission::UsersView,
+        ],
+    ),
+    (
+        "rule_engineer",
+        &[
+            Permission::EventsView,
+            Permission::SensorsView,
+            Permission::RulesView,
+            Permission::RulesEdit,
+        ],
+    ),
+    (
+        BASIC_ROLE,
+        &[
+            Permission::EventsView,
+            Permission::SensorsView,
+            Permission::RulesView,
+        ],
+    ),
+    // accounts of sensor configs, they can't do anything in console
+    (SENSOR_OPERATOR_ROLE, &[Permission::SensorOperator]),
+];
+
+fn role_line((role, permissions): &(&str, &[Permission])) -> String {
+    let names: Vec<&str> = permissions.iter().map(Permission::name).collect();
+    format!("{}[:|:]{}\n", role, names.join(", "))
+}
+
+pub fn default_roles() -> String {
+    DEFAULT_ROLES.iter().map(role_line).collect()
+}
+
+// role line: role[:|:]permission, permission, ...
+fn parse_role_line(line: &str) -> Result<(String, HashSet<Permission>), String> {
+    let parts: Vec<&str> = line.split("[:|:]").collect();
+    if parts.len() != 2 || parts[0].is_empty() {
+        return Err(format!("Wrong role string format: '{}'", line));
+    }
+
+    let mut permissions: HashSet<Permission> = HashSet::new();
+    for name in parts[1].split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match Permission::from_name(name) {
+            Some(permission) => {
+                permissions.insert(permission);
+            }
+            None => return Err(format!("Unknown permission '{}' of role {}", name, parts[0])),
+        }
+    }
+
+    Ok((parts[0].to_string(), permissions))
+}
+
+// Roles file is protected like users file, changed outside of the server it gives nobody any rights.
+// It is changed only by offline 'users roles --import' command
+pub fn load_roles(roles_file: &str) -> HashMap<String, HashSet<Permission>> {
+    let content = match load_protected(roles_file) {
+        Ok(content) => content,
+        Err(reason) => {
+            console_println!("Roles file {} is not loaded: {}", roles_file, reason);
+            return HashMap::new();
+        }
+    };
+    let mut result: HashMap<String, HashSet<Permission>> = HashMap::new();
+
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        match parse_role_line(line) {
+            Ok((role, permissions)) => {
+                result.insert(role, permissions);
+            }
+            Err(reason) => console_println!("{}", reason),
+        }
+    }
+
+    result
+}
+
+// Offline 'users roles --import': content replaces the whole roles file and is signed.
+// Nothing is saved if any line is wrong or administrator role is lost. Returns number of roles
+pub fn import_roles(roles_file: &str, content: &str) -> Result<usize, String> {
+    let mut roles: HashSet<String> = HashSet::new();
+    for line in content.lines().map(str::trim_end).filter(|line| !line.is_empty()) {
+        let (role, _) = parse_role_line(line)?;
+        if !roles.insert(role.clone()) {
+            return Err(format!("Role {} is defined twice", role));
+        }
+    }
+    if !roles.contains(ADMIN_ROLE) {
+        return Err(format!("Role {} is missing", ADMIN_ROLE));
+    }
+
+    let lines: String = content
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string() + "\n")
+        .collect();
+    if !save_protected(roles_file, &lines) {
+        return Err("roles file can't be saved".to_string());
+    }
+
+    Ok(roles.len())
+}
+
+// Offline 'users sign': roles files of older versions have no role for sensor accounts.
+// Ok(true) - the role is added
+pub fn add_sensor_operator_role(roles_file: &str) -> Result<bool, String> {
+    let mut content = load_protected(roles_file)?;
+    let operator_prefix = format!("{}[:|:]", SENSOR_OPERATOR_ROLE);
+    if content.lines().any(|line| line.starts_with(&operator_prefix)) {
+        return Ok(false);
+    }
+
+    let line = DEFAULT_ROLES
+        .iter()
+        .find(|(role, _)| *role == SENSOR_OPERATOR_ROLE)
+        .map(role_line)
+        .unwrap_or_default();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&line);
+    if !save_protected(roles_file, &content) {
+        return Err("roles file can't be saved".to_string());
+    }
+
+    Ok(true)
+}
+
+// unknown role has no permissions
+pub fn role_permissions(roles_file: &str, role: &str) -> HashSet<Permission> {
+    match load_roles(roles_file).remove(role) {
+        Some(permissions) => permissions,
+        None => {
+            console_println!("Role {} is not defined in roles file, it has no permissions", role);
+            HashSet::new()
+        }
+    }
+}
+
+// Role and account of the session user as they are now: the user could be demoted,
+// disabled or deleted since logon. Err - reason the account can't do anything.
+fn refresh_permissions(session_status: &mut SessionStatus) -> Result<(), String> {
+    let user = get_user_map(&session_status.users_file).remove(&session_status.user);
+    let state = match user {
+        None => Some("deleted"),
+        Some(ref user) if user.disabled => Some("disabled"),
+        Some(ref user) if account_expired(user) => Some("expired"),
+        Some(_) => None,
+    };
+    if let Some(state) = state {
+        session_status.permissions.clear();
+        return Err(format!("account is {}", state));
+    }
+
+    let role = user.map(|user| user.role).unwrap_or_default();
+    if role != session_status.role {
+        console_println!(
+            "Role of {} is changed from {} to {}.",
+            session_status.user, session_status.role, role
+        );
+        session_status.role = role;
+    }
+    session_status.permissions = role_permissions(&session_status.roles_file, &session_status.role);
+    Ok(())
+}
+
+// Denied action is written to audit log.
+pub fn check_permission(
+    session_status: &mut SessionStatus,
+    permission: Permission,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) -> bool {
+    let reason = match refresh_permissions(session_status) {
+        Ok(_) if session_status.permissions.contains(&permission) => return true,
+        Ok(_) => {
+            console_println!(
+                "Permission denied: role {} has no '{}' permission.",
+                session_status.role, permission
+            );
+            format!("Role - {}", session_status.role)
+        }
+        Err(reason) => {
+            console_println!("Permission denied: {}.", reason);
+            reason
+        }
+    };
+
+    let aud_stat = *audit_status.lock().unwrap();
+    write_audit_event(
+        session_status.host.clone(),
+        session_status.user.clone(),
+        AuditEventType::AccessDenied,
+        format!("Access denied. Permission - {}. {}", permission, reason),
+        file_mutexes,
+        log_file,
+        aud_stat,
+    );
+    false
+}
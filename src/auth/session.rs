@@ -0,0 +1,213 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::auth::{read_secret, verify_password};
+use crate::auth::lockout::{
+    clear_failures, describe_lock, lock_message, lock_state, record_failure, LockState,
+};
+use crate::console_println;
+use crate::event_bus::event_bus::ServerEvent;
+use crate::file_manager::file_manager::audit_handler::write_audit_event;
+use crate::file_manager::file_manager::user_file_handler::load_users;
+use crate::protocol::messages::ConsoleOutput;
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{AuditEventType, ConsoleSessions, SessionStatus};
+
+fn write_session_audit(
+    event_type: AuditEventType,
+    text: String,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) {
+    let aud_stat = *audit_status.lock().unwrap();
+    if !write_audit_event(
+        session_status.host.clone(),
+        session_status.user.clone(),
+        event_type,
+        text,
+        file_mutexes,
+        log_file,
+        aud_stat,
+    ) {
+        console_println!("Error occured with audit logging.");
+    }
+}
+
+// Password of session user, failures are counted as failed logons.
+// Password is read before lock check, so locked account doesn't spin the unlock loop.
+// None - input is closed
+fn confirm_password(
+    session_status: &SessionStatus,
+    purpose: &str,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) -> Option<bool> {
+    let password = read_secret(&format!("Password of {}: ", session_status.user))?;
+
+    match lock_state(&session_status.lockout_file, &session_status.user, file_mutexes) {
+        LockState::Locked(locked_until) => {
+            write_session_audit(
+                AuditEventType::FailLogon,
+                format!("Account is locked {} on {}", describe_lock(locked_until), purpose),
+                session_status,
+                file_mutexes,
+                log_file,
+                audit_status,
+            );
+            console_println!("Account is locked {}.", describe_lock(locked_until));
+            return Some(false);
+        }
+        LockState::Expired => write_session_audit(
+            AuditEventType::UserUnlock,
+            "Lockout period is over. Target account - ".to_string() + &session_status.user,
+            session_status,
+            file_mutexes,
+            log_file,
+            audit_status,
+        ),
+        LockState::Unlocked => {}
+    }
+
+    // account may be disabled or deleted by another admin while session is open
+    let verified = load_users(&session_status.users_file).iter().any(|user| {
+        user.name == session_status.user
+            && !user.disabled
+            && verify_password(&password, &user.pass_hash)
+    });
+    if verified {
+        clear_failures(&session_status.lockout_file, &session_status.user, file_mutexes);
+        return Some(true);
+    }
+
+    console_println!("Wrong password.");
+    write_session_audit(
+        AuditEventType::FailLogon,
+        format!("Wrong password on {}", purpose),
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    if let Some(locked_until) = record_failure(
+        &session_status.lockout_file,
+        &session_status.user,
+        &session_status.lockout,
+        file_mutexes,
+    ) {
+        write_session_audit(
+            AuditEventType::UserLock,
+            lock_message(&session_status.lockout, locked_until, &session_status.user),
+            session_status,
+            file_mutexes,
+            log_file,
+            audit_status,
+        );
+    }
+    Some(false)
+}
+
+// Called on every menu choice. Choice made on locked session is dropped:
+// it may be typed by somebody else, menu is shown again after unlock.
+// Returns false if the choice must be dropped.
+pub fn check_session(
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) -> bool {
+    {
+        let mut activity = session_status.activity.lock().unwrap();
+        if !activity.locked {
+            activity.last_input = Instant::now();
+            return true;
+        }
+    }
+
+    console_println!(
+        "Session of {} is locked. Enter password to unlock.",
+        session_status.user
+    );
+    loop {
+        match confirm_password(session_status, "session unlock", file_mutexes, log_file, audit_status) {
+            Some(true) => break,
+            Some(false) => continue,
+            // console is closed, nothing can be done until shutdown
+            None => return false,
+        }
+    }
+
+    let mut activity = session_status.activity.lock().unwrap();
+    activity.locked = false;
+    activity.last_input = Instant::now();
+    drop(activity);
+
+    write_session_audit(
+        AuditEventType::SessionUnlock,
+        "Session unlocked".to_string(),
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    false
+}
+
+// Sensitive actions ask for password again, one attempt. false - action is cancelled.
+pub fn reauthenticate(
+    session_status: &SessionStatus,
+    action: &str,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) -> bool {
+    console_println!("Enter your password to confirm: {}.", action);
+    if confirm_password(session_status, action, file_mutexes, log_file, audit_status) != Some(true) {
+        console_println!("Action cancelled.");
+        return false;
+    }
+
+    write_session_audit(
+        AuditEventType::ReAuth,
+        format!("Password confirmed for action: {}", action),
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    true
+}
+
+// Called by management server loop. Remote clients get lock notice right away,
+// returned events are published to audit log and local console.
+pub fn lock_idle_sessions(sessions: &ConsoleSessions, idle_timeout: Duration) -> Vec<ServerEvent> {
+    let mut locked = Vec::new();
+
+    for session in sessions.lock().unwrap().values() {
+        let mut activity = session.activity.lock().unwrap();
+        if activity.locked || activity.last_input.elapsed() < idle_timeout {
+            continue;
+        }
+        activity.locked = true;
+
+        if let Some(output) = &session.output {
+            let _ = output.send(ConsoleOutput::Text {
+                text: format!(
+                    "\x1B[2J\x1B[HSession of {} is locked after {} seconds of inactivity. Press enter and type password to unlock.",
+                    session.user,
+                    idle_timeout.as_secs()
+                ),
+            });
+        }
+        locked.push(ServerEvent::SessionLocked {
+            host: session.host.clone(),
+            user: session.user.clone(),
+            remote_addr: session.remote_addr.clone(),
+            idle_secs: idle_timeout.as_secs(),
+        });
+    }
+
+    locked
+}
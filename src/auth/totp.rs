@@ -0,0 +1,315 @@
+use chrono::Local;
+use data_encoding::BASE32_NOPAD;
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+use crate::auth::auth::read_secret;
+use crate::console_println;
+use crate::file_manager::file_manager::user_file_handler::update_user;
+use crate::structs::soc_structs::multithread::FileMutexes;
+
+// RFC 6238 with the parameters every authenticator app supports: HMAC-SHA1, 30 seconds, 6 digits.
+// Everything is computed from the secret and local time, nothing goes to network
+const TIME_STEP: u64 = 30;
+const CODE_DIGITS: u32 = 6;
+// codes of neighbouring steps are accepted, clocks of phone and server may differ
+const STEP_WINDOW: u64 = 1;
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+// attempts to enter the first code on set up
+const ENROLL_ATTEMPTS: u32 = 3;
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+// RFC 4226 HOTP with dynamic truncation
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    binary % 10u32.pow(CODE_DIGITS)
+}
+
+fn current_step() -> u64 {
+    Local::now().timestamp().max(0) as u64 / TIME_STEP
+}
+
+// Returns time step of the accepted code. Steps up to last_step were used already
+pub fn verify_code(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let code: u32 = match code.trim() {
+        code if code.len() == CODE_DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) => {
+            code.parse().ok()?
+        }
+        _ => return None,
+    };
+    let key = match BASE32_NOPAD.decode(secret.as_bytes()) {
+        Ok(key) => key,
+        Err(_) => {
+            console_println!("Wrong TOTP secret format in user file");
+            return None;
+        }
+    };
+
+    let now = current_step();
+    (now.saturating_sub(STEP_WINDOW)..=now + STEP_WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| hotp(&key, *step) == code)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().as_bytes()))
+}
+
+// Returns codes to show to the user and their hashes to keep in users file.
+// Codes are random enough for plain SHA-256
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            OsRng
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LENGTH)
+                .map(char::from)
+                .collect()
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+fn uri_escape(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// key URI of authenticator apps, it can be typed or turned into QR code on an offline machine
+fn otpauth_uri(secret: &str, username: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_escape(issuer),
+        uri_escape(username),
+        secret,
+        uri_escape(issuer),
+        CODE_DIGITS,
+        TIME_STEP
+    )
+}
+
+// Checks TOTP or recovery code of the user and marks it as used. Both are done under
+// one lock of users file, so the same code can't be accepted for two logons at once.
+// Ok - message for audit log, Err - reason of failure
+pub fn check_second_factor(
+    users_file: &str,
+    file_mutexes: &FileMutexes,
+    username: &str,
+    input: &str,
+) -> Result<String, String> {
+    let input_hash = hash_recovery_code(input);
+    let mut result = Err("second factor is not set up".to_string());
+
+    let saved = update_user(users_file, file_mutexes, username, |user| {
+        if user.totp_secret.is_empty() {
+            return;
+        }
+        if let Some(step) = verify_code(&user.totp_secret, input, user.totp_last_step) {
+            user.totp_last_step = step;
+            result = Ok("Authentication code accepted".to_string());
+        } else if user.recovery_codes.contains(&input_hash) {
+            user.recovery_codes.retain(|code| *code != input_hash);
+            result = Ok(format!(
+                "Recovery code accepted, {} left",
+                user.recovery_codes.len()
+            ));
+        } else {
+            result = Err("wrong authentication or recovery code".to_string());
+        }
+    });
+
+    match result {
+        Ok(_) if !saved => Err("used code can't be saved".to_string()),
+        result => result,
+    }
+}
+
+// Shows new secret, checks the first code from the app and shows recovery codes.
+// Secret of the user is replaced only after the code is right. false - set up failed
+pub fn enroll(users_file: &str, file_mutexes: &FileMutexes, username: &str, issuer: &str) -> bool {
+    let secret = generate_secret();
+    console_println!(
+        "Add this key to authenticator app (TOTP, SHA1, {} digits, {} seconds):\n{}\nor key URI:\n{}",
+        CODE_DIGITS,
+        TIME_STEP,
+        secret,
+        otpauth_uri(&secret, username, issuer)
+    );
+
+    let mut accepted = None;
+    for _ in 0..ENROLL_ATTEMPTS {
+        let code = match read_secret("Code from the app: ") {
+            Some(code) => code,
+            None => return false,
+        };
+        accepted = verify_code(&secret, &code, 0);
+        if accepted.is_some() {
+            break;
+        }
+        console_println!("Wrong code. Check time on the device and try again.");
+    }
+    let step = match accepted {
+        Some(step) => step,
+        None => return false,
+    };
+
+    let (codes, hashes) = generate_recovery_codes();
+    if !update_user(users_file, file_mutexes, username, |user| {
+        user.totp_secret = secret;
+        user.totp_last_step = step;
+        user.recovery_codes = hashes;
+    }) {
+        return false;
+    }
+
+    console_println!(
+        "Two-factor authentication is set up. Recovery codes (shown only once, each works once):\n{}",
+        codes.join("\n")
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_manager::file_manager::user_file_handler::{create_users, key_file_path, load_users, set_test_key_dir};
+    use crate::structs::soc_structs::UserAccount;
+
+    // RFC 6238 appendix B, SHA1 key. Codes are the last 6 of 8 digits there
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: [(u64, u32); 6] = [
+        (59, 287082),
+        (1111111109, 81804),
+        (1111111111, 50471),
+        (1234567890, 5924),
+        (2000000000, 279037),
+        (20000000000, 353130),
+    ];
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_KEY, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        for (time, code) in RFC_VECTORS {
+            assert_eq!(hotp(RFC_KEY, time / TIME_STEP), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn code_is_accepted_once() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        let now = current_step();
+        let code = format!("{:06}", hotp(RFC_KEY, now));
+
+        let step = verify_code(&secret, &code, 0);
+        assert_eq!(step, Some(now));
+        assert_eq!(verify_code(&secret, &code, now), None);
+        // previous step is in the window
+        let previous = format!("{:06}", hotp(RFC_KEY, now - 1));
+        assert_eq!(verify_code(&secret, &previous, 0), Some(now - 1));
+    }
+
+    #[test]
+    fn malformed_codes_are_rejected() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        for code in ["", "12345", "1234567", "12a456"] {
+            assert_eq!(verify_code(&secret, code, 0), None);
+        }
+        assert_eq!(verify_code("not base32!", "123456", 0), None);
+        assert!(BASE32_NOPAD.decode(generate_secret().as_bytes()).unwrap().len() == SECRET_LENGTH);
+    }
+
+    #[test]
+    fn recovery_codes_match_their_hashes() {
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(codes.iter().all(|code| code.len() == RECOVERY_CODE_LENGTH));
+        for (code, hash) in codes.iter().zip(&hashes) {
+            assert_eq!(hash_recovery_code(code), *hash);
+            // spaces around typed code don't matter
+            assert_eq!(hash_recovery_code(&format!(" {} ", code)), *hash);
+        }
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), RECOVERY_CODES);
+    }
+
+    #[test]
+    fn recovery_code_is_used_up() {
+        let users_file = std::env::temp_dir()
+            .join(format!("rsoc_totp_users_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_file(&users_file);
+        set_test_key_dir();
+        let _ = std::fs::remove_file(key_file_path(&users_file).unwrap());
+        let file_mutexes = FileMutexes::for_tests("totp");
+
+        let (codes, hashes) = generate_recovery_codes();
+        let user = UserAccount {
+            name: "operator".to_string(),
+            pass_hash: String::new(),
+            role: "analyst".to_string(),
+            disabled: false,
+            must_change_password: false,
+            password_changed: 1,
+            password_history: Vec::new(),
+            totp_secret: BASE32_NOPAD.encode(RFC_KEY),
+            totp_last_step: 0,
+            recovery_codes: hashes,
+            created: 1,
+            last_logon: 0,
+            last_failed_logon: 0,
+            expires: 0,
+        };
+        assert!(create_users(&users_file, &[user]));
+
+        let accepted = check_second_factor(&users_file, &file_mutexes, "operator", &codes[0]);
+        assert_eq!(accepted, Ok(format!("Recovery code accepted, {} left", RECOVERY_CODES - 1)));
+        assert!(check_second_factor(&users_file, &file_mutexes, "operator", &codes[0]).is_err());
+        assert_eq!(load_users(&users_file)[0].recovery_codes.len(), RECOVERY_CODES - 1);
+
+        // TOTP code moves the last used step
+        let code = format!("{:06}", hotp(RFC_KEY, current_step()));
+        assert!(check_second_factor(&users_file, &file_mutexes, "operator", &code).is_ok());
+        assert!(check_second_factor(&users_file, &file_mutexes, "operator", &code).is_err());
+        assert!(check_second_factor(&users_file, &file_mutexes, "nobody", &code).is_err());
+
+        let _ = std::fs::remove_file(&users_file);
+        let _ = std::fs::remove_file(format!("{}.key", users_file));
+    }
+}
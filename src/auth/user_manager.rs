@@ -0,0 +1,458 @@
+use chrono::{Local, NaiveDate, TimeZone};
+
+use crate::auth::auth::{
+    account_expired, hash_password, read_new_password, set_new_password, temporary_password,
+    verify_password,
+};
+use crate::auth::lockout::{clear_failures, lock_state, LockState};
+use crate::auth::totp::enroll;
+use crate::console_println;
+use crate::file_manager::file_manager::audit_handler::write_audit_event;
+use crate::file_manager::file_manager::user_file_handler::{load_users, save_users, update_user};
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{AuditEventType, PasswordPolicy, SessionStatus, UserAccount};
+
+// Every change is written to audit log by the operator with the target account in the message.
+// Err - reason to show to the operator, nothing is changed then.
+
+// names are put in "[:|:]"-separated files and passed in command line
+fn is_valid_username(name: &str) -> bool {
+    !name.is_empty() && !name.contains("[:") && !name.contains(char::is_whitespace)
+}
+
+// operator can't lock out their own account: it is changed only with "Change own password"
+fn check_target(name: &str, session_status: &SessionStatus) -> Result<(), String> {
+    if name == session_status.user {
+        return Err("own account can't be changed this way, ask another admin".to_string());
+    }
+    Ok(())
+}
+
+fn write_user_audit(
+    event_type: AuditEventType,
+    text: String,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) {
+    if !write_audit_event(
+        session_status.host.clone(),
+        session_status.user.clone(),
+        event_type,
+        text,
+        file_mutexes,
+        log_file,
+        audit_status,
+    ) {
+        console_println!("Error occured with audit logging.");
+    }
+}
+
+// UNIX-time as in audit log, empty one is shown as `unset`
+fn format_time(timestamp: i64, unset: &str) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) if timestamp != 0 => time.format("%d-%m-%Y %H:%M:%S").to_string(),
+        _ => unset.to_string(),
+    }
+}
+
+pub fn get_user_list(users_file: &str, lockout_file: &str, file_mutexes: &FileMutexes) {
+    console_println!("------------------------------------------------------------------------------\n\
+             || ----- Name ----- || ----- Role ----- || ----- State ----- || ----- Password ----- || ----- 2FA ----- \
+             || ----- Created ----- || ----- Last logon ----- || ----- Last failed logon ----- || ----- Expires ----- ||\n\
+             ------------------------------------------------------------------------------");
+
+    for user in load_users(users_file) {
+        console_println!(
+            "|| {} || {} || {} || {} || {} || {} || {} || {} || {} ||",
+            user.name,
+            user.role,
+            if user.disabled {
+                "disabled"
+            } else if account_expired(&user) {
+                "expired"
+            } else if let LockState::Locked(_) = lock_state(lockout_file, &user.name, file_mutexes) {
+                "locked"
+            } else {
+                "active"
+            },
+            if user.must_change_password {
+                "temporary"
+            } else {
+                "set by user"
+            },
+            if user.totp_secret.is_empty() {
+                "off".to_string()
+            } else {
+                format!("on, {} recovery codes", user.recovery_codes.len())
+            },
+            format_time(user.created, "unknown"),
+            format_time(user.last_logon, "never"),
+            format_time(user.last_failed_logon, "never"),
+            format_time(user.expires, "never")
+        );
+    }
+
+    console_println!("------------------------------------------------------------------------------");
+}
+
+// Returns temporary password, user changes it on the first logon.
+// role is checked against roles file by the caller
+pub fn create_user(
+    name: &str,
+    role: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<String, String> {
+    if !is_valid_username(name) {
+        return Err("name can't be empty or contain spaces and '[:'".to_string());
+    }
+
+    let _users_lock = file_mutexes.users_mutex.lock().unwrap();
+    let mut users = load_users(users_file);
+    if users.iter().any(|user| user.name == name) {
+        return Err("user already exists".to_string());
+    }
+
+    let password = temporary_password();
+    users.push(UserAccount {
+        name: name.to_string(),
+        pass_hash: hash_password(&password),
+        role: role.to_string(),
+        disabled: false,
+        must_change_password: true,
+        password_changed: 0,
+        password_history: Vec::new(),
+        totp_secret: String::new(),
+        totp_last_step: 0,
+        recovery_codes: Vec::new(),
+        created: Local::now().timestamp(),
+        last_logon: 0,
+        last_failed_logon: 0,
+        expires: 0,
+    });
+    if !save_users(users_file, &users) {
+        return Err("user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::UserCreate,
+        format!("User created. Target account - {}. Role - {}", name, role),
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(password)
+}
+
+pub fn delete_user(
+    name: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    check_target(name, session_status)?;
+
+    let _users_lock = file_mutexes.users_mutex.lock().unwrap();
+    let mut users = load_users(users_file);
+    let count = users.len();
+    users.retain(|user| user.name != name);
+    if users.len() == count {
+        return Err("there is no such user".to_string());
+    }
+    if !save_users(users_file, &users) {
+        return Err("user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::UserDelete,
+        "User deleted. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
+
+// Disables active user and enables disabled one. Returns true if user is disabled now.
+pub fn switch_user_state(
+    name: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<bool, String> {
+    check_target(name, session_status)?;
+
+    let mut disabled = false;
+    if !update_user(users_file, file_mutexes, name, |user| {
+        user.disabled = !user.disabled;
+        disabled = user.disabled;
+    }) {
+        return Err("there is no such user or user file can't be saved".to_string());
+    }
+
+    let (event_type, text) = if disabled {
+        (AuditEventType::UserDisable, "User disabled. Target account - ")
+    } else {
+        (AuditEventType::UserEnable, "User enabled. Target account - ")
+    };
+    write_user_audit(
+        event_type,
+        text.to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(disabled)
+}
+
+// Returns temporary password, user changes it on the next logon.
+pub fn reset_password(
+    name: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<String, String> {
+    check_target(name, session_status)?;
+
+    let password = temporary_password();
+    let pass_hash = hash_password(&password);
+    if !update_user(users_file, file_mutexes, name, |user| {
+        user.pass_hash = pass_hash;
+        user.must_change_password = true;
+    }) {
+        return Err("there is no such user or user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::PassReset,
+        "Password reset. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(password)
+}
+
+// current_password is checked again, so unattended console can't be used to take the account
+pub fn change_own_password(
+    current_password: &str,
+    policy: &PasswordPolicy,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    let name = session_status.user.as_str();
+    let user = load_users(users_file)
+        .into_iter()
+        .find(|user| user.name == name && verify_password(current_password, &user.pass_hash));
+    let user = match user {
+        Some(user) => user,
+        None => {
+            write_user_audit(
+                AuditEventType::FailLogon,
+                "Wrong current password on password change. Target account - ".to_string() + name,
+                session_status,
+                file_mutexes,
+                log_file,
+                audit_status,
+            );
+            return Err("wrong current password".to_string());
+        }
+    };
+
+    let new_password = match read_new_password(&user, policy) {
+        Some(password) => password,
+        None => return Err("new password is not entered".to_string()),
+    };
+    if !set_new_password(users_file, name, &new_password, policy, file_mutexes) {
+        return Err("user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::PassChange,
+        "Password changed. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
+
+pub fn set_role(
+    name: &str,
+    role: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    check_target(name, session_status)?;
+
+    if !update_user(users_file, file_mutexes, name, |user| user.role = role.to_string()) {
+        return Err("there is no such user or user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::PrivChange,
+        format!("Role changed. Target account - {}. Role - {}", name, role),
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
+
+// last_day - "YYYY-MM-DD", account works until the end of it. Empty - account never expires.
+// Returns expiry time, 0 - never
+pub fn set_account_expiry(
+    name: &str,
+    last_day: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<i64, String> {
+    check_target(name, session_status)?;
+
+    let expires = match last_day.trim() {
+        "" => 0,
+        last_day => NaiveDate::parse_from_str(last_day, "%Y-%m-%d")
+            .ok()
+            .and_then(|day| day.succ_opt())
+            .and_then(|day| Local.from_local_datetime(&day.and_hms_opt(0, 0, 0)?).earliest())
+            .map(|time| time.timestamp())
+            .ok_or("wrong date, expected YYYY-MM-DD".to_string())?,
+    };
+    if !update_user(users_file, file_mutexes, name, |user| user.expires = expires) {
+        return Err("there is no such user or user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::UserExpiry,
+        match expires {
+            0 => "Account expiry removed. Target account - ".to_string() + name,
+            _ => format!(
+                "Account expiry set. Target account - {}. Expires - {}",
+                name,
+                format_time(expires, "never")
+            ),
+        },
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(expires)
+}
+
+// lock of failed logons is removed before its time
+pub fn unlock_user(
+    name: &str,
+    lockout_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    if !clear_failures(lockout_file, name, file_mutexes) {
+        return Err("user is not locked".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::UserUnlock,
+        "User unlocked. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
+
+// New secret and recovery codes replace the old ones after the first code is checked
+pub fn enroll_own_second_factor(
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    let name = session_status.user.as_str();
+    if !enroll(users_file, file_mutexes, name, &session_status.host) {
+        write_user_audit(
+            AuditEventType::MfaFail,
+            "Second factor is not set up. Target account - ".to_string() + name,
+            session_status,
+            file_mutexes,
+            log_file,
+            audit_status,
+        );
+        return Err("code is not confirmed or user file can't be saved".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::MfaEnroll,
+        "Second factor set up. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
+
+// user who lost the device sets up second factor again on the next logon (administrators must do it)
+pub fn reset_second_factor(
+    name: &str,
+    users_file: &str,
+    session_status: &SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> Result<(), String> {
+    check_target(name, session_status)?;
+
+    let mut enrolled = false;
+    if !update_user(users_file, file_mutexes, name, |user| {
+        enrolled = !user.totp_secret.is_empty();
+        user.totp_secret.clear();
+        user.totp_last_step = 0;
+        user.recovery_codes.clear();
+    }) {
+        return Err("there is no such user or user file can't be saved".to_string());
+    }
+    if !enrolled {
+        return Err("second factor of the user is not set up".to_string());
+    }
+
+    write_user_audit(
+        AuditEventType::MfaReset,
+        "Second factor reset. Target account - ".to_string() + name,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+    Ok(())
+}
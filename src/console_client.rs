@@ -0,0 +1,152 @@
+use clap::{value_parser, Arg, Command};
+use std::io::{self, Write};
+
+use rsoc::auth::auth::{logon_password, read_secret};
+use rsoc::file_manager::file_manager::config_handler::read_config;
+use rsoc::menu::console::read_line;
+use rsoc::menu::menu::get_user_choice;
+use rsoc::protocol::framing::{message_stream, recv_message, send_message};
+use rsoc::protocol::messages::{ConsoleOutput, ConsoleRequest, PROTOCOL_VERSION};
+use rsoc::tls::tls_config::{connect_to_server, sensor_tls_connector, TlsFiles};
+
+const CONFIG: &str = "console_client_config.txt";
+
+#[tokio::main]
+async fn main() {
+    let matches = Command::new("rSOC")
+        .version("0.1.0")
+        .author("buran <bvran@proton.me>")
+        .about("rSOC - Simple network and endpoint SOC implementation written on Rust\n\nThis is Console Client - remote command console of Management Server")
+        .arg(Arg::new("user")
+                 .short('u')
+                 .long("user")
+                 .required(true)
+                 .help("User to authenticate"))
+        .arg(Arg::new("password_env")
+                 .long("password-env")
+                 .value_name("VARIABLE")
+                 .conflicts_with("password_fd")
+                 .help("Read user's password from environment variable, prompt is used if no password option is set"))
+        .arg(Arg::new("password_fd")
+                 .long("password-fd")
+                 .value_name("FD")
+                 .value_parser(value_parser!(u32))
+                 .help("Read user's password from the first line of opened file descriptor, e.g. --password-fd 3 3<password_file"))
+        .get_matches();
+
+    let mut server_address: String = String::new();
+    let mut tls_ca_cert: String = String::new();
+    let mut tls_cert: String = String::new();
+    let mut tls_key: String = String::new();
+
+    // config parcing
+    match read_config(CONFIG) {
+        Ok(config) => {
+            for (key, value) in config {
+                match key.as_str() {
+                    "server_address" => server_address = value,
+                    "tls_ca_cert" => tls_ca_cert = value,
+                    "tls_cert" => tls_cert = value,
+                    "tls_key" => tls_key = value,
+                    _ => println!("Weird parameter: {}", key),
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+        }
+    }
+
+    let username = matches.get_one::<String>("user").unwrap().clone();
+    let password = match logon_password(&matches, "Password: ") {
+        Some(password) => password,
+        None => {
+            println!("Password is not entered. Goodbye.");
+            return;
+        }
+    };
+
+    if server_address.is_empty() {
+        println!("Enter address (IP:port, [IPv6]:port or unix:/path/to/socket) of management server console:");
+        server_address = get_user_choice();
+    }
+
+    let tls_files = TlsFiles {
+        ca_cert: tls_ca_cert,
+        cert: tls_cert,
+        key: tls_key,
+    };
+    let tls_connector = match sensor_tls_connector(&tls_files) {
+        Ok(connector) => connector,
+        Err(e) => {
+            println!("Failed to set up TLS: {}", e);
+            return;
+        }
+    };
+    let mut stream = match connect_to_server(&server_address, &tls_connector).await {
+        Ok(tls_stream) => message_stream(tls_stream),
+        Err(e) => {
+            println!("Failed to connect: {}", e);
+            return;
+        }
+    };
+
+    let hello = ConsoleRequest::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        username,
+        password,
+    };
+    if let Err(e) = send_message(&mut stream, &hello).await {
+        println!("Failed to connect: {}", e);
+        return;
+    }
+
+    // Reading stdin blocks, so it runs on its own thread.
+    // Server output is shown meanwhile, e.g. lock notice while the prompt is waiting
+    let mut pending_input: Option<tokio::task::JoinHandle<Option<String>>> = None;
+    loop {
+        tokio::select! {
+            message = recv_message::<_, ConsoleOutput>(&mut stream) => match message {
+                Ok(Some(ConsoleOutput::Text { text })) => println!("{}", text),
+                Ok(Some(ConsoleOutput::Prompt { text, secret })) => {
+                    if pending_input.is_none() {
+                        pending_input = Some(tokio::task::spawn_blocking(move || {
+                            if secret {
+                                return read_secret(&text);
+                            }
+                            print!("{}", text);
+                            io::stdout().flush().unwrap();
+                            read_line().map(|line| line.trim_end_matches(['\r', '\n']).to_string())
+                        }));
+                    }
+                }
+                Ok(Some(ConsoleOutput::Closed { reason })) => {
+                    println!("Connection closed: {}", reason);
+                    break;
+                }
+                Ok(None) => {
+                    println!("Management server closed connection");
+                    break;
+                }
+                Err(e) => {
+                    println!("Connection error: {}", e);
+                    break;
+                }
+            },
+            line = async { pending_input.as_mut().unwrap().await }, if pending_input.is_some() => {
+                pending_input = None;
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    _ => break,
+                };
+                if let Err(e) = send_message(&mut stream, &ConsoleRequest::Input { line }).await {
+                    println!("Connection error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // input thread can be blocked on reading stdin, there is nothing to wait for
+    std::process::exit(0);
+}
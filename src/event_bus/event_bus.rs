@@ -0,0 +1,227 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::protocol::messages::{RateLimit, RulesState};
+
+// Messages older than this are dropped for best-effort subscriber that can't keep up,
+// other subscribers are not slowed down by it. Writers never lose messages.
+pub const BUS_CAPACITY: usize = 1024;
+
+// everything that happens in management server and can be written, printed or counted
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    // security event from sensor, not written to event log yet
+    SensorEvent {
+        sensor_id: String,
+        name: String,
+        level: String,
+        capturing: bool,
+        seq: u64,
+        rule_hash: String,
+        timestamp: i64,
+        path: Option<String>,
+    },
+    // security event is in event log
+    EventWritten {
+        name: String,
+        timestamp: i64,
+    },
+    SensorConnected {
+        addr: String,
+        name: String,
+        level: String,
+        user: String,
+        cert_subject: String,
+    },
+    SensorDisconnected {
+        sensor_id: String,
+        addr: String,
+        name: String,
+        level: String,
+        user: String,
+    },
+    // name and user are claimed by sensor, " " if unknown
+    SensorRejected {
+        addr: String,
+        name: String,
+        user: String,
+        reason: String,
+    },
+    // operator of sensor config is not authenticated, the connection is rejected
+    OperatorRejected {
+        addr: String,
+        name: String,
+        user: String,
+        reason: String,
+    },
+    // text - audit message of the lock
+    OperatorLocked {
+        name: String,
+        user: String,
+        text: String,
+    },
+    SensorEnrolled {
+        sensor_id: String,
+        name: String,
+        user: String,
+    },
+    SensorSilent {
+        name: String,
+        user: String,
+        timeout_secs: u64,
+    },
+    SensorRecovered {
+        addr: String,
+        name: String,
+        user: String,
+    },
+    // by_sensor - sensor drops events itself, otherwise server slows it down
+    SensorThrottled {
+        name: String,
+        user: String,
+        by_sensor: bool,
+        limit: RateLimit,
+    },
+    // over_limit - events dropped by sensor or delayed by server while throttled
+    SensorUnthrottled {
+        name: String,
+        user: String,
+        by_sensor: bool,
+        over_limit: u64,
+    },
+    // operator - who sent the command, command - "Unknown" if sensor answered to unknown ID.
+    // session_id - console session of the operator, None - server console
+    CommandDone {
+        name: String,
+        operator: String,
+        session_id: Option<u64>,
+        command: String,
+        success: bool,
+        output: String,
+    },
+    RulesApplied {
+        name: String,
+        user: String,
+        level: String,
+        rules: RulesState,
+    },
+    // client of remote console, published after the user is authenticated
+    ConsoleConnected {
+        host: String,
+        addr: String,
+        user: String,
+        cert_subject: String,
+    },
+    ConsoleDisconnected {
+        host: String,
+        addr: String,
+        user: String,
+    },
+    // user is " " if unknown
+    ConsoleRejected {
+        host: String,
+        addr: String,
+        user: String,
+        reason: String,
+    },
+    // console was idle for idle_secs, input is taken only after password
+    SessionLocked {
+        host: String,
+        user: String,
+        remote_addr: Option<String>,
+        idle_secs: u64,
+    },
+    // user - who stops the server, "system" for signals
+    ShutdownRequested {
+        user: String,
+        reason: String,
+    },
+    // last message on the bus, subscribers finish after it.
+    // Sensors are disconnected and everything they sent is on the bus already
+    Shutdown {
+        host: String,
+        user: String,
+        reason: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    broadcast: broadcast::Sender<ServerEvent>,
+    // event log and audit writers: every message is queued for them
+    writers: Arc<Mutex<Vec<mpsc::UnboundedSender<ServerEvent>>>>,
+}
+
+impl EventBus {
+    // best-effort subscriber, e.g. console printer: it loses messages it can't keep up with
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.broadcast.subscribe()
+    }
+
+    // writer gets every message published after this call, in publish order
+    pub fn subscribe_writer(&self) -> mpsc::UnboundedReceiver<ServerEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.writers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+pub fn new_event_bus() -> EventBus {
+    EventBus {
+        broadcast: broadcast::channel(BUS_CAPACITY).0,
+        writers: Arc::new(Mutex::new(Vec::new())),
+    }
+}
+
+// Nobody listening is not an error: message just has no consumers.
+// Writers are sent to under the lock, so all of them see messages in the same order
+pub fn publish(bus: &EventBus, event: ServerEvent) {
+    let mut writers = bus.writers.lock().unwrap();
+    writers.retain(|writer| writer.send(event.clone()).is_ok());
+    drop(writers);
+    let _ = bus.broadcast.send(event);
+}
+
+// Waits for the next message on the bus.
+// Some(Err) - subscriber was too slow and lost this number of messages.
+// None - bus is closed.
+pub async fn next_message(
+    bus_rx: &mut broadcast::Receiver<ServerEvent>,
+) -> Option<Result<ServerEvent, u64>> {
+    match bus_rx.recv().await {
+        Ok(event) => Some(Ok(event)),
+        Err(broadcast::error::RecvError::Lagged(lost)) => Some(Err(lost)),
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(number: i64) -> ServerEvent {
+        ServerEvent::EventWritten {
+            name: "Zarya-1".to_string(),
+            timestamp: number,
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_gets_everything_printer_may_lag() {
+        let bus = new_event_bus();
+        let mut writer = bus.subscribe_writer();
+        let mut printer = bus.subscribe();
+        let total = BUS_CAPACITY as i64 * 2;
+        for number in 0..total {
+            publish(&bus, written(number));
+        }
+
+        for number in 0..total {
+            match writer.recv().await {
+                Some(ServerEvent::EventWritten { timestamp, .. }) => assert_eq!(timestamp, number),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert!(matches!(next_message(&mut printer).await, Some(Err(_))));
+    }
+}
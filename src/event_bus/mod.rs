@@ -0,0 +1,4 @@
+// crate::event_bus::event_bus::... paths are used across the crate and binaries
+#[allow(clippy::module_inception)]
+pub mod event_bus;
+pub mod subscribers;
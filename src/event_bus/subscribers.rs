@@ -0,0 +1,544 @@
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::event_bus::event_bus::{next_message, publish, EventBus, ServerEvent};
+use crate::file_manager::file_manager::audit_handler::write_audit_event;
+use crate::file_manager::file_manager::event_handler::write_security_event;
+use crate::sensor_handler::sensor_handler::ack_event;
+use crate::sensor_handler::sensor_registry::{get_last_event_seq, set_last_event_seqs};
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::protocol::messages::ConsoleOutput;
+use crate::structs::soc_structs::{AuditEventType, ConsoleSessions, SensorMap};
+
+// Subscribers are spawned before anything is published, so they see every message.
+// Each of them works until Shutdown and is awaited by the server before exit.
+// Event and audit writers have their own queues and lose nothing, console printer may skip messages.
+
+// events written before registry is saved, so a steady flow of events doesn't hold acks
+const MAX_UNSAVED_EVENTS: usize = 256;
+
+// Writes security events to event log and acknowledges them to sensors.
+pub fn spawn_event_writer(
+    bus: &EventBus,
+    sensors: SensorMap,
+    file_mutexes: FileMutexes,
+    event_log: String,
+) -> JoinHandle<()> {
+    let mut bus_rx = bus.subscribe_writer();
+    let bus = bus.clone();
+
+    tokio::spawn(async move {
+        // (last written event, write failed) for every sensor
+        let mut last_event_seqs: HashMap<String, (u64, bool)> = HashMap::new();
+        // written events not in registry yet: sensor -> last of them
+        let mut unsaved: HashMap<String, u64> = HashMap::new();
+        let mut unsaved_events = 0;
+
+        loop {
+            // registry is saved and sensors get ack once per batch: when the bus
+            // has nothing more for the writer or too many events are waiting
+            if bus_rx.is_empty() || unsaved_events >= MAX_UNSAVED_EVENTS {
+                save_event_seqs(&mut unsaved, &sensors, &file_mutexes);
+                unsaved_events = 0;
+            }
+            let message = match bus_rx.recv().await {
+                Some(message) => message,
+                None => break,
+            };
+            let (sensor_id, name, level, capturing, seq, rule_hash, timestamp, path) =
+                match message {
+                    ServerEvent::SensorEvent {
+                        sensor_id,
+                        name,
+                        level,
+                        capturing,
+                        seq,
+                        rule_hash,
+                        timestamp,
+                        path,
+                    } => (
+                        sensor_id, name, level, capturing, seq, rule_hash, timestamp, path,
+                    ),
+                    ServerEvent::Shutdown { .. } => break,
+                    _ => continue,
+                };
+
+            let (last_seq, broken) = last_event_seqs
+                .entry(sensor_id.clone())
+                .or_insert_with(|| (get_last_event_seq(&sensor_id, &file_mutexes), false));
+
+            // retransmitted event which is already in event log: just confirm it again
+            if seq <= *last_seq {
+                ack_event(&sensors, &sensor_id, seq);
+                continue;
+            }
+            // after failed write later events wait until the lost one is retransmitted
+            if *broken && seq != *last_seq + 1 {
+                continue;
+            }
+
+            // events of stopped sensor are confirmed, but not written
+            if capturing {
+                let datetime: DateTime<Local> = DateTime::from_timestamp(timestamp, 0)
+                    .unwrap()
+                    .with_timezone(&Local);
+                let path = path.unwrap_or_else(|| " ".to_string());
+
+                if !write_security_event(
+                    datetime,
+                    name.clone(),
+                    rule_hash,
+                    level == "net",
+                    &file_mutexes,
+                    &event_log,
+                    &path,
+                ) {
+                    println!(
+                        "Error while writing event {} of sensor {}. Sensor will send it again.",
+                        seq, name
+                    );
+                    *broken = true;
+                    continue;
+                }
+                publish(&bus, ServerEvent::EventWritten { name, timestamp });
+            }
+
+            *last_seq = seq;
+            *broken = false;
+            unsaved.insert(sensor_id, seq);
+            unsaved_events += 1;
+        }
+
+        save_event_seqs(&mut unsaved, &sensors, &file_mutexes);
+    })
+}
+
+// Records last written events of sensors in registry and acknowledges them.
+fn save_event_seqs(unsaved: &mut HashMap<String, u64>, sensors: &SensorMap, file_mutexes: &FileMutexes) {
+    if unsaved.is_empty() {
+        return;
+    }
+
+    let seqs: Vec<(String, u64)> = unsaved.drain().collect();
+    if !set_last_event_seqs(&seqs, file_mutexes) {
+        println!("Error while saving last events of sensors. Events can be written again after restart.");
+    }
+    for (sensor_id, seq) in seqs {
+        ack_event(sensors, &sensor_id, seq);
+    }
+}
+
+// Writes audit records for everything sensors do. Shutdown is written as the last record.
+pub fn spawn_audit_writer(
+    bus: &EventBus,
+    file_mutexes: FileMutexes,
+    audit_log: String,
+    audit_status: Arc<Mutex<bool>>,
+) -> JoinHandle<()> {
+    let mut bus_rx = bus.subscribe_writer();
+
+    tokio::spawn(async move {
+        while let Some(event) = bus_rx.recv().await {
+            let shutdown = matches!(event, ServerEvent::Shutdown { .. });
+            let (host, user, event_type, text) = match event {
+                ServerEvent::SensorConnected {
+                    name,
+                    level,
+                    user,
+                    cert_subject,
+                    ..
+                } => (
+                    name,
+                    user,
+                    if level == "net" {
+                        AuditEventType::NetSenConn
+                    } else {
+                        AuditEventType::HostSenConn
+                    },
+                    format!(
+                        "Sensor connected. Type - {}. Certificate - {}",
+                        level, cert_subject
+                    ),
+                ),
+                ServerEvent::SensorDisconnected {
+                    name, level, user, ..
+                } => (
+                    name,
+                    user,
+                    if level == "net" {
+                        AuditEventType::NetSenDisconn
+                    } else {
+                        AuditEventType::HostSenDisconn
+                    },
+                    "Sensor disconnected. Type - ".to_string() + level.as_str(),
+                ),
+                ServerEvent::OperatorRejected {
+                    addr,
+                    name,
+                    user,
+                    reason,
+                } => (
+                    name.clone(),
+                    user,
+                    AuditEventType::FailLogon,
+                    format!(
+                        "Sensor operator authentication failure. Sensor - {}. Address - {}. Reason - {}",
+                        name, addr, reason
+                    ),
+                ),
+                ServerEvent::OperatorLocked { name, user, text } => {
+                    (name, user, AuditEventType::UserLock, text)
+                }
+                ServerEvent::SensorRejected {
+                    addr,
+                    name,
+                    user,
+                    reason,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::SenReject,
+                    format!(
+                        "Sensor connection rejected. Address - {}. Reason - {}",
+                        addr, reason
+                    ),
+                ),
+                ServerEvent::SensorEnrolled {
+                    sensor_id,
+                    name,
+                    user,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::SenEnroll,
+                    "Sensor enrolled. Sensor ID - ".to_string() + sensor_id.as_str(),
+                ),
+                ServerEvent::SensorSilent {
+                    name,
+                    user,
+                    timeout_secs,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::SenSilent,
+                    format!(
+                        "No heartbeat from sensor for more than {} seconds",
+                        timeout_secs
+                    ),
+                ),
+                ServerEvent::SensorRecovered { addr, name, user } => (
+                    name,
+                    user,
+                    AuditEventType::SenRecover,
+                    "Sensor recovered after silence. Address - ".to_string() + addr.as_str(),
+                ),
+                ServerEvent::SensorThrottled {
+                    name,
+                    user,
+                    by_sensor,
+                    limit,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::SenThrottle,
+                    format!(
+                        "Sensor event rate is over limit ({}). {}",
+                        limit,
+                        if by_sensor {
+                            "Sensor drops events over limit"
+                        } else {
+                            "Server slows down the sensor"
+                        }
+                    ),
+                ),
+                ServerEvent::SensorUnthrottled {
+                    name,
+                    user,
+                    by_sensor,
+                    over_limit,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::SenUnthrottle,
+                    format!(
+                        "Sensor event rate is under limit again. Events {} - {}",
+                        if by_sensor {
+                            "dropped by sensor"
+                        } else {
+                            "delayed by server"
+                        },
+                        over_limit
+                    ),
+                ),
+                ServerEvent::CommandDone {
+                    name,
+                    operator,
+                    command,
+                    success,
+                    output,
+                    ..
+                } => {
+                    let (event_type, text) = match (command.as_str(), success) {
+                        ("Start", true) => (
+                            AuditEventType::SenEnable,
+                            "Detection started on sensor".to_string(),
+                        ),
+                        ("Stop", true) => (
+                            AuditEventType::SenDisable,
+                            "Detection stopped on sensor".to_string(),
+                        ),
+                        (command, true) => (
+                            AuditEventType::SenCommandAck,
+                            format!("Command {} done. {}", command, output),
+                        ),
+                        (command, false) => (
+                            AuditEventType::SenCommandAck,
+                            format!("Command {} failed. {}", command, output),
+                        ),
+                    };
+                    (name, operator, event_type, text)
+                }
+                ServerEvent::RulesApplied {
+                    name,
+                    user,
+                    level,
+                    rules,
+                } => (
+                    name,
+                    user,
+                    AuditEventType::RulesUpdate,
+                    format!("Rules updated - {} level, version {}", level, rules),
+                ),
+                ServerEvent::ConsoleConnected {
+                    host,
+                    addr,
+                    user,
+                    cert_subject,
+                } => (
+                    host,
+                    user,
+                    AuditEventType::ConsoleConn,
+                    format!(
+                        "Remote console connected. Address - {}. Certificate - {}",
+                        addr, cert_subject
+                    ),
+                ),
+                ServerEvent::ConsoleDisconnected { host, addr, user } => (
+                    host,
+                    user,
+                    AuditEventType::ConsoleDisconn,
+                    "Remote console disconnected. Address - ".to_string() + addr.as_str(),
+                ),
+                ServerEvent::ConsoleRejected {
+                    host,
+                    addr,
+                    user,
+                    reason,
+                } => (
+                    host,
+                    user,
+                    AuditEventType::ConsoleReject,
+                    format!("Remote console rejected. Address - {}. Reason - {}", addr, reason),
+                ),
+                ServerEvent::SessionLocked {
+                    host,
+                    user,
+                    remote_addr,
+                    idle_secs,
+                } => (
+                    host,
+                    user,
+                    AuditEventType::SessionLock,
+                    match remote_addr {
+                        Some(addr) => format!(
+                            "Session locked after {} seconds of inactivity. Remote console - {}",
+                            idle_secs, addr
+                        ),
+                        None => format!("Session locked after {} seconds of inactivity", idle_secs),
+                    },
+                ),
+                ServerEvent::Shutdown { host, user, reason } => (
+                    host,
+                    user,
+                    AuditEventType::ServOff,
+                    "Management server turned off. Reason - ".to_string() + reason.as_str(),
+                ),
+                ServerEvent::SensorEvent { .. }
+                | ServerEvent::EventWritten { .. }
+                | ServerEvent::ShutdownRequested { .. } => continue,
+            };
+
+            let aud_stat = *audit_status.lock().unwrap();
+            write_audit_event(
+                host,
+                user,
+                event_type,
+                text,
+                &file_mutexes,
+                &audit_log,
+                aud_stat,
+            );
+
+            if shutdown {
+                let _ = file_mutexes.audit_mutex.lock().unwrap().sync_all();
+                break;
+            }
+        }
+    })
+}
+
+// Result of sensor command goes to console session of the operator who sent it.
+// Server console gets it if the session is local or is over already
+fn show_in_session(sessions: &ConsoleSessions, session_id: Option<u64>, text: String) {
+    let output = session_id.and_then(|session_id| {
+        sessions
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .and_then(|session| session.output.clone())
+    });
+    match output {
+        Some(output) => {
+            let _ = output.send(ConsoleOutput::Text { text });
+        }
+        None => println!("{}", text),
+    }
+}
+
+// Prints what happens with sensors to management server console.
+// print_events - "event_print" config option.
+pub fn spawn_console_printer(bus: &EventBus, print_events: bool, sessions: ConsoleSessions) -> JoinHandle<()> {
+    let mut bus_rx = bus.subscribe();
+
+    tokio::spawn(async move {
+        while let Some(message) = next_message(&mut bus_rx).await {
+            let event = match message {
+                Ok(event) => event,
+                Err(lost) => {
+                    println!("Console missed {} messages", lost);
+                    continue;
+                }
+            };
+
+            match event {
+                ServerEvent::EventWritten { name, timestamp } if print_events => {
+                    let datetime: DateTime<Local> = DateTime::from_timestamp(timestamp, 0)
+                        .unwrap()
+                        .with_timezone(&Local);
+                    println!(
+                        "Event! Time: {}, Sensor: {}",
+                        datetime.format("%d-%m-%Y %H:%M:%S"),
+                        name
+                    );
+                }
+                ServerEvent::SensorConnected {
+                    addr,
+                    name,
+                    level,
+                    user,
+                    cert_subject,
+                } => println!(
+                    "Client connected! IP: {}, Name: {}, Level: {}, User: {}, Certificate: {}",
+                    addr, name, level, user, cert_subject
+                ),
+                ServerEvent::SensorDisconnected { addr, name, .. } => {
+                    println!("Client disconnected: {} ({})", addr, name)
+                }
+                ServerEvent::SensorRejected { addr, reason, .. } => {
+                    println!("Client {} rejected: {}", addr, reason)
+                }
+                ServerEvent::OperatorRejected {
+                    addr,
+                    name,
+                    user,
+                    reason,
+                } => println!(
+                    "Client {} rejected: operator {} of sensor {} is not authenticated: {}",
+                    addr, user, name, reason
+                ),
+                ServerEvent::SensorSilent {
+                    name, timeout_secs, ..
+                } => println!(
+                    "Sensor {} is silent for more than {} seconds",
+                    name, timeout_secs
+                ),
+                ServerEvent::SensorRecovered { addr, name, .. } => {
+                    println!("Sensor {} ({}) is alive again", name, addr)
+                }
+                ServerEvent::CommandDone {
+                    name,
+                    session_id,
+                    command,
+                    success,
+                    output,
+                    ..
+                } => show_in_session(
+                    &sessions,
+                    session_id,
+                    format!(
+                        "Sensor {}: command {} {}. {}",
+                        name,
+                        command,
+                        if success { "done" } else { "failed" },
+                        output
+                    ),
+                ),
+                ServerEvent::RulesApplied { name, rules, .. } => {
+                    println!("Sensor {} applied rules version {}", name, rules)
+                }
+                ServerEvent::SensorThrottled {
+                    name,
+                    by_sensor,
+                    limit,
+                    ..
+                } => println!(
+                    "Sensor {} is throttled by {}: event rate is over limit ({})",
+                    name,
+                    if by_sensor { "itself" } else { "server" },
+                    limit
+                ),
+                ServerEvent::SensorUnthrottled {
+                    name, over_limit, ..
+                } => println!(
+                    "Sensor {} is not throttled anymore, {} events over limit",
+                    name, over_limit
+                ),
+                ServerEvent::ConsoleConnected {
+                    addr,
+                    user,
+                    cert_subject,
+                    ..
+                } => println!(
+                    "Remote console connected! IP: {}, User: {}, Certificate: {}",
+                    addr, user, cert_subject
+                ),
+                ServerEvent::ConsoleDisconnected { addr, user, .. } => {
+                    println!("Remote console disconnected: {} ({})", addr, user)
+                }
+                ServerEvent::ConsoleRejected { addr, reason, .. } => {
+                    println!("Remote console {} rejected: {}", addr, reason)
+                }
+                // screen is cleared, so data shown before is not left on unattended console
+                ServerEvent::SessionLocked {
+                    user,
+                    remote_addr: None,
+                    idle_secs,
+                    ..
+                } => println!(
+                    "\x1B[2J\x1B[HSession of {} is locked after {} seconds of inactivity. Press enter and type password to unlock.",
+                    user, idle_secs
+                ),
+                ServerEvent::ShutdownRequested { reason, .. } => {
+                    println!("Stopping management server: {}. Stop listening...", reason)
+                }
+                ServerEvent::Shutdown { .. } => {
+                    println!("Management server stopped");
+                    break;
+                }
+                _ => {}
+            }
+        }
+    })
+}
@@ -1,45 +1,631 @@
 pub mod user_file_handler {
+    use crate::console_println;
+    use chrono::Local;
+    use data_encoding::HEXLOWER;
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+    use ring::hmac;
     use std::collections::HashMap;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader, Write};
+    use std::fs::{self, OpenOptions};
+    use std::io::{ErrorKind, Write};
+    use std::process;
     use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::OnceLock;
 
-    // passwords "admin" and "user" in SHA-256
-    const BASIC_USER: &str = "admin[:|:]8c6976e5b5410415bde908bd4dee15dfb167a9c873fc4bb8a81f6f2ab448a918[:|:]1\n\
-                              user[:|:]04f8996da763b7a969b1028ee3007569eaf3a635486ddab211d512c85b9df8fb[:|:]0";
+    use crate::auth::auth::hash_password;
+    use crate::auth::roles::{default_roles, ADMIN_ROLE, BASIC_ROLE};
+    use crate::structs::soc_structs::multithread::FileMutexes;
+    use crate::structs::soc_structs::UserAccount;
+
+    static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+    // users of new users file: (name, password, role).
+    // Their passwords must be changed on the first logon
+    pub const DEFAULT_USERS: [(&str, &str, &str); 2] = [("admin", "admin", ADMIN_ROLE), ("user", "user", BASIC_ROLE)];
+
+    // The last line of users and roles files is keyed integrity check of all lines above it:
+    // #hmac-sha256[:|:]hex. Key of <dir>/<file> is <key dir>/<file>.key. Key directory is set
+    // in server config apart from data files, so whoever can change users file can't sign it
+    const INTEGRITY_PREFIX: &str = "#hmac-sha256[:|:]";
+    const KEY_LENGTH: usize = 32;
+
+    static KEY_DIR: OnceLock<String> = OnceLock::new();
+
+    // "integrity_key_dir" of server config, set once on start.
+    // Err - it is empty or the same directory as one of the protected files
+    pub fn set_key_dir(key_dir: &str, files: &[&str]) -> Result<(), String> {
+        if key_dir.is_empty() {
+            return Err("integrity_key_dir is not set in server config".to_string());
+        }
+        let key_path = fs::canonicalize(key_dir).unwrap_or_else(|_| Path::new(key_dir).to_path_buf());
+        for file in files {
+            let parent = match Path::new(file).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            if fs::canonicalize(parent).is_ok_and(|parent| parent == key_path) {
+                return Err(format!("integrity keys must not be kept in the directory of {}", file));
+            }
+        }
+
+        KEY_DIR.get_or_init(|| key_dir.to_string());
+        Ok(())
+    }
+
+    // keys of tests are kept apart from their temp files
+    #[cfg(test)]
+    pub fn set_test_key_dir() {
+        let key_dir = std::env::temp_dir().join(format!("rsoc_keys_{}", process::id()));
+        KEY_DIR.get_or_init(|| key_dir.to_string_lossy().to_string());
+    }
+
+    pub fn key_file_path(file: &str) -> Result<String, String> {
+        let key_dir = KEY_DIR.get().ok_or("integrity key directory is not set".to_string())?;
+        let name = Path::new(file).file_name().ok_or(format!("{} is not a file", file))?;
+        Ok(Path::new(key_dir)
+            .join(format!("{}.key", name.to_string_lossy()))
+            .to_string_lossy()
+            .to_string())
+    }
+
+    // Ok(None) - there is no key yet
+    fn read_key(file: &str) -> Result<Option<hmac::Key>, String> {
+        let key_file = key_file_path(file)?;
+        match fs::read_to_string(&key_file) {
+            Ok(content) => match HEXLOWER.decode(content.trim().as_bytes()) {
+                Ok(key) if key.len() == KEY_LENGTH => Ok(Some(hmac::Key::new(hmac::HMAC_SHA256, &key))),
+                _ => Err(format!("wrong integrity key format in {}", key_file)),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("integrity key {} can't be read: {}", key_file, e)),
+        }
+    }
+
+    // Only 'users init' and 'users sign' create keys, the server just reads them
+    fn create_key(file: &str) -> Result<hmac::Key, String> {
+        let key_file = key_file_path(file)?;
+        if let Some(key_dir) = Path::new(&key_file).parent() {
+            fs::create_dir_all(key_dir).map_err(|e| format!("key directory can't be created: {}", e))?;
+        }
+        let mut key = [0u8; KEY_LENGTH];
+        OsRng.fill_bytes(&mut key);
+        if !replace_private_file(&key_file, &(HEXLOWER.encode(&key) + "\n")) {
+            return Err("integrity key can't be saved".to_string());
+        }
+        Ok(hmac::Key::new(hmac::HMAC_SHA256, &key))
+    }
+
+    enum UserStore {
+        // there is neither file nor key: the first start or both are deleted
+        Missing,
+        // file of older version without integrity line, it has data lines only
+        Unprotected(String),
+        // data lines of the file, it is not changed outside of the server
+        Verified(String),
+    }
+
+    // Err - reason of failed check
+    fn read_user_store(file: &str) -> Result<UserStore, String> {
+        let key = read_key(file)?;
+        let content = match fs::read_to_string(file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return match key {
+                    Some(_) => Err("file is missing, but integrity key exists".to_string()),
+                    None => Ok(UserStore::Missing),
+                };
+            }
+            Err(e) => return Err(format!("file can't be read: {}", e)),
+        };
+
+        let trimmed = content.trim_end();
+        let (body, last_line) = match trimmed.rfind('\n') {
+            Some(position) => (&content[..position + 1], &trimmed[position + 1..]),
+            None => ("", trimmed),
+        };
+        match (key, last_line.strip_prefix(INTEGRITY_PREFIX)) {
+            (Some(key), Some(tag)) => {
+                let tag = HEXLOWER
+                    .decode(tag.trim().as_bytes())
+                    .map_err(|_| "wrong integrity line format".to_string())?;
+                hmac::verify(&key, body.as_bytes(), &tag)
+                    .map_err(|_| "integrity check failed, file was changed outside of the server".to_string())?;
+                Ok(UserStore::Verified(body.to_string()))
+            }
+            (Some(_), None) => Err("integrity line is missing".to_string()),
+            (None, Some(_)) => Err(format!("integrity key {} is missing", key_file_path(file)?)),
+            (None, None) => Ok(UserStore::Unprotected(content)),
+        }
+    }
+
+    // Called on server start for users and roles files, Ok(true) - unprotected file of older version is signed now.
+    // It is signed only by offline 'users sign' command, otherwise removed key and integrity line would pass.
+    // Missing file is an alert too: defaults are created only by offline 'users init' command
+    pub fn check_user_store(file: &str, sign_unprotected: bool) -> Result<bool, String> {
+        match read_user_store(file)? {
+            UserStore::Unprotected(content) if sign_unprotected => {
+                let key = create_key(file)?;
+                if !write_signed(file, &key, &content) {
+                    return Err("file can't be saved".to_string());
+                }
+                Ok(true)
+            }
+            UserStore::Unprotected(_) => Err(
+                "file is not protected with integrity key. If nobody has changed it, protect it with 'users sign' command"
+                    .to_string(),
+            ),
+            UserStore::Missing => Err(
+                "file and its integrity key are missing. On the first start create them with 'users init' command"
+                    .to_string(),
+            ),
+            UserStore::Verified(_) => Ok(false),
+        }
+    }
+
+    // First start: default users and roles files with new keys.
+    // Err - reason they are not created, existing files are never replaced
+    pub fn init_user_store(user_file: &str, roles_file: &str) -> Result<(), String> {
+        for file in [user_file, roles_file] {
+            match read_user_store(file) {
+                Ok(UserStore::Missing) => {}
+                Ok(_) => return Err(format!("{} exists already", file)),
+                Err(reason) => return Err(format!("{}: {}", file, reason)),
+            }
+        }
+        if !create_users(user_file, &default_users()) {
+            return Err(format!("{} can't be created", user_file));
+        }
+        if write_store(roles_file, &default_roles(), true).is_err() {
+            return Err(format!("{} can't be created", roles_file));
+        }
+
+        Ok(())
+    }
+
+    // Verified lines of users or roles file, Err - reason they can't be trusted
+    pub fn load_protected(file: &str) -> Result<String, String> {
+        match read_user_store(file)? {
+            UserStore::Verified(content) => Ok(content),
+            UserStore::Unprotected(_) => Err("file is not protected with integrity key".to_string()),
+            UserStore::Missing => Err("file and its integrity key are missing".to_string()),
+        }
+    }
+
+    // Signs and saves lines of users or roles file changed by the server
+    pub fn save_protected(file: &str, content: &str) -> bool {
+        match write_store(file, content, false) {
+            Ok(()) => true,
+            Err(reason) => {
+                console_println!("File {} is not saved: {}", file, reason);
+                false
+            }
+        }
+    }
 
-    pub fn get_user_map(user_file: &str) -> HashMap<String, (String, bool)> {
-        let path = Path::new(user_file);
-        if !path.exists() {
-            println!("User file existance error. Creating default file.");
+    // first_run - file and key are created, otherwise they must exist and pass the check.
+    // File changed outside of the server is left as it is for investigation
+    fn write_store(file: &str, content: &str, first_run: bool) -> Result<(), String> {
+        let key = match read_user_store(file)? {
+            UserStore::Missing if first_run => create_key(file)?,
+            UserStore::Verified(_) if !first_run => match read_key(file)? {
+                Some(key) => key,
+                None => return Err("integrity key is missing".to_string()),
+            },
+            UserStore::Missing => return Err("file and its integrity key are missing".to_string()),
+            UserStore::Verified(_) => return Err("file exists already".to_string()),
+            UserStore::Unprotected(_) => return Err("file is not protected with integrity key".to_string()),
+        };
 
-            let mut f = File::create(path).unwrap();
-            let _ = f.write_all(BASIC_USER.as_bytes());
+        if write_signed(file, &key, content) {
+            Ok(())
+        } else {
+            Err("file can't be written".to_string())
         }
+    }
 
-        let file = File::open(path).expect("File opening error.");
-        let reader = BufReader::new(file);
-        let mut result: HashMap<String, (String, bool)> = HashMap::new();
+    fn write_signed(file: &str, key: &hmac::Key, content: &str) -> bool {
+        let tag = hmac::sign(key, content.as_bytes());
+        replace_private_file(
+            file,
+            &format!("{}{}{}\n", content, INTEGRITY_PREFIX, HEXLOWER.encode(tag.as_ref())),
+        )
+    }
 
-        for line in reader.lines() {
-            let line = line.expect("Line reading error.");
+    // user line: name[:|:]pass_hash[:|:]role[:|:]disabled (0/1)[:|:]must_change_password (0/1)[:|:]
+    // password_changed[:|:]password_history (space separated hashes)[:|:]totp_secret[:|:]totp_last_step[:|:]
+    // recovery_codes (space separated hashes)[:|:]created[:|:]last_logon[:|:]last_failed_logon[:|:]expires
+    // lines of 3, 5, 7 and 10 fields are left from older versions, role was admin flag (0/1) in the first ones.
+    // Nobody can log on with users file changed outside of the server
+    pub fn load_users(user_file: &str) -> Vec<UserAccount> {
+        let content = match load_protected(user_file) {
+            Ok(content) => content,
+            Err(reason) => {
+                console_println!("Users file {} is not loaded: {}", user_file, reason);
+                return Vec::new();
+            }
+        };
+        let mut result: Vec<UserAccount> = Vec::new();
+
+        for line in content.lines().filter(|line| !line.is_empty()) {
             let parts: Vec<&str> = line.split("[:|:]").collect();
-            if parts.len() == 3 {
-                let is_admin = if parts[2] == "1" { true } else { false };
-                result
-                    .entry(parts[0].to_string())
-                    .or_insert_with(|| (parts[1].to_string(), is_admin));
-            } else {
-                println!("Wrong user string format: '{}'", &line);
+            if ![3, 5, 7, 10, 14].contains(&parts.len()) {
+                console_println!("Wrong user string format: '{}'", &line);
                 continue;
             }
+            if result.iter().any(|user| user.name == parts[0]) {
+                console_println!("User {} is defined twice, only the first one is used", parts[0]);
+                continue;
+            }
+            let time_field = |index: usize| -> i64 {
+                if parts.len() == 14 {
+                    parts[index].parse().unwrap_or(0)
+                } else {
+                    0
+                }
+            };
+
+            result.push(UserAccount {
+                name: parts[0].to_string(),
+                pass_hash: parts[1].to_string(),
+                role: match parts[2] {
+                    "1" => ADMIN_ROLE.to_string(),
+                    "0" => BASIC_ROLE.to_string(),
+                    role => role.to_string(),
+                },
+                disabled: parts.len() >= 5 && parts[3] == "1",
+                must_change_password: parts.len() >= 5 && parts[4] == "1",
+                password_changed: if parts.len() >= 7 { parts[5].parse().unwrap_or(0) } else { 0 },
+                password_history: if parts.len() >= 7 {
+                    parts[6].split_whitespace().map(String::from).collect()
+                } else {
+                    Vec::new()
+                },
+                totp_secret: if parts.len() >= 10 { parts[7].to_string() } else { String::new() },
+                totp_last_step: if parts.len() >= 10 { parts[8].parse().unwrap_or(0) } else { 0 },
+                recovery_codes: if parts.len() >= 10 {
+                    parts[9].split_whitespace().map(String::from).collect()
+                } else {
+                    Vec::new()
+                },
+                created: time_field(10),
+                last_logon: time_field(11),
+                last_failed_logon: time_field(12),
+                expires: time_field(13),
+            });
         }
 
         result
     }
+
+    fn default_users() -> Vec<UserAccount> {
+        DEFAULT_USERS
+            .iter()
+            .map(|(name, password, role)| UserAccount {
+                name: name.to_string(),
+                pass_hash: hash_password(password),
+                role: role.to_string(),
+                disabled: false,
+                must_change_password: true,
+                password_changed: 0,
+                password_history: Vec::new(),
+                totp_secret: String::new(),
+                totp_last_step: 0,
+                recovery_codes: Vec::new(),
+                created: Local::now().timestamp(),
+                last_logon: 0,
+                last_failed_logon: 0,
+                expires: 0,
+            })
+            .collect()
+    }
+
+    pub fn get_user_map(user_file: &str) -> HashMap<String, UserAccount> {
+        load_users(user_file)
+            .into_iter()
+            .map(|user| (user.name.clone(), user))
+            .collect()
+    }
+
+    fn users_content(users: &[UserAccount]) -> String {
+        users
+            .iter()
+            .map(|user| {
+                [
+                    user.name.clone(),
+                    user.pass_hash.clone(),
+                    user.role.clone(),
+                    if user.disabled { "1" } else { "0" }.to_string(),
+                    if user.must_change_password { "1" } else { "0" }.to_string(),
+                    user.password_changed.to_string(),
+                    user.password_history.join(" "),
+                    user.totp_secret.clone(),
+                    user.totp_last_step.to_string(),
+                    user.recovery_codes.join(" "),
+                    user.created.to_string(),
+                    user.last_logon.to_string(),
+                    user.last_failed_logon.to_string(),
+                    user.expires.to_string(),
+                ]
+                .join("[:|:]")
+                    + "\n"
+            })
+            .collect()
+    }
+
+    pub fn save_users(user_file: &str, users: &[UserAccount]) -> bool {
+        save_protected(user_file, &users_content(users))
+    }
+
+    // users file of the first start, false - it exists already or can't be written
+    pub fn create_users(user_file: &str, users: &[UserAccount]) -> bool {
+        match write_store(user_file, &users_content(users), true) {
+            Ok(()) => true,
+            Err(reason) => {
+                console_println!("Users file {} is not created: {}", user_file, reason);
+                false
+            }
+        }
+    }
+
+    // false - there is no such user or file can't be saved
+    pub fn update_user(
+        user_file: &str,
+        file_mutexes: &FileMutexes,
+        username: &str,
+        change: impl FnOnce(&mut UserAccount),
+    ) -> bool {
+        let _users_lock = file_mutexes.users_mutex.lock().unwrap();
+        let mut users = load_users(user_file);
+        match users.iter_mut().find(|user| user.name == username) {
+            Some(user) => change(user),
+            None => return false,
+        }
+
+        save_users(user_file, &users)
+    }
+
+    // new content is written next to the file and renamed over it, so users are never lost halfway.
+    // Only owner can read the file: password hashes and failed logons are there
+    pub fn replace_private_file(file_path: &str, content: &str) -> bool {
+        // every write has its own temporary file, two writers never mix their content
+        let temp_path = format!(
+            "{}.{}-{}.tmp",
+            file_path,
+            process::id(),
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let written = options.open(&temp_path).and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|_| fs::rename(&temp_path, file_path)) {
+            Ok(()) => true,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                console_println!("Error while saving {}: {}", file_path, e);
+                false
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::auth::roles::{add_sensor_operator_role, import_roles, load_roles, SENSOR_OPERATOR_ROLE};
+
+        static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+        // users and roles files without keys
+        fn store_paths() -> (String, String) {
+            set_test_key_dir();
+            let number = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+            let path = |kind: &str| {
+                let path = std::env::temp_dir()
+                    .join(format!("rsoc_{}_test_{}_{}.txt", kind, process::id(), number))
+                    .to_string_lossy()
+                    .to_string();
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_file(key_file_path(&path).unwrap());
+                path
+            };
+            (path("users"), path("roles"))
+        }
+
+        #[test]
+        fn keys_are_not_kept_next_to_files() {
+            let users_file = std::env::temp_dir().join("users.txt").to_string_lossy().to_string();
+            assert!(set_key_dir("", &[&users_file]).is_err());
+            assert!(set_key_dir(&std::env::temp_dir().to_string_lossy(), &[&users_file]).is_err());
+
+            let (users_file, _) = store_paths();
+            let key_file = key_file_path(&users_file).unwrap();
+            assert_ne!(Path::new(&key_file).parent(), Path::new(&users_file).parent());
+        }
+
+        #[test]
+        fn init_creates_signed_defaults_once() {
+            let (users_file, roles_file) = store_paths();
+            assert!(init_user_store(&users_file, &roles_file).is_ok());
+            assert_eq!(check_user_store(&users_file, false), Ok(false));
+            assert_eq!(check_user_store(&roles_file, false), Ok(false));
+
+            let names: Vec<String> = load_users(&users_file).into_iter().map(|user| user.name).collect();
+            assert_eq!(names, ["admin", "user"]);
+            assert!(load_roles(&roles_file).contains_key(ADMIN_ROLE));
+
+            // existing files are not replaced
+            assert!(init_user_store(&users_file, &roles_file).is_err());
+            let mut users = load_users(&users_file);
+            users.truncate(1);
+            assert!(save_users(&users_file, &users));
+            assert_eq!(load_users(&users_file).len(), 1);
+        }
+
+        #[test]
+        fn deleted_files_are_not_recreated() {
+            let (users_file, roles_file) = store_paths();
+            assert!(check_user_store(&users_file, false).is_err());
+            assert!(load_users(&users_file).is_empty());
+            assert!(!save_users(&users_file, &default_users()));
+            assert!(load_roles(&roles_file).is_empty());
+            assert!(!fs::exists(&users_file).unwrap());
+            assert!(!fs::exists(&roles_file).unwrap());
+        }
+
+        #[test]
+        fn changed_file_is_rejected() {
+            let (users_file, roles_file) = store_paths();
+            init_user_store(&users_file, &roles_file).unwrap();
+
+            let content = fs::read_to_string(&users_file).unwrap();
+            fs::write(&users_file, content.replacen("[:|:]analyst[:|:]", "[:|:]administrator[:|:]", 1)).unwrap();
+            assert!(check_user_store(&users_file, false).is_err());
+            assert!(load_users(&users_file).is_empty());
+            // the file is left for investigation
+            assert!(!save_users(&users_file, &default_users()));
+
+            let content = fs::read_to_string(&roles_file).unwrap();
+            fs::write(&roles_file, content.replacen("analyst[:|:]", "analyst[:|:]users_manage, ", 1)).unwrap();
+            assert!(check_user_store(&roles_file, false).is_err());
+            assert!(load_roles(&roles_file).is_empty());
+        }
+
+        #[test]
+        fn missing_key_or_integrity_line_is_rejected() {
+            let (users_file, roles_file) = store_paths();
+            init_user_store(&users_file, &roles_file).unwrap();
+            let key = fs::read_to_string(key_file_path(&users_file).unwrap()).unwrap();
+
+            fs::remove_file(key_file_path(&users_file).unwrap()).unwrap();
+            assert!(check_user_store(&users_file, false).is_err());
+            assert!(check_user_store(&users_file, true).is_err());
+            assert!(load_users(&users_file).is_empty());
+
+            fs::write(key_file_path(&users_file).unwrap(), key).unwrap();
+            let content = fs::read_to_string(&users_file).unwrap();
+            let body = &content[..content.find(INTEGRITY_PREFIX).unwrap()];
+            fs::write(&users_file, body).unwrap();
+            assert!(check_user_store(&users_file, false).is_err());
+            assert!(load_users(&users_file).is_empty());
+
+            // key without file
+            fs::remove_file(&users_file).unwrap();
+            assert!(check_user_store(&users_file, false).is_err());
+            assert!(init_user_store(&users_file, &roles_file).is_err());
+        }
+
+        #[test]
+        fn unprotected_file_is_signed_only_on_request() {
+            let (users_file, roles_file) = store_paths();
+            fs::write(&users_file, users_content(&default_users())).unwrap();
+            // roles file of older version without sensor operator role
+            fs::write(&roles_file, "administrator[:|:]users_manage\n").unwrap();
+
+            for file in [&users_file, &roles_file] {
+                assert!(check_user_store(file, false).is_err());
+                assert!(!save_protected(file, "changed\n"));
+            }
+            assert!(load_users(&users_file).is_empty());
+            assert!(load_roles(&roles_file).is_empty());
+
+            for file in [&users_file, &roles_file] {
+                assert_eq!(check_user_store(file, true), Ok(true));
+                assert_eq!(check_user_store(file, true), Ok(false));
+            }
+            assert_eq!(load_users(&users_file).len(), DEFAULT_USERS.len());
+
+            // loading doesn't change the file, 'users sign' adds sensor operator role
+            assert!(!load_roles(&roles_file).contains_key(SENSOR_OPERATOR_ROLE));
+            assert_eq!(add_sensor_operator_role(&roles_file), Ok(true));
+            assert_eq!(add_sensor_operator_role(&roles_file), Ok(false));
+            assert!(load_roles(&roles_file).contains_key(SENSOR_OPERATOR_ROLE));
+        }
+
+        #[test]
+        fn imported_roles_are_checked_and_signed() {
+            let (users_file, roles_file) = store_paths();
+            init_user_store(&users_file, &roles_file).unwrap();
+            let before = load_protected(&roles_file).unwrap();
+
+            assert!(import_roles(&roles_file, "administrator[:|:]users_manage, flying\n").is_err());
+            assert!(import_roles(&roles_file, "analyst[:|:]events_view\n").is_err());
+            assert!(import_roles(&roles_file, "administrator[:|:]users_manage\nadministrator[:|:]\n").is_err());
+            assert_eq!(load_protected(&roles_file).unwrap(), before);
+
+            let content = "administrator[:|:]users_manage, users_view\n\nauditor[:|:]audit_view\n";
+            assert_eq!(import_roles(&roles_file, content), Ok(2));
+            assert_eq!(check_user_store(&roles_file, false), Ok(false));
+            let roles = load_roles(&roles_file);
+            assert_eq!(roles.len(), 2);
+            assert!(roles["auditor"].contains(&crate::structs::soc_structs::Permission::AuditView));
+        }
+    }
+}
+
+// failed logons are kept between server restarts
+pub mod lockout_handler {
+    use crate::console_println;
+    use std::fs;
+
+    use super::user_file_handler::replace_private_file;
+    use crate::structs::soc_structs::LogonFailures;
+
+    // lockout line: user[:|:]failures[:|:]first_failure[:|:]locked_until
+    // It is not signed like users file: counters only slow down guessing and give nobody access,
+    // while a failed check would have to lock out everyone or nobody
+    pub fn load_lockouts(lockout_file: &str) -> Vec<LogonFailures> {
+        // no file - nobody has failed yet
+        let content = fs::read_to_string(lockout_file).unwrap_or_default();
+        let mut result: Vec<LogonFailures> = Vec::new();
+
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let parts: Vec<&str> = line.split("[:|:]").collect();
+            if parts.len() != 4 {
+                console_println!("Wrong lockout string format: '{}'", &line);
+                continue;
+            }
+
+            result.push(LogonFailures {
+                user: parts[0].to_string(),
+                failures: parts[1].parse().unwrap_or(0),
+                first_failure: parts[2].parse().unwrap_or(0),
+                locked_until: parts[3].parse().unwrap_or(0),
+            });
+        }
+
+        result
+    }
+
+    pub fn save_lockouts(lockout_file: &str, lockouts: &[LogonFailures]) -> bool {
+        let content: String = lockouts
+            .iter()
+            .map(|entry| {
+                [
+                    entry.user.clone(),
+                    entry.failures.to_string(),
+                    entry.first_failure.to_string(),
+                    entry.locked_until.to_string(),
+                ]
+                .join("[:|:]")
+                    + "\n"
+            })
+            .collect();
+
+        replace_private_file(lockout_file, &content)
+    }
 }
 
 pub mod audit_handler {
+    use crate::console_println;
     use chrono::offset::Local;
     use chrono::DateTime;
     use std::fs::OpenOptions;
@@ -76,6 +662,12 @@ pub mod audit_handler {
             audit_mutex: Arc::new(Mutex::new(audit_file)),
             event_mutex: Arc::new(Mutex::new(event_file)),
             rules_mutex: Arc::new(Mutex::new(rules_file)),
+            rules_history_mutex: Arc::new(Mutex::new(log_files.rules_history_file.clone())),
+            registry_mutex: Arc::new(Mutex::new(log_files.registry_file.clone())),
+            tokens_mutex: Arc::new(Mutex::new(log_files.tokens_file.clone())),
+            state_mutex: Arc::new(Mutex::new(log_files.state_file.clone())),
+            users_mutex: Arc::new(Mutex::new(())),
+            lockout_mutex: Arc::new(Mutex::new(())),
         }
     }
 
@@ -92,25 +684,24 @@ pub mod audit_handler {
 
                 while top_count > 0 {
                     data_vec.push(strings[size - top_count].to_string());
-                    top_count = top_count - 1;
+                    top_count -= 1;
                 }
 
                 console_output(data_vec);
                 let _ = audit_file.rewind();
-                return;
             }
-            Err(e) => println!("Error occured while reading from audit file: {}", e),
+            Err(e) => console_println!("Error occured while reading from audit file: {}", e),
         }
     }
 
+    // record time is the moment of writing
     pub fn write_audit_event(
-        timestamp: SystemTime,
         host: String,
         user: String,
         event_type: AuditEventType,
         message: String,
         file_mutexes: &FileMutexes,
-        log_file: &String,
+        log_file: &str,
         audit_status: bool,
     ) -> bool {
         if !audit_status {
@@ -118,8 +709,8 @@ pub mod audit_handler {
         }
 
         let mut audit_file = file_mutexes.audit_mutex.lock().unwrap();
-        let time_string: DateTime<Local> = timestamp.into();
-        let params_list = vec![
+        let time_string: DateTime<Local> = SystemTime::now().into();
+        let params_list = [
             time_string.format("%d-%m-%Y %H:%M:%S").to_string(),
             host,
             user,
@@ -139,7 +730,7 @@ pub mod audit_handler {
                     .append(true)
                     .create(true)
                     .read(true)
-                    .open(&log_file)
+                    .open(log_file)
                     .unwrap(),
             );
         }
@@ -152,7 +743,7 @@ pub mod audit_handler {
         host: String,
         user: String,
         file_mutexes: &FileMutexes,
-        log_file: &String,
+        log_file: &str,
     ) -> (bool, bool) {
         let mut audit_stat = audit_status.lock().unwrap();
         *audit_stat = !*audit_stat;
@@ -161,7 +752,6 @@ pub mod audit_handler {
             (
                 true,
                 write_audit_event(
-                    SystemTime::now(),
                     host,
                     user,
                     AuditEventType::AudEnable,
@@ -175,7 +765,6 @@ pub mod audit_handler {
             (
                 false,
                 write_audit_event(
-                    SystemTime::now(),
                     host,
                     user,
                     AuditEventType::AudDisable,
@@ -207,18 +796,17 @@ pub mod audit_handler {
             result += "||\n";
         }
 
-        print!("{}", result + "------------------------------------------------------------------------------------------\n");
+        console_println!("{}", result + "------------------------------------------------------------------------------------------");
     }
 }
 
 pub mod event_handler {
+    use crate::console_println;
     use chrono::offset::Local;
     use chrono::DateTime;
-    use std::collections::HashMap;
     use std::fs::OpenOptions;
     use std::io::{Read, Seek, Write};
     use std::mem;
-    use std::time::SystemTime;
 
     use crate::structs::soc_structs::multithread::FileMutexes;
 
@@ -278,7 +866,7 @@ pub mod event_handler {
                 //refresh file_pointer
                 let _ = event_file.rewind();
             }
-            Err(e) => println!("Error occured while reading from audit file: {}", e),
+            Err(e) => console_println!("Error occured while reading from audit file: {}", e),
         }
     }
 
@@ -288,8 +876,8 @@ pub mod event_handler {
         rule_hash: String,
         is_net_level: bool,
         file_mutexes: &FileMutexes,
-        event_file: &String,
-        host_path: &String,
+        event_file: &str,
+        host_path: &str,
     ) -> bool {
         let mut event_file_mutex = file_mutexes.event_mutex.lock().unwrap();
 
@@ -299,16 +887,19 @@ pub mod event_handler {
             String::from("host")
         };
 
-        let basic_list_string: String = vec![
+        let basic_list_string: String = [
             timestamp.format("%d-%m-%Y %H:%M:%S").to_string(),
             host,
             is_net_rule_string,
             rule_hash,
-            host_path.to_string()
+            host_path.to_string(),
         ]
         .join("[:2:]");
 
-        let result = match writeln!(event_file_mutex, "{}", basic_list_string) {
+        // event is acknowledged to sensor only after it is on disk
+        let result = match writeln!(event_file_mutex, "{}", basic_list_string)
+            .and_then(|_| event_file_mutex.sync_data())
+        {
             Ok(_) => true,
             Err(_e) => false,
         };
@@ -320,7 +911,7 @@ pub mod event_handler {
                     .append(true)
                     .create(true)
                     .read(true)
-                    .open(&event_file)
+                    .open(event_file)
                     .unwrap(),
             );
         }
@@ -347,6 +938,619 @@ pub mod event_handler {
             result += "||\n";
         }
 
-        println!("{}", result);
+        console_println!("{}", result);
+    }
+}
+
+// sensor side: persistent credential issued by management server on enrollment
+pub mod credential_handler {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    // credential file: sensor_id[:|:]secret
+    pub fn load_sensor_credential(credential_file: &str) -> Option<(String, String)> {
+        let content = fs::read_to_string(credential_file).ok()?;
+        let parts: Vec<&str> = content.trim().split("[:|:]").collect();
+
+        if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
+            println!("Wrong credential file format: '{}'", credential_file);
+            return None;
+        }
+
+        Some((parts[0].to_string(), parts[1].to_string()))
+    }
+
+    pub fn save_sensor_credential(credential_file: &str, sensor_id: &str, secret: &str) -> bool {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        match options.open(credential_file) {
+            Ok(mut file) => writeln!(file, "{}[:|:]{}", sensor_id, secret).is_ok(),
+            Err(e) => {
+                println!("Error while saving credential: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// sensor side: events raised while management server is unreachable
+pub mod spool_handler {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+
+    use crate::protocol::messages::SensorMessage;
+
+    // acknowledged lines are left in the file until there are this many of them
+    // and at least as many as events waiting for ack, then the file is compacted
+    const COMPACT_LINES: usize = 1000;
+
+    // spool file: one event per line, seq[:|:]rule_hash[:|:]UNIX-time[:|:]path (" " if net).
+    // First line next[:|:]seq keeps sequence counter when all events are acknowledged.
+    // ack[:|:]seq lines are appended on acknowledgement: events up to seq are sent.
+    pub struct EventSpool {
+        pub spool_file: String,
+        pub limit: usize,
+        pub dropped: u64,
+        // sequence number of the next event
+        pub next_seq: u64,
+        // events not acknowledged by server yet, in the order they were raised
+        events: Vec<SensorMessage>,
+        // events and ack lines in the file which are acknowledged already
+        stale_lines: usize,
+        // opened on the first append, kept open until the file is compacted
+        file: Option<File>,
+        // appended lines are not on disk yet, see sync
+        unsynced: bool,
+    }
+
+    impl EventSpool {
+        // events left from previous run are kept and sent again on next connection
+        pub fn open(spool_file: &str, limit: usize) -> EventSpool {
+            let mut next_seq: u64 = 1;
+            let mut acked_seq: u64 = 0;
+            let mut stale_lines = 0;
+            let mut events: Vec<SensorMessage> = Vec::new();
+            let mut unnumbered: Vec<SensorMessage> = Vec::new();
+
+            if let Ok(content) = fs::read_to_string(spool_file) {
+                for line in content.lines().filter(|line| !line.is_empty()) {
+                    if let Some(counter) = line.strip_prefix("next[:|:]") {
+                        next_seq = next_seq.max(counter.parse().unwrap_or(1));
+                        continue;
+                    }
+                    if let Some(seq) = line.strip_prefix("ack[:|:]") {
+                        acked_seq = acked_seq.max(seq.parse().unwrap_or(0));
+                        stale_lines += 1;
+                        continue;
+                    }
+                    match line_to_event(line) {
+                        Some(event @ SensorMessage::Event { seq: 0, .. }) => unnumbered.push(event),
+                        Some(event) => {
+                            if let SensorMessage::Event { seq, .. } = event {
+                                next_seq = next_seq.max(seq + 1);
+                            }
+                            events.push(event);
+                        }
+                        None => {}
+                    }
+                }
+            }
+
+            let before = events.len();
+            events.retain(|event| event_seq(event) > acked_seq);
+            stale_lines += before - events.len();
+
+            let mut spool = EventSpool {
+                spool_file: spool_file.to_string(),
+                limit,
+                dropped: 0,
+                next_seq: next_seq.max(acked_seq + 1),
+                events,
+                stale_lines,
+                file: None,
+                unsynced: false,
+            };
+
+            // spool of older versions has no sequence numbers
+            for mut event in unnumbered {
+                if let SensorMessage::Event { ref mut seq, .. } = event {
+                    *seq = spool.next_seq;
+                    spool.next_seq += 1;
+                }
+                spool.events.push(event);
+                spool.stale_lines += 1;
+            }
+            if spool.stale_lines > 0 {
+                spool.rewrite();
+            }
+
+            spool
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.events.is_empty()
+        }
+
+        pub fn len(&self) -> usize {
+            self.events.len()
+        }
+
+        pub fn events(&self) -> &[SensorMessage] {
+            &self.events
+        }
+
+        // Numbers the event and stores it until server acknowledges it.
+        // The line is appended, but not synced to disk, see sync.
+        // None if event is dropped: spool is full or can't be written.
+        pub fn push(&mut self, mut event: SensorMessage) -> Option<SensorMessage> {
+            match event {
+                SensorMessage::Event { ref mut seq, .. } => *seq = self.next_seq,
+                _ => return None,
+            }
+
+            if self.events.len() >= self.limit {
+                self.dropped += 1;
+                println!(
+                    "Spool is full ({} events), event dropped. Dropped total: {}",
+                    self.limit, self.dropped
+                );
+                return None;
+            }
+
+            let line = event_to_line(&event)?;
+            if !self.append(&line) {
+                self.dropped += 1;
+                println!("Error while writing to spool file: {}", self.spool_file);
+                return None;
+            }
+
+            self.next_seq += 1;
+            self.events.push(event.clone());
+            Some(event)
+        }
+
+        // Removes events up to seq. Returns number of removed events.
+        // Acknowledgement is appended, the file is compacted only when it is mostly stale.
+        pub fn ack(&mut self, acked_seq: u64) -> u64 {
+            let removed = self
+                .events
+                .iter()
+                .take_while(|event| event_seq(event) <= acked_seq)
+                .count();
+            if removed == 0 {
+                return 0;
+            }
+            self.events.drain(..removed);
+            self.stale_lines += removed + 1;
+
+            // lost ack line only makes the events go to server again
+            if self.stale_lines >= COMPACT_LINES.max(self.events.len())
+                || !self.append(&format!("ack[:|:]{}", acked_seq))
+            {
+                self.rewrite();
+            }
+            removed as u64
+        }
+
+        // Puts appended lines on disk. Called once per sent batch or heartbeat, not per event.
+        pub fn sync(&mut self) -> bool {
+            if !self.unsynced {
+                return true;
+            }
+            match self.file.as_ref().map(|file| file.sync_data()) {
+                Some(Ok(_)) => {
+                    self.unsynced = false;
+                    true
+                }
+                _ => {
+                    println!("Error while syncing spool file: {}", self.spool_file);
+                    false
+                }
+            }
+        }
+
+        // server has written events up to last_event_seq: new events must go after it
+        pub fn sync_seq(&mut self, last_event_seq: u64) {
+            if self.next_seq <= last_event_seq {
+                self.next_seq = last_event_seq + 1;
+                self.rewrite();
+            }
+        }
+
+        fn append(&mut self, line: &str) -> bool {
+            if self.file.is_none() {
+                self.file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.spool_file)
+                    .ok();
+            }
+            let written = match self.file.as_mut() {
+                Some(file) => writeln!(file, "{}", line).is_ok(),
+                None => false,
+            };
+            if written {
+                self.unsynced = true;
+            } else {
+                // opened again on next append
+                self.file = None;
+            }
+            written
+        }
+
+        // Writes events waiting for ack to a new file which replaces the spool,
+        // so a crash in the middle leaves the old spool whole.
+        fn rewrite(&mut self) -> bool {
+            let mut content = format!("next[:|:]{}\n", self.next_seq);
+            for line in self.events.iter().filter_map(event_to_line) {
+                content.push_str(&line);
+                content.push('\n');
+            }
+
+            let tmp_file = format!("{}.tmp", self.spool_file);
+            let result = File::create(&tmp_file)
+                .and_then(|mut file| {
+                    file.write_all(content.as_bytes())?;
+                    file.sync_all()
+                })
+                .and_then(|_| fs::rename(&tmp_file, &self.spool_file));
+
+            // appends go to the new file
+            self.file = None;
+            match result {
+                Ok(_) => {
+                    self.stale_lines = 0;
+                    self.unsynced = false;
+                    true
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&tmp_file);
+                    println!("Error while rewriting spool file: {}", e);
+                    false
+                }
+            }
+        }
+    }
+
+    // Events the sensor had no room for in memory, in the order they were raised.
+    // They are not numbered yet, the sender takes them all and puts them in spool.
+    pub struct SpillFile {
+        pub spill_file: String,
+        pub limit: usize,
+        pub dropped: u64,
+        count: usize,
+        file: Option<File>,
+    }
+
+    impl SpillFile {
+        // events left from previous run come first
+        pub fn open(spill_file: &str, limit: usize) -> SpillFile {
+            let count = fs::read_to_string(spill_file)
+                .map(|content| content.lines().filter(|line| !line.is_empty()).count())
+                .unwrap_or(0);
+
+            SpillFile {
+                spill_file: spill_file.to_string(),
+                limit,
+                dropped: 0,
+                count,
+                file: None,
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.count == 0
+        }
+
+        // false if event is dropped: spill file is full or can't be written
+        pub fn append(&mut self, event: &SensorMessage) -> bool {
+            if self.count >= self.limit {
+                self.dropped += 1;
+                return false;
+            }
+            let line = match event_to_line(event) {
+                Some(line) => line,
+                None => return false,
+            };
+
+            if self.file.is_none() {
+                self.file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.spill_file)
+                    .ok();
+            }
+            match self.file.as_mut().map(|file| writeln!(file, "{}", line)) {
+                Some(Ok(_)) => {
+                    self.count += 1;
+                    true
+                }
+                _ => {
+                    self.file = None;
+                    self.dropped += 1;
+                    false
+                }
+            }
+        }
+
+        // All spilled events, the file is emptied
+        pub fn take(&mut self) -> Vec<SensorMessage> {
+            if self.count == 0 {
+                return Vec::new();
+            }
+
+            self.file = None;
+            let events = fs::read_to_string(&self.spill_file)
+                .map(|content| {
+                    content
+                        .lines()
+                        .filter(|line| !line.is_empty())
+                        .filter_map(line_to_event)
+                        .collect()
+                })
+                .unwrap_or_default();
+            if let Err(e) = fs::remove_file(&self.spill_file) {
+                println!("Error while removing spill file: {}", e);
+            }
+            self.count = 0;
+            events
+        }
+    }
+
+    fn event_seq(event: &SensorMessage) -> u64 {
+        match event {
+            SensorMessage::Event { seq, .. } => *seq,
+            _ => 0,
+        }
+    }
+
+    fn event_to_line(event: &SensorMessage) -> Option<String> {
+        match event {
+            SensorMessage::Event {
+                seq,
+                rule_hash,
+                timestamp,
+                path,
+            } => Some(
+                [
+                    seq.to_string(),
+                    rule_hash.clone(),
+                    timestamp.to_string(),
+                    path.clone().unwrap_or_else(|| " ".to_string()),
+                ]
+                .join("[:|:]"),
+            ),
+            _ => None,
+        }
+    }
+
+    // lines of 3 fields are left from older versions, they get seq 0
+    fn line_to_event(line: &str) -> Option<SensorMessage> {
+        let mut parts: Vec<&str> = line.split("[:|:]").collect();
+        let seq = match parts.len() {
+            4 => parts.remove(0).parse().ok()?,
+            3 => 0,
+            _ => {
+                println!("Wrong spool string format: '{}'", line);
+                return None;
+            }
+        };
+
+        Some(SensorMessage::Event {
+            seq,
+            rule_hash: parts[0].to_string(),
+            timestamp: parts[1].parse().ok()?,
+            path: if parts[2] == " " {
+                None
+            } else {
+                Some(parts[2].to_string())
+            },
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+        fn spool_path() -> String {
+            let number = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!(
+                "rsoc_spool_test_{}_{}.txt",
+                std::process::id(),
+                number
+            ));
+            let _ = fs::remove_file(&path);
+            path.to_string_lossy().to_string()
+        }
+
+        fn event(rule_hash: &str) -> SensorMessage {
+            SensorMessage::Event {
+                seq: 0,
+                rule_hash: rule_hash.to_string(),
+                timestamp: 1_700_000_000,
+                path: Some("/tmp/file".to_string()),
+            }
+        }
+
+        fn seqs(spool: &EventSpool) -> Vec<u64> {
+            spool.events().iter().map(event_seq).collect()
+        }
+
+        #[test]
+        fn push_numbers_events_and_respects_limit() {
+            let path = spool_path();
+            let mut spool = EventSpool::open(&path, 2);
+
+            assert!(spool.push(event("a")).is_some());
+            assert!(spool.push(event("b")).is_some());
+            assert!(spool.push(event("c")).is_none());
+            assert_eq!(seqs(&spool), vec![1, 2]);
+            assert_eq!(spool.dropped, 1);
+            assert!(spool.sync());
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn ack_removes_events_up_to_seq() {
+            let path = spool_path();
+            let mut spool = EventSpool::open(&path, 10);
+            for rule in ["a", "b", "c", "d"] {
+                spool.push(event(rule));
+            }
+
+            assert_eq!(spool.ack(2), 2);
+            assert_eq!(spool.ack(2), 0);
+            assert_eq!(seqs(&spool), vec![3, 4]);
+            assert_eq!(spool.ack(10), 2);
+            assert!(spool.is_empty());
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn reopened_spool_keeps_unacknowledged_events() {
+            let path = spool_path();
+            let mut spool = EventSpool::open(&path, 10);
+            for rule in ["a", "b", "c"] {
+                spool.push(event(rule));
+            }
+            spool.ack(1);
+            drop(spool);
+
+            let mut spool = EventSpool::open(&path, 10);
+            assert_eq!(seqs(&spool), vec![2, 3]);
+            assert_eq!(spool.next_seq, 4);
+            // stale lines are compacted on open
+            let content = fs::read_to_string(&path).unwrap();
+            assert_eq!(content.lines().count(), 3);
+            assert!(content.starts_with("next[:|:]4\n"));
+
+            spool.ack(3);
+            drop(spool);
+            let spool = EventSpool::open(&path, 10);
+            assert!(spool.is_empty());
+            assert_eq!(spool.next_seq, 4);
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn many_acks_compact_the_file() {
+            let path = spool_path();
+            let mut spool = EventSpool::open(&path, COMPACT_LINES * 2);
+            for _ in 0..COMPACT_LINES {
+                let evt = spool.push(event("a")).unwrap();
+                spool.ack(event_seq(&evt));
+            }
+
+            let content = fs::read_to_string(&path).unwrap();
+            assert!(content.lines().count() < COMPACT_LINES);
+            assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+            drop(spool);
+            assert_eq!(EventSpool::open(&path, 10).next_seq, COMPACT_LINES as u64 + 1);
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn sync_seq_moves_counter_past_server() {
+            let path = spool_path();
+            let mut spool = EventSpool::open(&path, 10);
+            spool.sync_seq(41);
+            assert_eq!(spool.push(event("a")).map(|evt| event_seq(&evt)), Some(42));
+            drop(spool);
+            assert_eq!(EventSpool::open(&path, 10).next_seq, 43);
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn spilled_events_are_taken_in_order() {
+            let path = spool_path();
+            let mut spill = SpillFile::open(&path, 2);
+            assert!(spill.append(&event("a")));
+            assert!(spill.append(&event("b")));
+            assert!(!spill.append(&event("c")));
+            assert_eq!(spill.dropped, 1);
+
+            // left from previous run
+            let mut spill = SpillFile::open(&path, 2);
+            assert!(!spill.is_empty());
+            let hashes: Vec<String> = spill
+                .take()
+                .into_iter()
+                .filter_map(|evt| match evt {
+                    SensorMessage::Event { rule_hash, .. } => Some(rule_hash),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(hashes, vec!["a", "b"]);
+            assert!(spill.is_empty());
+            assert!(!std::path::Path::new(&path).exists());
+            assert!(spill.append(&event("d")));
+
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn old_spool_without_seq_is_numbered() {
+            let path = spool_path();
+            fs::write(
+                &path,
+                "hash1[:|:]1700000000[:|:]/tmp/a\nhash2[:|:]1700000001[:|:] \n",
+            )
+            .unwrap();
+
+            let spool = EventSpool::open(&path, 10);
+            assert_eq!(seqs(&spool), vec![1, 2]);
+            assert!(matches!(
+                &spool.events()[1],
+                SensorMessage::Event { path: None, .. }
+            ));
+            // numbers are saved, the next run sees the same ones
+            drop(spool);
+            assert_eq!(seqs(&EventSpool::open(&path, 10)), vec![1, 2]);
+
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+// "key: value;" config files, lines starting with '#' are comments
+pub mod config_handler {
+    use std::fs;
+    use std::io;
+
+    pub fn read_config(config_file: &str) -> io::Result<Vec<(String, String)>> {
+        let buf = fs::read_to_string(config_file)?;
+        let mut result: Vec<(String, String)> = Vec::new();
+
+        for line in buf.split("\n") {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with("#") {
+                continue;
+            }
+
+            // value can contain ':' itself (addresses), so split by the first one
+            if let Some((key, value)) = line.split_once(':') {
+                let key = key.trim();
+                let value = value.trim().trim_end_matches(';');
+                result.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        Ok(result)
     }
 }
@@ -1 +1,3 @@
+// crate::file_manager::file_manager::... paths are used across the crate and binaries
+#[allow(clippy::module_inception)]
 pub mod file_manager;
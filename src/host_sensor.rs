@@ -1,19 +1,14 @@
-mod auth;
-mod file_manager;
-mod menu;
-mod sensor_handler;
-mod structs;
-
 use chrono::offset::Local;
 use chrono::DateTime;
-use clap::{Arg, Command};
+use clap::{value_parser, Arg, Command};
 use notify::event::RenameMode;
-use std::fs::OpenOptions;
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-use tokio::time::{sleep, Duration};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
 
 // dir watching
 use notify::{recommended_watcher, EventKind, RecursiveMode, Watcher};
@@ -23,8 +18,18 @@ use std::fs::File;
 use std::path::Path;
 use std::sync::mpsc::channel;
 
-use crate::menu::menu::get_user_choice;
-use crate::sensor_handler::rule_handler::get_rules_map;
+use rsoc::auth::auth::logon_password;
+use rsoc::menu::menu::get_user_choice;
+use rsoc::protocol::framing::{recv_message, send_message};
+use rsoc::file_manager::file_manager::config_handler::read_config;
+use rsoc::file_manager::file_manager::credential_handler::load_sensor_credential;
+use rsoc::file_manager::file_manager::spool_handler::EventSpool;
+use rsoc::protocol::messages::{RateLimit, RulesState, SensorCommand, SensorMessage, ServerMessage};
+use rsoc::sensor_handler::rule_bundle::RuleReceiver;
+use rsoc::sensor_handler::rule_handler::{get_rules_map, parse_rules, LevelRules};
+use rsoc::sensor_handler::rate_limiter::EventThrottle;
+use rsoc::sensor_handler::sensor_link::{check_throttle, open_session, reconnect, replay_spool, update_rules, Batching, SensorLink};
+use rsoc::tls::tls_config::{sensor_tls_connector, TlsFiles};
 
 const CONFIG: &str = "host_sensor_config.txt";
 
@@ -38,189 +43,519 @@ async fn main() {
                  .short('c')
                  .long("command")
                  .help("Type 'update' to update sensor rules. (BTW now you can type anything to update)"))     
+        .arg(Arg::new("password_env")
+                 .long("password-env")
+                 .value_name("VARIABLE")
+                 .conflicts_with("password_fd")
+                 .help("Read password of sensor operator from environment variable, prompt is used if no password option is set"))
+        .arg(Arg::new("password_fd")
+                 .long("password-fd")
+                 .value_name("FD")
+                 .value_parser(value_parser!(u32))
+                 .help("Read password of sensor operator from the first line of opened file descriptor, e.g. --password-fd 3 3<password_file"))
         .get_matches();
 
     let mut sensor_name: String = String::new();
     let mut username: String = String::new();
     let mut rules_file: String = String::new();
+    let mut tls_ca_cert: String = String::new();
+    let mut tls_cert: String = String::new();
+    let mut tls_key: String = String::new();
+    let mut enrollment_token: String = String::new();
+    let mut credential_file: String = String::new();
+    let mut heartbeat_interval: u64 = 10;
+    let mut spool_file: String = String::new();
+    let mut spool_limit: usize = 10000;
+    let mut reconnect_max_delay: u64 = 60;
+    let mut ack_timeout: u64 = 30;
     let mut control_path: String = String::new();
 
     let level: String = String::from("host");
 
     // config parcing
-    {
-        let mut conf_file = OpenOptions::new()
-            .read(true) // Позволяем чтение
-            .open(CONFIG)
-            .unwrap();
-        let buf: &mut String = &mut "".to_owned();
-
-        match (conf_file).read_to_string(buf) {
-            Ok(_) => {
-                let strings = buf.split("\n");
-
-                for line in strings {
-                    if line.trim().is_empty() {
-                        continue;
-                    }
-                    if line.starts_with("#") {
+    match read_config(CONFIG) {
+        Ok(config) => {
+            for (key, value) in config {
+                match key.as_str() {
+                    "sensor_name" => sensor_name = value,
+                    "username" => username = value,
+                    "rules_file" => rules_file = value,
+                    "tls_ca_cert" => tls_ca_cert = value,
+                    "tls_cert" => tls_cert = value,
+                    "tls_key" => tls_key = value,
+                    "enrollment_token" => enrollment_token = value,
+                    "credential_file" => credential_file = value,
+                    "heartbeat_interval" => heartbeat_interval = value.parse().unwrap_or(10),
+                    "spool_file" => spool_file = value,
+                    "spool_limit" => spool_limit = value.parse().unwrap_or(10000),
+                    "reconnect_max_delay" => reconnect_max_delay = value.parse().unwrap_or(60),
+                    "ack_timeout" => ack_timeout = value.parse().unwrap_or(30),
+                    "control_path" => control_path = value,
+                    _ => println!("Weird parameter: {}", key),
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+        }
+    }
+
+    if enrollment_token.is_empty() && load_sensor_credential(&credential_file).is_none() {
+        println!("Sensor is not enrolled. Create enrollment token in management server console and put it in 'enrollment_token' field of sensor config.");
+        return;
+    }
+
+    // operator is checked by management server on every connection
+    let password = match logon_password(&matches, &format!("Password of sensor operator {}: ", username)) {
+        Some(password) => password,
+        None => {
+            println!("Password is not entered.");
+            return;
+        }
+    };
+
+    println!("Enter address (IP:port, [IPv6]:port or unix:/path/to/socket) of management server:");
+    let mgmt_server = get_user_choice();
+
+    let tls_files = TlsFiles {
+        ca_cert: tls_ca_cert,
+        cert: tls_cert,
+        key: tls_key,
+    };
+    let tls_connector = match sensor_tls_connector(&tls_files) {
+        Ok(connector) => connector,
+        Err(e) => {
+            println!("Failed to set up TLS: {}", e);
+            return;
+        }
+    };
+
+    let link = SensorLink {
+        address: mgmt_server,
+        connector: tls_connector,
+        name: sensor_name,
+        level: level.clone(),
+        username,
+        password,
+        rules_file: rules_file.clone(),
+        credential_file,
+        enrollment_token,
+    };
+
+    // server may be down at start, then sensor works offline and connects later
+    let mut stream = match open_session(&link).await {
+        Ok(stream) => Some(stream),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied || matches.contains_id("rules_update") => {
+            eprintln!("Failed to connect: {}", e);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to connect: {}. Events will be spooled until connection is restored.", e);
+            None
+        }
+    };
+
+    if matches.contains_id("rules_update") {
+        let (mut stream, _, _) = stream.unwrap();
+        let mut receiver = RuleReceiver::load(&rules_file, &level);
+        match update_rules(&mut stream, &mut receiver).await {
+            Ok(rules) => println!("Rules updated succesfully! Version {}", rules),
+            Err(e) => println!("Rules are not updated: {}", e),
+        }
+
+        return;
+    }
+
+    let (tx, rx) = channel();
+    let mut watcher;
+
+    match recommended_watcher(tx) {
+        Ok(wtr) => {
+            watcher = wtr;
+        }
+        Err(e) => {
+            println!("Failed to set up directory watcher: {}", e);
+            return;
+        }
+    }
+
+    match watcher.watch(Path::new(&control_path), RecursiveMode::Recursive) {
+        Ok(_) => {}
+        Err(e) => {
+            println!("Failed to bind to directory: {}", e);
+            return;
+        }
+    }
+
+    let rules_mutex = Arc::new(Mutex::new(
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .read(true)
+            .open(rules_file)
+            .unwrap(),
+    ));
+
+    let mut all_rules_map = get_rules_map(&rules_mutex);
+    let rules_vec = match all_rules_map.remove(level.as_str()) {
+        Some(data_vec) => data_vec,
+        None => {
+            println!("Error with parcing rules. Check rules file.");
+            return;
+        }
+    };
+    // rules pushed by server replace this set on the fly
+    let active_rules = Arc::new(RwLock::new(rules_vec));
+    let thread_rules = Arc::clone(&active_rules);
+
+    // stop/start from server pauses detection without stopping the watcher
+    let capturing = Arc::new(AtomicBool::new(true));
+    let thread_capturing = Arc::clone(&capturing);
+
+    // directory watching is blocking, so it lives in its own thread and hands events over
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<SensorMessage>();
+    let thread_event_tx = event_tx.clone();
+    thread::spawn(move || {
+        // keep watcher alive while thread is working
+        let _watcher = watcher;
+
+        loop {
+            match rx.recv() {
+                Ok(Ok(evt)) => {
+                    if !thread_capturing.load(Ordering::Relaxed) {
                         continue;
                     }
 
-                    let parts: Vec<&str> = line.split(':').collect();
-                    if parts.len() == 2 {
-                        let key = parts[0].trim();
-                        let value = parts[1].trim().trim_end_matches(';');
-
-                        match key {
-                            "sensor_name" => sensor_name = value.to_string(),
-                            "username" => username = value.to_string(),
-                            "rules_file" => rules_file = value.to_string(),
-                            "control_path" => control_path = value.to_string(),
-                            _ => println!("Weird parameter: {}", key),
+                    if evt.kind
+                        == EventKind::Access(notify::event::AccessKind::Close(
+                            notify::event::AccessMode::Write,
+                        ))
+                        || evt.kind == EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                    {
+                        // rules are copied, so rule update doesn't wait for the scan
+                        let rules_vec = thread_rules.read().unwrap().clone();
+                        if !scan_file(&evt.paths[0], &rules_vec, &thread_event_tx) {
+                            return;
                         }
                     }
                 }
+                Ok(Err(e)) => println!("Client handling error: {}", e),
+                Err(e) => {
+                    eprintln!("Ошибка: {:?}", e);
+                    return;
+                }
             }
-            Err(e) => {
-                println!("{}", e);
+        }
+    });
+
+    // heartbeat lets the server notice a hung or silently dead sensor
+    let started = Instant::now();
+    let mut events_detected: u64 = 0;
+    let mut events_sent: u64 = 0;
+    let mut spool = EventSpool::open(&spool_file, spool_limit);
+    let mut rule_receiver = RuleReceiver::load(&link.rules_file, &level);
+    // limit comes from server, there is none until the first connection
+    let mut throttle = EventThrottle::new(RateLimit::default());
+
+    loop {
+        let (mut server, last_event_seq, rate_limit) = match stream.take() {
+            Some(session) => session,
+            None => match reconnect(&link, &mut event_rx, &mut spool, Duration::from_secs(reconnect_max_delay), &mut events_detected, &mut throttle).await {
+                Ok(session) => session,
+                Err(e) => {
+                    println!("Can't connect to management server: {}. Stop working...", e);
+                    return;
+                }
+            },
+        };
+        spool.sync_seq(last_event_seq);
+        if throttle.limit() != rate_limit {
+            throttle.set_limit(rate_limit);
+            println!("Event rate limit: {}", rate_limit);
+        }
+
+        // events stay in spool until server confirms they are written
+        let mut awaiting_ack: Option<Instant> = None;
+        match replay_spool(&mut server, &spool, Batching::none()).await {
+            Ok(0) => {}
+            Ok(_) => awaiting_ack = Some(Instant::now()),
+            Err(_) => {
+                println!("Troubles with connection. Reconnecting...");
+                continue;
             }
         }
-    }
 
-    println!("Enter address (IP:port) of management server:");
-    let mgmt_server = get_user_choice();
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_interval.max(1)));
 
-    match TcpStream::connect(mgmt_server.as_str()) {
-        Ok(mut stream) => {
-            let init_message = sensor_name + "[:1:]" + level.as_str() + "[:1:]" + username.as_str();
-            let init_message_byte_fmt = init_message.as_bytes();
-            let mut buffer = [0; 1024];
-            stream.write(init_message_byte_fmt).unwrap();
-
-            let _ = sleep(Duration::from_secs(1)).await;
-            if matches.contains_id("rules_update") {
-                stream.write(b"update").unwrap();
-                let size = stream.read(&mut buffer).unwrap();
-                match size {
-                    0 => {
-                        println!("Server disconnected. Stop working...");
+        loop {
+            tokio::select! {
+                event = event_rx.recv() => match event {
+                    Some(evt @ SensorMessage::Event { .. }) => {
+                        events_detected += 1;
+                        let admitted = throttle.admit();
+                        check_throttle(&mut throttle);
+                        if !admitted {
+                            continue;
+                        }
+                        let evt = match spool.push(evt) {
+                            Some(evt) => evt,
+                            None => continue,
+                        };
+                        if send_message(&mut server, &evt).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                        awaiting_ack.get_or_insert_with(Instant::now);
+                    }
+                    Some(evt) => {
+                        if send_message(&mut server, &evt).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                    }
+                    None => return,
+                },
+                _ = heartbeat.tick() => {
+                    // events are sent one by one, spool goes to disk once per heartbeat
+                    spool.sync();
+                    check_throttle(&mut throttle);
+                    let beat = SensorMessage::Heartbeat {
+                        uptime_secs: started.elapsed().as_secs(),
+                        events_detected,
+                        events_sent,
+                        events_dropped: throttle.over_limit,
+                        capturing: capturing.load(Ordering::Relaxed),
+                        throttled: throttle.throttled,
+                    };
+                    if send_message(&mut server, &beat).await.is_err() {
+                        println!("Troubles with connection. Reconnecting...");
+                        break;
+                    }
+
+                    // no acknowledgement for too long: server could fail to write events
+                    if awaiting_ack.is_some_and(|since| since.elapsed() >= Duration::from_secs(ack_timeout)) {
+                        if replay_spool(&mut server, &spool, Batching::none()).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                        awaiting_ack = Some(Instant::now());
+                    }
+                },
+                message = recv_message::<_, ServerMessage>(&mut server) => match message {
+                    Ok(Some(ServerMessage::Rejected { reason })) => {
+                        println!("Connection closed by server: {}. Stop working...", reason);
+                        return;
                     }
-                    _ => {
-                        let mut rules_file = OpenOptions::new()
-                            .write(true)
-                            .truncate(true)
-                            .create(true)
-                            .read(true)
-                            .open(rules_file)
-                            .unwrap();
-
-                        match write!(rules_file, "{}", String::from_utf8_lossy(&buffer[..size])) {
-                            Ok(_) => {
-                                println!("Rules updated succesfully!")
+                    Ok(Some(ServerMessage::Shutdown { reason })) => {
+                        println!("Management server is shutting down: {}. Events will be spooled until connection is restored", reason);
+                        break;
+                    }
+                    Ok(Some(ServerMessage::EventAck { seq })) => {
+                        events_sent += spool.ack(seq);
+                        awaiting_ack = if spool.is_empty() { None } else { Some(Instant::now()) };
+                    }
+                    Ok(Some(chunk @ ServerMessage::RulesChunk { .. })) => {
+                        // rules are swapped only when the whole bundle is received and checked
+                        let answer = match rule_receiver.receive(chunk) {
+                            Ok(Some(rules)) => {
+                                *active_rules.write().unwrap() = parse_rules(&rule_receiver.rules_text()).remove(level.as_str()).unwrap_or_default();
+                                println!("Rules updated to version {}", rules);
+                                SensorMessage::RulesApplied { rules }
                             }
-                            Err(e) => {
-                                println!("Error while writing to rule file: {}", e)
+                            Ok(None) => continue,
+                            Err(reason) => {
+                                println!("Rules bundle rejected: {}. Requesting full rule set", reason);
+                                SensorMessage::RulesRequest { rules: RulesState::default() }
                             }
                         };
 
-                        return;
+                        if send_message(&mut server, &answer).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
                     }
-                }
-            }
+                    Ok(Some(ServerMessage::Command { command_id, command })) => {
+                        println!("Command from server: {}", command);
 
-            let (tx, rx) = channel();
-            let mut watcher;
-        
-            match recommended_watcher(tx) {
-                Ok(wtr) => { watcher = wtr; },
-                Err(e) => { println!("Failed to set up directory watcher: {}", e); return; }
-            }
-        
-            match watcher.watch(Path::new(&control_path), RecursiveMode::Recursive) {
-                Ok(_) => {},
-                Err(e) => { println!("Failed to bind to directory: {}", e); return; }
-            }
-
-            let rules_mutex = Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .read(true)
-                    .open(rules_file)
-                    .unwrap(),
-            ));
-
-            if let Some(_) = get_rules_map(&rules_mutex).get(level.as_str()) {
-                // rules_vec = data_vec;
-            } else {
-                println!("Error with parcing rules. Check rules file.");
-                return;
-            }
-
-            let all_rules_map = get_rules_map(&rules_mutex);
-            let rules_vec = all_rules_map.get(level.as_str()).unwrap();
-
-            loop {
-                match rx.recv() {
-                    Ok(event) => {
-                        match event {
-                            Ok(evt) => {
-                                if evt.kind == EventKind::Access(notify::event::AccessKind::Close(notify::event::AccessMode::Write)) || evt.kind == EventKind::Modify(ModifyKind::Name(RenameMode::To))  {
-                                    let file_path = &evt.paths[0];
-                                    let mut file = File::open(file_path).unwrap();
-                                    let mut contents = String::new();
-                                    let _ = file.read_to_string(&mut contents);
-
-                                    for rule in rules_vec {
-                                        for pairs_vector_with_hash in rule {
-                                            for pair in pairs_vector_with_hash.1 {
-                                                if pair.0 == "payload" {
-                                                    let regex = Regex::new(pair.1.as_str()).unwrap();
-
-                                                    if regex.is_match(&contents) {
-                                                        let timestamp: DateTime<Local> = SystemTime::now().into();
-                                                        let cmd_string = "event".to_string()
-                                                            + "[:3:]"
-                                                            + &pairs_vector_with_hash.0
-                                                            + "[:3:]"
-                                                            + &timestamp.timestamp().to_string()
-                                                            + "[:3:]"
-                                                            + format!("{}", file_path.canonicalize().unwrap().display()).as_str();
-                                                        
-                                                        let cmd_string_byte_fmt = cmd_string.as_bytes();
-                                                        match stream.write(cmd_string_byte_fmt) {
-                                                            Ok(_) => {}
-                                                            Err(_) => {
-                                                                println!(
-                                                                    "Troubles with connection. Stop working..."
-                                                                );
-                                                                return;
-                                                            }
-                                                        }
-
-                                                        println!(
-                                                            "Catch event! Rule hash: {} | Time: {} | Path: {}",
-                                                            pairs_vector_with_hash.0,
-                                                            timestamp.format("%d-%m-%Y %H:%M:%S"),
-                                                            format!("{}", file_path.canonicalize().unwrap().display()).as_str()
-                                                        );
-                                                    }                                          
-                                                }
-                                            }
+                        let (success, output) = match command {
+                            SensorCommand::Start => {
+                                capturing.store(true, Ordering::Relaxed);
+                                (true, "detection started".to_string())
+                            }
+                            SensorCommand::Stop => {
+                                capturing.store(false, Ordering::Relaxed);
+                                (true, "detection stopped".to_string())
+                            }
+                            SensorCommand::RescanNow if !capturing.load(Ordering::Relaxed) => {
+                                (false, "detection is stopped".to_string())
+                            }
+                            SensorCommand::RescanNow => {
+                                // result is sent by scanning thread through event channel
+                                // the walk can be long, rules lock is not held while it goes on
+                                let scan_rules = active_rules.read().unwrap().clone();
+                                let scan_event_tx = event_tx.clone();
+                                let scan_path = control_path.clone();
+                                thread::spawn(move || {
+                                    let scanned = rescan_directory(Path::new(&scan_path), &scan_rules, &scan_event_tx);
+                                    let _ = scan_event_tx.send(SensorMessage::CommandResult {
+                                        command_id,
+                                        success: true,
+                                        output: format!("{} files scanned in {}", scanned, scan_path),
+                                    });
+                                });
+                                continue;
+                            }
+                            SensorCommand::ReportStatus => (
+                                true,
+                                format!(
+                                    "capturing: {}, rules version: {}, uptime: {}s, events detected: {}, sent: {}, dropped: {}, not acknowledged: {}",
+                                    capturing.load(Ordering::Relaxed),
+                                    rule_receiver.state,
+                                    started.elapsed().as_secs(),
+                                    events_detected,
+                                    events_sent,
+                                    throttle.over_limit,
+                                    spool.len()
+                                ),
+                            ),
+                            SensorCommand::ReloadConfig => match read_config(CONFIG) {
+                                Ok(config) => {
+                                    for (key, value) in config {
+                                        match key.as_str() {
+                                            "heartbeat_interval" => heartbeat_interval = value.parse().unwrap_or(heartbeat_interval),
+                                            "spool_limit" => spool.limit = value.parse().unwrap_or(spool.limit),
+                                            "reconnect_max_delay" => reconnect_max_delay = value.parse().unwrap_or(reconnect_max_delay),
+                                            "ack_timeout" => ack_timeout = value.parse().unwrap_or(ack_timeout),
+                                            _ => {}
                                         }
                                     }
+                                    heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_interval.max(1)));
+
+                                    rule_receiver = RuleReceiver::load(&link.rules_file, &level);
+                                    *active_rules.write().unwrap() = parse_rules(&rule_receiver.rules_text()).remove(level.as_str()).unwrap_or_default();
+
+                                    (
+                                        true,
+                                        format!(
+                                            "heartbeat_interval: {}, spool_limit: {}, reconnect_max_delay: {}, ack_timeout: {}, rules version: {}. Other settings are applied after restart",
+                                            heartbeat_interval, spool.limit, reconnect_max_delay, ack_timeout, rule_receiver.state
+                                        ),
+                                    )
                                 }
+                                Err(e) => (false, format!("can't read {}: {}", CONFIG, e)),
                             },
-                            Err(e) => { println!("Client handling error: {}", e) }
+                        };
+
+                        let result = SensorMessage::CommandResult { command_id, success, output };
+                        if send_message(&mut server, &result).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
                         }
                     }
-                    Err(e) => eprintln!("Ошибка: {:?}", e),
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        println!("Server disconnected. Reconnecting...");
+                        break;
+                    }
+                    Err(_) => {
+                        println!("Troubles with connection. Reconnecting...");
+                        break;
+                    }
                 }
             }
         }
-        Err(e) => {
-            eprintln!("Failed to connect: {}", e);
+    }
+}
+
+// Match file content against rules and hand events over.
+// Returns false if the connection side is gone.
+fn scan_file(
+    file_path: &Path,
+    rules_vec: &LevelRules,
+    event_tx: &mpsc::UnboundedSender<SensorMessage>,
+) -> bool {
+    let mut file = match File::open(file_path) {
+        Ok(f) => f,
+        Err(_) => return true,
+    };
+    let mut contents = String::new();
+    let _ = file.read_to_string(&mut contents);
+    let full_path = match file_path.canonicalize() {
+        Ok(p) => format!("{}", p.display()),
+        Err(_) => format!("{}", file_path.display()),
+    };
+
+    for rule in rules_vec {
+        for pairs_vector_with_hash in rule {
+            for pair in pairs_vector_with_hash.1 {
+                if pair.0 == "payload" {
+                    let regex = match Regex::new(pair.1.as_str()) {
+                        Ok(regex) => regex,
+                        Err(e) => {
+                            println!("Wrong payload of rule {}, it is skipped: {}", pairs_vector_with_hash.0, e);
+                            continue;
+                        }
+                    };
+
+                    if regex.is_match(&contents) {
+                        let timestamp: DateTime<Local> = SystemTime::now().into();
+                        // sequence number is given by spool
+                        let event = SensorMessage::Event {
+                            seq: 0,
+                            rule_hash: pairs_vector_with_hash.0.clone(),
+                            timestamp: timestamp.timestamp(),
+                            path: Some(full_path.clone()),
+                        };
+
+                        if event_tx.send(event).is_err() {
+                            return false;
+                        }
+
+                        println!(
+                            "Catch event! Rule hash: {} | Time: {} | Path: {}",
+                            pairs_vector_with_hash.0,
+                            timestamp.format("%d-%m-%Y %H:%M:%S"),
+                            full_path
+                        );
+                    }
+                }
+            }
         }
     }
-}
\ No newline at end of file
+
+    true
+}
+
+// "rescan now" command: check every file under the controlled directory.
+// Returns number of scanned files.
+fn rescan_directory(
+    dir: &Path,
+    rules_vec: &LevelRules,
+    event_tx: &mpsc::UnboundedSender<SensorMessage>,
+) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut scanned = 0;
+
+    // unreadable entry is skipped, the rest of directory is still scanned
+    for entry in entries.filter_map(Result::ok) {
+        // symlinks are skipped, a link to a parent directory would loop forever
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            scanned += rescan_directory(&path, rules_vec, event_tx);
+        } else if file_type.is_file() {
+            if !scan_file(&path, rules_vec, event_tx) {
+                break;
+            }
+            scanned += 1;
+        }
+    }
+
+    scanned
+}
@@ -0,0 +1,8 @@
+pub mod auth;
+pub mod event_bus;
+pub mod file_manager;
+pub mod menu;
+pub mod protocol;
+pub mod sensor_handler;
+pub mod structs;
+pub mod tls;
@@ -0,0 +1,92 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use tokio::sync::mpsc;
+
+use crate::protocol::messages::ConsoleOutput;
+
+// Menus and everything they call print and read through this module.
+// Local console uses stdin and stdout, thread of remote console session talks to its client.
+
+// channels of remote console connection, see remote_console::serve_console
+pub struct RemoteConsole {
+    pub output: mpsc::UnboundedSender<ConsoleOutput>,
+    // menus run inside block_on, so input is a std channel: tokio one can't block there
+    pub input: Receiver<String>,
+}
+
+thread_local! {
+    static REMOTE: RefCell<Option<RemoteConsole>> = const { RefCell::new(None) };
+    // input is over: stdin is closed or remote client has gone
+    static CLOSED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[macro_export]
+macro_rules! console_println {
+    ($($arg:tt)*) => {
+        $crate::menu::console::print_line(format!($($arg)*))
+    };
+}
+
+// session thread is a pooled one, so the console is detached when the session is over
+pub fn attach_remote(console: RemoteConsole) {
+    REMOTE.with(|remote| *remote.borrow_mut() = Some(console));
+    CLOSED.with(|closed| closed.set(false));
+}
+
+pub fn detach_remote() -> Option<RemoteConsole> {
+    CLOSED.with(|closed| closed.set(false));
+    REMOTE.with(|remote| remote.borrow_mut().take())
+}
+
+pub fn is_remote() -> bool {
+    REMOTE.with(|remote| remote.borrow().is_some())
+}
+
+pub fn input_closed() -> bool {
+    CLOSED.with(Cell::get)
+}
+
+pub fn print_line(text: String) {
+    REMOTE.with(|remote| match remote.borrow().as_ref() {
+        Some(console) => {
+            let _ = console.output.send(ConsoleOutput::Text { text });
+        }
+        None => println!("{}", text),
+    })
+}
+
+// Remote client shows the prompt and answers with a line, secret one is read hidden.
+// None - console is local, Some(None) - input is closed
+pub fn remote_prompt(prompt: &str, secret: bool) -> Option<Option<String>> {
+    REMOTE.with(|remote| {
+        let mut remote = remote.borrow_mut();
+        let console = remote.as_mut()?;
+        let _ = console.output.send(ConsoleOutput::Prompt {
+            text: prompt.to_string(),
+            secret,
+        });
+        let line = console.input.recv().ok();
+        if line.is_none() {
+            CLOSED.with(|closed| closed.set(true));
+        }
+        Some(line)
+    })
+}
+
+// None - input is closed
+pub fn read_line() -> Option<String> {
+    if let Some(line) = remote_prompt("", false) {
+        return line;
+    }
+
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => {
+            CLOSED.with(|closed| closed.set(true));
+            None
+        }
+        Ok(_) => Some(line),
+    }
+}
@@ -1,16 +1,31 @@
+use crate::auth::user_manager::{
+    change_own_password, create_user, delete_user, enroll_own_second_factor, get_user_list,
+    reset_password, reset_second_factor, set_account_expiry, set_role, switch_user_state,
+    unlock_user,
+};
+use crate::auth::auth::read_secret;
+use crate::auth::roles::{check_permission, load_roles};
+use crate::auth::session::{check_session, reauthenticate};
+use crate::console_println;
+use crate::menu::console::{input_closed, read_line};
+use crate::event_bus::event_bus::{publish, EventBus, ServerEvent};
 use crate::file_manager::file_manager::audit_handler::{
     change_audit_status, get_10_latest_audit_messages, write_audit_event,
 };
 use crate::file_manager::file_manager::event_handler::get_10_latest_event_messages;
+use crate::protocol::messages::SensorCommand;
 use crate::sensor_handler::rule_handler::{add_rule, delete_rule, get_rules_list};
-use crate::sensor_handler::sensor_handler::{change_sensor_state, get_sensor_list};
+use crate::sensor_handler::sensor_handler::{
+    change_sensor_state, get_sensor_list, push_rules, revoke_registered_sensor, send_sensor_command,
+};
+use crate::sensor_handler::sensor_registry::{create_enrollment_token, get_registry_list};
 use crate::structs::soc_structs::multithread::FileMutexes;
-use crate::structs::soc_structs::{AuditEventType, LogFiles, SessionStatus};
+use crate::structs::soc_structs::{
+    AuditEventType, LogFiles, PasswordPolicy, Permission, SessionStatus,
+};
 use regex::Regex;
 use std::collections::HashMap;
-use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
 
 const MAIN_MENU: &str = "\
         ------------------------------------------------------\n\
@@ -21,7 +36,8 @@ const MAIN_MENU: &str = "\
         2) Sensors settings\n\
         3) Audit settings\n\
         4) Rules settings\n\
-        5) Exit\n\
+        5) Users\n\
+        6) Exit\n\
         ------------------------------------------------------";
 const EVENT_MENU: &str = "\
             ------------------------------------------------------\n\
@@ -35,7 +51,11 @@ const SENSORS_MENU: &str = "\
             Select option:\n\
             1) List of sensors\n\
             2) Start/stop sensor\n\
-            3) Back\n\
+            3) Enrolled sensors\n\
+            4) Create enrollment token\n\
+            5) Revoke sensor\n\
+            6) Send command to sensor\n\
+            7) Back\n\
             ------------------------------------------------------";
 const AUDIT_MENU: &str = "\
             ------------------------------------------------------\n\
@@ -53,113 +73,171 @@ const RULE_MENU: &str = "\
             4) Back\n\
             ------------------------------------------------------";
 
+const USERS_MENU: &str = "\
+            ------------------------------------------------------\n\
+            Select option:\n\
+            1) List of users\n\
+            2) Create user\n\
+            3) Delete user\n\
+            4) Disable/enable user\n\
+            5) Reset user password\n\
+            6) Change own password\n\
+            7) Change user role\n\
+            8) Unlock user\n\
+            9) Set up own two-factor authentication\n\
+            10) Reset two-factor authentication of user\n\
+            11) Set account expiry\n\
+            12) Back\n\
+            ------------------------------------------------------";
+
 macro_rules! pause {
     () => {{
-        println!(
+        console_println!(
             "------------------------------------------------------\n\
              Press enter to continue..."
         );
-        let mut buffer = String::new();
-
-        std::io::stdin()
-            .read_line(&mut buffer)
-            .expect("Failed to read line");
+        let _ = $crate::menu::console::read_line();
     }};
 }
 
 pub async fn main_menu(
     session_status: &mut SessionStatus,
     log_files: &LogFiles,
-    tx: tokio::sync::mpsc::Sender<String>,
+    bus: EventBus,
     file_mutexes: &FileMutexes,
     audit_status: &Arc<Mutex<bool>>,
+    password_policy: &PasswordPolicy,
 ) {
     loop {
-        println!("{}", MAIN_MENU);
+        console_println!("{}", MAIN_MENU);
         let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, &log_files.audit_file, audit_status) {
+            continue;
+        }
 
         match choise.as_str() {
             "1" => event_menu(
                 session_status,
-                &file_mutexes,
+                file_mutexes,
                 &log_files.audit_file,
                 audit_status,
             ),
             "2" => sensors_menu(
                 session_status,
-                &file_mutexes,
+                file_mutexes,
                 &log_files.audit_file,
                 audit_status,
             ),
             "3" => audit_menu(
                 session_status,
-                &file_mutexes,
+                file_mutexes,
                 &log_files.audit_file,
                 audit_status,
             ),
-            "4" => rule_menu(session_status, &file_mutexes, &log_files.rules_file),
-            "5" => {
-                println!("Goodbye.");
-                tx.send("stop".to_string()).await.unwrap();
+            "4" => rule_menu(
+                session_status,
+                file_mutexes,
+                &log_files.rules_file,
+                &log_files.audit_file,
+                audit_status,
+            ),
+            "5" => users_menu(
+                session_status,
+                file_mutexes,
+                log_files,
+                audit_status,
+                password_policy,
+            ),
+            // remote operator leaves, only local console stops the server
+            "6" if session_status.remote_addr.is_some() => {
+                console_println!("Goodbye.");
+                return;
+            }
+            "6" => {
+                console_println!("Goodbye.");
+                publish(
+                    &bus,
+                    ServerEvent::ShutdownRequested {
+                        user: session_status.user.clone(),
+                        reason: "exit from console".to_string(),
+                    },
+                );
                 return;
             }
-            _ => println!("Undefined option. Try again.\n"),
+            _ => console_println!("Undefined option. Try again.\n"),
         }
     }
 }
 
+// empty if input is closed, see console::input_closed
 pub fn get_user_choice() -> String {
-    let mut choice = String::new();
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut choice).expect("Input error.");
-    choice.trim().to_string()
+    read_line().unwrap_or_default().trim().to_string()
 }
 
 fn event_menu(
     session_status: &mut SessionStatus,
     file_mutexes: &FileMutexes,
-    log_file: &String,
+    log_file: &str,
     audit_status: &Arc<Mutex<bool>>,
 ) {
     loop {
-        println!("{}", EVENT_MENU);
+        console_println!("{}", EVENT_MENU);
         let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, log_file, audit_status) {
+            continue;
+        }
+
+        let required = match choise.as_str() {
+            "1" | "2" => Some(Permission::EventsView),
+            _ => None,
+        };
+        if required.is_some_and(|permission| {
+            !check_permission(session_status, permission, file_mutexes, log_file, audit_status)
+        }) {
+            continue;
+        }
 
         match choise.as_str() {
             "1" => {
                 get_10_latest_event_messages(file_mutexes, "");
-                let aud_status = audit_status.lock().unwrap();
+                let aud_stat = *audit_status.lock().unwrap();
                 write_audit_event(
-                    SystemTime::now(),
                     session_status.host.clone(),
                     session_status.user.clone(),
                     AuditEventType::EvtLogAccess,
                     "Event log has been checked".to_string(),
                     file_mutexes,
                     log_file,
-                    *aud_status,
+                    aud_stat,
                 );
                 pause!();
             }
             "2" => {
-                println!("Please, enter name of the sensor:");
+                console_println!("Please, enter name of the sensor:");
                 let required_sensor = get_user_choice();
                 get_10_latest_event_messages(file_mutexes, &required_sensor);
-                let aud_status = audit_status.lock().unwrap();
+                let aud_stat = *audit_status.lock().unwrap();
                 write_audit_event(
-                    SystemTime::now(),
                     session_status.host.clone(),
                     session_status.user.clone(),
                     AuditEventType::EvtLogAccess,
                     "Event log has been checked".to_string(),
                     file_mutexes,
                     log_file,
-                    *aud_status,
+                    aud_stat,
                 );
                 pause!();
             }
             "3" => break,
-            _ => println!("Undefined option. Try again."),
+            _ => console_println!("Undefined option. Try again."),
         }
     }
 }
@@ -167,53 +245,180 @@ fn event_menu(
 fn sensors_menu(
     session_status: &mut SessionStatus,
     file_mutexes: &FileMutexes,
-    log_file: &String,
+    log_file: &str,
     audit_status: &Arc<Mutex<bool>>,
 ) {
     loop {
-        println!("{}", SENSORS_MENU);
+        console_println!("{}", SENSORS_MENU);
         let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, log_file, audit_status) {
+            continue;
+        }
+
+        let required = match choise.as_str() {
+            "1" | "3" => Some(Permission::SensorsView),
+            "2" | "6" => Some(Permission::SensorsControl),
+            "4" | "5" => Some(Permission::SensorsEnroll),
+            _ => None,
+        };
+        if required.is_some_and(|permission| {
+            !check_permission(session_status, permission, file_mutexes, log_file, audit_status)
+        }) {
+            continue;
+        }
 
         match choise.as_str() {
             "1" => {
                 get_sensor_list(session_status);
-                println!(" ");
+                console_println!(" ");
                 pause!();
             }
             "2" => {
-                if !session_status.is_admin {
-                    println!("Admin privileges required.");
+                if !reauthenticate(session_status, "change sensor state", file_mutexes, log_file, audit_status) {
                     continue;
                 }
-
-                println!("Enter address of sensor to change it's status:");
+                console_println!("Enter name or ID of sensor to change it's status:");
                 let sensor_ip = &get_user_choice();
 
-                let aud_stat = audit_status.lock().unwrap();
+                let aud_stat = *audit_status.lock().unwrap();
                 let operation_status: (bool, bool, bool) = change_sensor_state(
                     sensor_ip,
                     session_status,
                     file_mutexes,
                     log_file,
-                    *aud_stat,
+                    aud_stat,
                 );
                 if !operation_status.2 {
-                    println!("There is no sensor with this IP.");
+                    console_println!("There is no connected sensor with this name or ID.");
                     break;
                 }
                 if !operation_status.1 {
-                    println!("Error occured with audit logging.");
+                    console_println!("Error occured with audit logging.");
                     break;
                 }
                 if !operation_status.0 {
-                    println!("System audit disabled")
+                    console_println!("Stop command sent to sensor. Result will be shown when sensor confirms it.")
                 } else {
-                    println!("System audit enabled")
+                    console_println!("Start command sent to sensor. Result will be shown when sensor confirms it.")
                 };
                 pause!();
             }
-            "3" => break,
-            _ => println!("Undefined option. Try again."),
+            "3" => {
+                get_registry_list(file_mutexes);
+                pause!();
+            }
+            "4" => {
+                console_println!("Enter name of the sensor to enroll (as 'sensor_name' in sensor config):");
+                let sensor_name = get_user_choice();
+                if sensor_name.is_empty() || sensor_name.contains("[:") {
+                    console_println!("Wrong sensor name. Try again.");
+                    continue;
+                }
+
+                console_println!("Token validity in hours (empty for 24):");
+                let validity_hours: i64 = match get_user_choice().as_str() {
+                    "" => 24,
+                    hours => match hours.parse() {
+                        Ok(h) if h > 0 => h,
+                        _ => {
+                            console_println!("Wrong number of hours. Try again.");
+                            continue;
+                        }
+                    },
+                };
+
+                match create_enrollment_token(
+                    &sensor_name,
+                    validity_hours,
+                    &session_status.user,
+                    file_mutexes,
+                ) {
+                    Some(token) => {
+                        let aud_stat = *audit_status.lock().unwrap();
+                        write_audit_event(
+                            session_status.host.clone(),
+                            session_status.user.clone(),
+                            AuditEventType::TokenCreate,
+                            format!(
+                                "Enrollment token created for sensor {}. Valid for {} hours",
+                                sensor_name, validity_hours
+                            ),
+                            file_mutexes,
+                            log_file,
+                            aud_stat,
+                        );
+                        console_println!(
+                            "One-time enrollment token (shown only once):\n{}\n\
+                             Put it in 'enrollment_token' field of sensor config.",
+                            token
+                        );
+                    }
+                    None => console_println!("Error while saving enrollment token."),
+                }
+                pause!();
+            }
+            "5" => {
+                console_println!("Enter name or ID of the sensor to revoke:");
+                let target = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                let operation_status: (bool, bool) = revoke_registered_sensor(
+                    &target,
+                    session_status,
+                    file_mutexes,
+                    log_file,
+                    aud_stat,
+                );
+                if !operation_status.0 {
+                    console_println!("There is no enrolled sensor with this name or ID.");
+                    continue;
+                }
+                if !operation_status.1 {
+                    console_println!("Error occured with audit logging.");
+                }
+                console_println!("Sensor revoked.");
+                pause!();
+            }
+            "6" => {
+                console_println!("Enter name or ID of sensor:");
+                let sensor_ip = get_user_choice();
+
+                console_println!("Enter command (rescan/status/reload):");
+                let command = match get_user_choice().as_str() {
+                    "rescan" => SensorCommand::RescanNow,
+                    "status" => SensorCommand::ReportStatus,
+                    "reload" => SensorCommand::ReloadConfig,
+                    _ => {
+                        console_println!("Undefined command. Try 'rescan', 'status' or 'reload'");
+                        continue;
+                    }
+                };
+
+                let aud_stat = *audit_status.lock().unwrap();
+                let operation_status: (bool, bool) = send_sensor_command(
+                    &sensor_ip,
+                    command,
+                    session_status,
+                    file_mutexes,
+                    log_file,
+                    aud_stat,
+                );
+                if !operation_status.0 {
+                    console_println!("There is no connected sensor with this name or ID.");
+                    continue;
+                }
+                if !operation_status.1 {
+                    console_println!("Error occured with audit logging.");
+                }
+                console_println!("Command sent. Result will be shown when sensor confirms it.");
+                pause!();
+            }
+            "7" => break,
+            _ => console_println!("Undefined option. Try again."),
         }
     }
 }
@@ -221,20 +426,40 @@ fn sensors_menu(
 fn audit_menu(
     session_status: &mut SessionStatus,
     file_mutexes: &FileMutexes,
-    log_file: &String,
+    log_file: &str,
     audit_status: &Arc<Mutex<bool>>,
 ) {
     loop {
-        println!("{}", AUDIT_MENU);
+        console_println!("{}", AUDIT_MENU);
         let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, log_file, audit_status) {
+            continue;
+        }
+
+        let required = match choise.as_str() {
+            "1" => Some(Permission::AuditControl),
+            "2" => Some(Permission::AuditView),
+            _ => None,
+        };
+        if required.is_some_and(|permission| {
+            !check_permission(session_status, permission, file_mutexes, log_file, audit_status)
+        }) {
+            continue;
+        }
 
         match choise.as_str() {
             "1" => {
-                if !session_status.is_admin {
-                    println!("Admin privileges required.");
+                // only disabling is sensitive: enabled audit loses nothing
+                let enabled = *audit_status.lock().unwrap();
+                if enabled
+                    && !reauthenticate(session_status, "disable audit", file_mutexes, log_file, audit_status)
+                {
                     continue;
                 }
-
                 let operation_status: (bool, bool) = change_audit_status(
                     audit_status,
                     session_status.host.clone(),
@@ -243,45 +468,68 @@ fn audit_menu(
                     log_file,
                 );
                 if !operation_status.1 {
-                    println!("Error occured with audit logging.");
+                    console_println!("Error occured with audit logging.");
                     break;
                 }
                 if !operation_status.0 {
-                    println!("System audit disabled")
+                    console_println!("System audit disabled")
                 } else {
-                    println!("System audit enabled")
+                    console_println!("System audit enabled")
                 };
                 pause!();
             }
             "2" => {
                 get_10_latest_audit_messages(file_mutexes);
-                let aud_status = audit_status.lock().unwrap();
+                let aud_stat = *audit_status.lock().unwrap();
                 write_audit_event(
-                    SystemTime::now(),
                     session_status.host.clone(),
                     session_status.user.clone(),
                     AuditEventType::AudLogAccess,
                     "Audit log has been checked".to_string(),
                     file_mutexes,
                     log_file,
-                    *aud_status,
+                    aud_stat,
                 );
                 pause!();
             }
             "3" => break,
-            _ => println!("Undefined option. Try again."),
+            _ => console_println!("Undefined option. Try again."),
         }
     }
 }
 
-fn rule_menu(session_status: &mut SessionStatus, file_mutexes: &FileMutexes, rule_file: &String) {
+fn rule_menu(
+    session_status: &mut SessionStatus,
+    file_mutexes: &FileMutexes,
+    rule_file: &str,
+    log_file: &str,
+    audit_status: &Arc<Mutex<bool>>,
+) {
     loop {
-        println!("{}", RULE_MENU);
+        console_println!("{}", RULE_MENU);
         let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, log_file, audit_status) {
+            continue;
+        }
+
+        let required = match choise.as_str() {
+            "1" => Some(Permission::RulesView),
+            "2" | "3" => Some(Permission::RulesEdit),
+            _ => None,
+        };
+        if required.is_some_and(|permission| {
+            !check_permission(session_status, permission, file_mutexes, log_file, audit_status)
+        }) {
+            continue;
+        }
 
         match choise.as_str() {
             "1" => {
-                println!("What type of rules you want to get? (net/host)");
+                console_println!("What type of rules you want to get? (net/host)");
                 let rule_level = get_user_choice();
 
                 match rule_level.as_str() {
@@ -292,17 +540,12 @@ fn rule_menu(session_status: &mut SessionStatus, file_mutexes: &FileMutexes, rul
                         get_rules_list("host", file_mutexes);
                     }
                     _ => {
-                        println!("Undefined rule level. Try 'net' or 'host'")
+                        console_println!("Undefined rule level. Try 'net' or 'host'")
                     }
                 }
                 pause!();
             }
             "2" => {
-                if !session_status.is_admin {
-                    println!("Admin privileges required.");
-                    continue;
-                }
-
                 let _rule_map = add_rule_interface();
                 if !_rule_map.1 {
                     break;
@@ -312,43 +555,259 @@ fn rule_menu(session_status: &mut SessionStatus, file_mutexes: &FileMutexes, rul
                 let desc = _rule_map.0 .0.get("description").unwrap().to_string();
                 let payload = _rule_map.0 .0.get("payload").unwrap().to_string();
 
-                add_rule(
-                    level,
+                if add_rule(
+                    level.clone(),
                     name,
                     payload,
                     desc,
                     &_rule_map.0 .1,
                     rule_file,
                     file_mutexes,
-                );
+                ) {
+                    let (pushed, failed) =
+                        push_rules(&level, rule_file, file_mutexes, &session_status.sensor_list);
+                    console_println!("Rule added. New rules sent to {} sensor(s).", pushed);
+                    report_failed_push(&failed);
+                }
                 pause!();
             }
             "3" => {
-                if !session_status.is_admin {
-                    println!("Admin privileges required.");
+                if !reauthenticate(session_status, "delete rule", file_mutexes, log_file, audit_status) {
                     continue;
                 }
-
-                println!("What type of rule you want to delete? (net/host)");
+                console_println!("What type of rule you want to delete? (net/host)");
                 let rule_level = get_user_choice();
                 if rule_level != "net" && rule_level != "host" {
-                    println!("Undefined rule level. Try 'net' or 'host'");
+                    console_println!("Undefined rule level. Try 'net' or 'host'");
                     continue;
                 }
 
-                println!("Enter rule hash (from rules list):");
+                console_println!("Enter rule hash (from rules list):");
                 let rule_hash = get_user_choice();
-                delete_rule(&rule_level, &rule_hash, rule_file, file_mutexes);
+                if delete_rule(&rule_level, &rule_hash, rule_file, file_mutexes) {
+                    let (pushed, failed) = push_rules(
+                        &rule_level,
+                        rule_file,
+                        file_mutexes,
+                        &session_status.sensor_list,
+                    );
+                    console_println!("New rules sent to {} sensor(s).", pushed);
+                    report_failed_push(&failed);
+                }
                 pause!();
             }
             "4" => break,
-            _ => println!("Undefined option. Try again."),
+            _ => console_println!("Undefined option. Try again."),
+        }
+    }
+}
+
+fn users_menu(
+    session_status: &mut SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_files: &LogFiles,
+    audit_status: &Arc<Mutex<bool>>,
+    password_policy: &PasswordPolicy,
+) {
+    let (users_file, roles_file, lockout_file, log_file) = (
+        &log_files.users_file,
+        &log_files.roles_file,
+        &log_files.lockout_file,
+        &log_files.audit_file,
+    );
+    loop {
+        console_println!("{}", USERS_MENU);
+        let choise = get_user_choice();
+        // stdin is closed or remote client has gone
+        if input_closed() {
+            return;
+        }
+        if !check_session(session_status, file_mutexes, log_file, audit_status) {
+            continue;
+        }
+
+        let required = match choise.as_str() {
+            "1" => Some(Permission::UsersView),
+            "2" | "3" | "4" | "5" | "7" | "8" | "10" | "11" => Some(Permission::UsersManage),
+            _ => None,
+        };
+        if required.is_some_and(|permission| {
+            !check_permission(session_status, permission, file_mutexes, log_file, audit_status)
+        }) {
+            continue;
+        }
+
+        match choise.as_str() {
+            "1" => {
+                get_user_list(users_file, lockout_file, file_mutexes);
+                pause!();
+            }
+            "2" => {
+                console_println!("Enter name of the new user:");
+                let name = get_user_choice();
+                let role = match read_role(roles_file) {
+                    Some(role) => role,
+                    None => continue,
+                };
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match create_user(&name, &role, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(password) => console_println!(
+                        "User created. Temporary password (shown only once):\n{}\n\
+                         User must change it on the first logon.",
+                        password
+                    ),
+                    Err(reason) => console_println!("User is not created: {}", reason),
+                }
+                pause!();
+            }
+            "3" => {
+                console_println!("Enter name of the user to delete:");
+                let name = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match delete_user(&name, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(()) => console_println!("User deleted."),
+                    Err(reason) => console_println!("User is not deleted: {}", reason),
+                }
+                pause!();
+            }
+            "4" => {
+                console_println!("Enter name of the user to disable or enable:");
+                let name = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match switch_user_state(&name, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(true) => console_println!("User disabled."),
+                    Ok(false) => console_println!("User enabled."),
+                    Err(reason) => console_println!("User state is not changed: {}", reason),
+                }
+                pause!();
+            }
+            "5" => {
+                console_println!("Enter name of the user to reset password:");
+                let name = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match reset_password(&name, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(password) => console_println!(
+                        "Password reset. Temporary password (shown only once):\n{}\n\
+                         User must change it on the next logon.",
+                        password
+                    ),
+                    Err(reason) => console_println!("Password is not reset: {}", reason),
+                }
+                pause!();
+            }
+            "6" => {
+                let current_password = match read_secret("Enter current password: ") {
+                    Some(password) => password,
+                    None => continue,
+                };
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match change_own_password(&current_password, password_policy, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(()) => console_println!("Password changed."),
+                    Err(reason) => console_println!("Password is not changed: {}", reason),
+                }
+                pause!();
+            }
+            "7" => {
+                console_println!("Enter name of the user to change role:");
+                let name = get_user_choice();
+                let role = match read_role(roles_file) {
+                    Some(role) => role,
+                    None => continue,
+                };
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match set_role(&name, &role, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(()) => console_println!("Role changed."),
+                    Err(reason) => console_println!("Role is not changed: {}", reason),
+                }
+                pause!();
+            }
+            "8" => {
+                console_println!("Enter name of the user to unlock:");
+                let name = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match unlock_user(&name, lockout_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(()) => console_println!("User unlocked."),
+                    Err(reason) => console_println!("User is not unlocked: {}", reason),
+                }
+                pause!();
+            }
+            "9" => {
+                if !reauthenticate(session_status, "set up two-factor authentication", file_mutexes, log_file, audit_status) {
+                    continue;
+                }
+
+                let aud_stat = *audit_status.lock().unwrap();
+                if let Err(reason) = enroll_own_second_factor(users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    console_println!("Two-factor authentication is not set up: {}", reason);
+                }
+                pause!();
+            }
+            "10" => {
+                console_println!("Enter name of the user to reset two-factor authentication:");
+                let name = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match reset_second_factor(&name, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(()) => console_println!("Two-factor authentication reset. User sets it up again on the next logon."),
+                    Err(reason) => console_println!("Two-factor authentication is not reset: {}", reason),
+                }
+                pause!();
+            }
+            "11" => {
+                console_println!("Enter name of the user to set account expiry:");
+                let name = get_user_choice();
+                console_println!("Enter the last day of the account (YYYY-MM-DD), empty - account never expires:");
+                let last_day = get_user_choice();
+
+                let aud_stat = *audit_status.lock().unwrap();
+                match set_account_expiry(&name, &last_day, users_file, session_status, file_mutexes, log_file, aud_stat) {
+                    Ok(0) => console_println!("Account never expires now."),
+                    Ok(_) => console_println!("Account expires after {}.", last_day),
+                    Err(reason) => console_println!("Account expiry is not set: {}", reason),
+                }
+                pause!();
+            }
+            "12" => break,
+            _ => console_println!("Undefined option. Try again."),
         }
     }
 }
 
-fn add_rule_interface() -> ((HashMap<String, String>, HashMap<String, String>), bool) {
-    let mut basic_fields: HashMap<String, String> = vec![
+fn report_failed_push(failed: &[String]) {
+    if !failed.is_empty() {
+        console_println!(
+            "Rules were not sent to {} (sensor queue is full), they get them on reconnect.",
+            failed.join(", ")
+        );
+    }
+}
+
+// None - wrong input, message is already shown
+fn read_role(roles_file: &str) -> Option<String> {
+    let mut roles: Vec<String> = load_roles(roles_file).into_keys().collect();
+    roles.sort();
+
+    console_println!("Enter role ({}):", roles.join("/"));
+    let role = get_user_choice();
+    if !roles.contains(&role) {
+        console_println!("Undefined role. Try one of: {}", roles.join(", "));
+        return None;
+    }
+    Some(role)
+}
+
+// rule field name -> value
+type RuleFields = HashMap<String, String>;
+
+fn add_rule_interface() -> ((RuleFields, RuleFields), bool) {
+    let mut basic_fields: RuleFields = vec![
         ("level".to_string(), "".to_string()),
         ("name".to_string(), "".to_string()),
         ("payload".to_string(), "".to_string()),
@@ -356,47 +815,46 @@ fn add_rule_interface() -> ((HashMap<String, String>, HashMap<String, String>),
     ]
     .into_iter()
     .collect();
-    let mut optional_fields_map: HashMap<String, String> = HashMap::new();
-    let level: &str;
+    let mut optional_fields_map: RuleFields = HashMap::new();
 
-    println!("Enter rule level (net/host): ");
-    match get_user_choice().as_str() {
+    console_println!("Enter rule level (net/host): ");
+    let level: &str = match get_user_choice().as_str() {
         "net" => {
             basic_fields.insert("level".to_string(), "net".to_string());
             optional_fields_map.insert("protocol".to_string(), "ipv4".to_string());
-            level = "net";
+            "net"
         }
         "host" => {
             basic_fields.insert("level".to_string(), "host".to_string());
-            level = "host";
+            "host"
         }
         _ => {
-            println!("Wrong rule level. Try again.");
+            console_println!("Wrong rule level. Try again.");
             return ((HashMap::new(), HashMap::new()), false);
         }
-    }
+    };
 
-    println!("Enter rule name: ");
+    console_println!("Enter rule name: ");
     let data = get_user_choice();
     if data.is_empty() {
-        println!("Can't write empty value. Try again.");
+        console_println!("Can't write empty value. Try again.");
         return ((HashMap::new(), HashMap::new()), false);
     }
     basic_fields.insert("name".to_string(), data);
 
-    println!("Enter rule description: ");
+    console_println!("Enter rule description: ");
     let data = get_user_choice();
     if data.is_empty() {
-        println!("Can't write empty value. Try again.");
+        console_println!("Can't write empty value. Try again.");
         return ((HashMap::new(), HashMap::new()), false);
     }
     basic_fields.insert("description".to_string(), data);
 
     if level == "host" {
-        println!("Enter rule payload: ");
+        console_println!("Enter rule payload: ");
         let data = get_user_choice();
         if data.is_empty() {
-            println!("Can't write empty value. Try again.");
+            console_println!("Can't write empty value. Try again.");
             return ((HashMap::new(), HashMap::new()), false);
         }
         basic_fields.insert("payload".to_string(), data);
@@ -404,54 +862,54 @@ fn add_rule_interface() -> ((HashMap<String, String>, HashMap<String, String>),
         basic_fields.insert("payload".to_string(), " ".to_string());
 
         let mut net_payload_flag = false;
+        let re = Regex::new(r"^([0-9a-f]{2}[:]){5}([0-9a-f]{2})$").unwrap();
         while !net_payload_flag {
-            println!("What field you want to setup as trigger? (src/dst/both): ");
-            let re = Regex::new(r"^([0-9a-f]{2}[:]){5}([0-9a-f]{2})$").unwrap();
+            console_println!("What field you want to setup as trigger? (src/dst/both): ");
             let mut mac_addr_str: String;
 
             match get_user_choice().as_str() {
                 "src" => {
-                    println!("Enter source MAC-address (':' as separator):");
+                    console_println!("Enter source MAC-address (':' as separator):");
                     mac_addr_str = get_user_choice().as_str().to_lowercase();
                     if re.is_match(mac_addr_str.as_str()) {
                         net_payload_flag = true;
                         optional_fields_map.insert("src".to_string(), mac_addr_str);
                         optional_fields_map.insert("dst".to_string(), " ".to_string());
                     } else {
-                        println!("Wrong MAC format. Try again");
+                        console_println!("Wrong MAC format. Try again");
                     }
                 }
                 "dst" => {
-                    println!("Enter destination MAC-address (':' as separator):");
+                    console_println!("Enter destination MAC-address (':' as separator):");
                     mac_addr_str = get_user_choice().as_str().to_lowercase();
                     if re.is_match(mac_addr_str.as_str()) {
                         net_payload_flag = true;
                         optional_fields_map.insert("dst".to_string(), mac_addr_str);
                         optional_fields_map.insert("src".to_string(), " ".to_string());
                     } else {
-                        println!("Wrong MAC format. Try again");
+                        console_println!("Wrong MAC format. Try again");
                     }
                 }
                 "both" => {
-                    println!("Enter source MAC-address (':' as separator):");
+                    console_println!("Enter source MAC-address (':' as separator):");
                     mac_addr_str = get_user_choice().as_str().to_lowercase();
                     if re.is_match(mac_addr_str.as_str()) {
                         optional_fields_map.insert("src".to_string(), mac_addr_str);
                     } else {
-                        println!("Wrong MAC format. Try again");
+                        console_println!("Wrong MAC format. Try again");
                     }
 
-                    println!("Enter destination MAC-address (':' as separator):");
+                    console_println!("Enter destination MAC-address (':' as separator):");
                     mac_addr_str = get_user_choice().as_str().to_lowercase();
                     if re.is_match(mac_addr_str.as_str()) {
                         net_payload_flag = true;
                         optional_fields_map.insert("dst".to_string(), mac_addr_str);
                     } else {
-                        println!("Wrong MAC format. Try again");
+                        console_println!("Wrong MAC format. Try again");
                     }
                 }
                 _ => {
-                    println!("Error parsing parameter. Try again");
+                    console_println!("Error parsing parameter. Try again");
                 }
             }
         }
@@ -1 +1,5 @@
+pub mod console;
+// crate::menu::menu::... paths are used across the crate and binaries
+#[allow(clippy::module_inception)]
 pub mod menu;
+pub mod remote_console;
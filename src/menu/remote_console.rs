@@ -0,0 +1,285 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+
+use crate::auth::auth::authenticate;
+use crate::auth::roles::role_permissions;
+use crate::event_bus::event_bus::{publish, EventBus, ServerEvent};
+use crate::menu::console::{attach_remote, detach_remote, RemoteConsole};
+use crate::menu::menu::main_menu;
+use crate::protocol::framing::{message_stream, recv_message, send_message};
+use crate::protocol::messages::{ConsoleOutput, ConsoleRequest, PROTOCOL_VERSION};
+use crate::sensor_handler::sensor_listener::SensorListener;
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{
+    ConsoleSession, ConsoleSessions, LogFiles, OperatorAuth, SensorMap, SessionActivity,
+    SessionStatus,
+};
+use crate::tls::certificates::CONSOLE_UNIT;
+use crate::tls::tls_config::peer_identity;
+
+// 0 is the local console
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+// unix socket peers have no address, so connections are numbered
+static NEXT_LOCAL_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+// everything remote console sessions share with the local one
+#[derive(Clone)]
+pub struct ConsoleContext {
+    pub tls_acceptor: TlsAcceptor,
+    pub hostname: String,
+    pub log_files: LogFiles,
+    pub operator_auth: OperatorAuth,
+    pub idle_timeout: Duration,
+    pub sensors: SensorMap,
+    pub sessions: ConsoleSessions,
+    pub bus: EventBus,
+    pub file_mutexes: FileMutexes,
+    pub audit_status: Arc<Mutex<bool>>,
+    // connections of consoles, server waits for them on shutdown
+    pub tracker: TaskTracker,
+}
+
+// Accepts console clients until the task is aborted. Listener is bound like sensor one.
+pub fn spawn_console_listener(listener: SensorListener, context: ConsoleContext) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match listener {
+                SensorListener::Tcp(ref tcp_listener, _) => match tcp_listener.accept().await {
+                    Ok((stream, addr)) => {
+                        context.tracker.spawn(serve_console(stream, addr.to_string(), context.clone()));
+                    }
+                    Err(e) => println!("Error while recieving console connection:\n{}", e),
+                },
+                #[cfg(unix)]
+                SensorListener::Unix(ref unix_listener, ref address) => {
+                    match unix_listener.accept().await {
+                        Ok((stream, _)) => {
+                            let number = NEXT_LOCAL_CONNECTION.fetch_add(1, Ordering::Relaxed);
+                            let addr_str = format!("{}#{}", address, number);
+                            context.tracker.spawn(serve_console(stream, addr_str, context.clone()));
+                        }
+                        Err(e) => println!("Error while recieving console connection:\n{}", e),
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn reject(context: &ConsoleContext, addr: String, user: String, reason: String) {
+    publish(
+        &context.bus,
+        ServerEvent::ConsoleRejected {
+            host: context.hostname.clone(),
+            addr,
+            user,
+            reason,
+        },
+    );
+}
+
+// Menus are synchronous, so the session runs on its own blocking thread.
+// This task only moves its output to the client and client lines to its input.
+async fn serve_console<S>(stream: S, addr_str: String, context: ConsoleContext)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tls_stream = match context.tls_acceptor.accept(stream).await {
+        Ok(tls_stream) => tls_stream,
+        Err(e) => {
+            reject(&context, addr_str, " ".to_string(), format!("TLS handshake failed: {}", e));
+            return;
+        }
+    };
+    let (_, cert_subject) = match peer_identity(tls_stream.get_ref().1.peer_certificates(), CONSOLE_UNIT) {
+        Ok(identity) => identity,
+        Err(reason) => {
+            reject(&context, addr_str, " ".to_string(), reason);
+            return;
+        }
+    };
+    let mut messages = message_stream(tls_stream);
+
+    let (username, password) = match recv_message::<_, ConsoleRequest>(&mut messages).await {
+        Ok(Some(ConsoleRequest::Hello {
+            protocol_version,
+            username,
+            password,
+        })) if protocol_version == PROTOCOL_VERSION => (username, password),
+        Ok(Some(ConsoleRequest::Hello {
+            protocol_version,
+            username,
+            ..
+        })) => {
+            let reason = format!(
+                "protocol version mismatch: server {}, console {}",
+                PROTOCOL_VERSION, protocol_version
+            );
+            let _ = send_message(&mut messages, &ConsoleOutput::Closed { reason: reason.clone() }).await;
+            reject(&context, addr_str, username, reason);
+            return;
+        }
+        _ => {
+            reject(&context, addr_str, " ".to_string(), "hello message expected".to_string());
+            return;
+        }
+    };
+
+    let (output_tx, mut output_rx) = mpsc::unbounded_channel::<ConsoleOutput>();
+    let (input_tx, input_rx) = std::sync::mpsc::channel::<String>();
+    let session = {
+        let context = context.clone();
+        let addr_str = addr_str.clone();
+        let username = username.clone();
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let output = output_tx.clone();
+            attach_remote(RemoteConsole {
+                output: output_tx,
+                input: input_rx,
+            });
+            let authenticated = run_session(&username, &password, addr_str, cert_subject, output, &context, &runtime);
+            detach_remote();
+            authenticated
+        })
+    };
+
+    loop {
+        tokio::select! {
+            output = output_rx.recv() => match output {
+                Some(output) => {
+                    let closed = matches!(output, ConsoleOutput::Closed { .. });
+                    if send_message(&mut messages, &output).await.is_err() || closed {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            request = recv_message::<_, ConsoleRequest>(&mut messages) => match request {
+                Ok(Some(ConsoleRequest::Input { line })) => {
+                    let _ = input_tx.send(line);
+                }
+                Ok(Some(ConsoleRequest::Hello { .. })) => {}
+                Ok(None) | Err(_) => break,
+            },
+        }
+    }
+
+    // input of the session is closed, so menus return to the session thread
+    drop(input_tx);
+    // failed logon is in audit log already, only sessions that were connected are closed
+    if session.await.unwrap_or(false) {
+        publish(
+            &context.bus,
+            ServerEvent::ConsoleDisconnected {
+                host: context.hostname.clone(),
+                addr: addr_str,
+                user: username,
+            },
+        );
+    }
+}
+
+// Logon and menus of one remote operator, console of this thread is attached already.
+// output - the same client, lock notices and the end of session go there.
+// Returns false if the operator is not authenticated
+fn run_session(
+    username: &str,
+    password: &str,
+    addr_str: String,
+    cert_subject: String,
+    output: mpsc::UnboundedSender<ConsoleOutput>,
+    context: &ConsoleContext,
+    runtime: &Handle,
+) -> bool {
+    // audit status is copied, holding it while operator types would stop audit writer
+    let aud_stat = *context.audit_status.lock().unwrap();
+    let (authenticated, user, role) = authenticate(
+        username,
+        password,
+        &context.operator_auth,
+        context.hostname.clone(),
+        &context.file_mutexes,
+        context.log_files.audit_file.clone(),
+        aud_stat,
+    );
+    if !authenticated {
+        let _ = output.send(ConsoleOutput::Closed {
+            reason: "authentication failed".to_string(),
+        });
+        return false;
+    }
+    publish(
+        &context.bus,
+        ServerEvent::ConsoleConnected {
+            host: context.hostname.clone(),
+            addr: addr_str.clone(),
+            user: user.clone(),
+            cert_subject,
+        },
+    );
+
+    let activity = Arc::new(Mutex::new(SessionActivity {
+        last_input: Instant::now(),
+        locked: false,
+    }));
+    let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    context.sessions.lock().unwrap().insert(
+        session_id,
+        ConsoleSession {
+            host: context.hostname.clone(),
+            user: user.clone(),
+            remote_addr: Some(addr_str.clone()),
+            activity: Arc::clone(&activity),
+            output: Some(output.clone()),
+        },
+    );
+
+    let mut session_status = SessionStatus {
+        host: context.hostname.clone(),
+        user,
+        permissions: role_permissions(&context.log_files.roles_file, &role),
+        role,
+        sensor_list: Arc::clone(&context.sensors),
+        activity,
+        idle_timeout: context.idle_timeout,
+        users_file: context.log_files.users_file.clone(),
+        lockout_file: context.log_files.lockout_file.clone(),
+        roles_file: context.log_files.roles_file.clone(),
+        lockout: context.operator_auth.policy.lockout,
+        remote_addr: Some(addr_str),
+        session_id,
+    };
+    runtime.block_on(main_menu(
+        &mut session_status,
+        &context.log_files,
+        context.bus.clone(),
+        &context.file_mutexes,
+        &context.audit_status,
+        &context.operator_auth.policy.password,
+    ));
+
+    context.sessions.lock().unwrap().remove(&session_id);
+    let _ = output.send(ConsoleOutput::Closed {
+        reason: "session is over".to_string(),
+    });
+    true
+}
+
+// Tells remote clients the server stops. Their sessions end when connections are closed
+pub fn close_remote_sessions(sessions: &ConsoleSessions, reason: &str) {
+    for session in sessions.lock().unwrap().values() {
+        if let Some(output) = &session.output {
+            let _ = output.send(ConsoleOutput::Closed {
+                reason: format!("management server is stopping: {}", reason),
+            });
+        }
+    }
+}
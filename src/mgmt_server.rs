@@ -1,30 +1,34 @@
-mod auth;
-mod file_manager;
-mod menu;
-mod sensor_handler;
-mod structs;
-
-use chrono::{DateTime, Local};
-use clap::{Arg, Command};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-use tokio::net::TcpListener;
-use tokio::spawn;
-use tokio::sync::mpsc;
-
-use crate::auth::auth::authenticate;
-use crate::file_manager::file_manager::audit_handler::{prepare_file_mutexes, write_audit_event};
-use crate::file_manager::file_manager::event_handler::write_security_event;
-use crate::menu::menu::main_menu;
-use crate::sensor_handler::sensor_handler::handle_client;
-use crate::structs::soc_structs::multithread::FileMutexes;
-use crate::structs::soc_structs::{AuditEventType, LogFiles, SessionStatus};
+use std::time::{Duration, Instant};
+use tokio_util::task::TaskTracker;
+
+use rsoc::auth::auth::{authenticate, logon_password};
+use rsoc::auth::roles::{add_sensor_operator_role, import_roles, role_permissions};
+use rsoc::auth::session::lock_idle_sessions;
+use rsoc::event_bus::event_bus::{new_event_bus, next_message, publish, ServerEvent};
+use rsoc::event_bus::subscribers::{spawn_audit_writer, spawn_console_printer, spawn_event_writer};
+use rsoc::file_manager::file_manager::audit_handler::{prepare_file_mutexes, write_audit_event};
+use rsoc::file_manager::file_manager::user_file_handler::{check_user_store, init_user_store, key_file_path, load_protected, set_key_dir};
+use rsoc::menu::menu::main_menu;
+use rsoc::menu::remote_console::{close_remote_sessions, spawn_console_listener, ConsoleContext};
+use rsoc::sensor_handler::sensor_handler::{find_silent_sensors, notify_shutdown, save_sensors_seen};
+use rsoc::protocol::messages::RateLimit;
+use rsoc::sensor_handler::rate_limiter::parse_sensor_limits;
+use rsoc::sensor_handler::sensor_listener::{bind_listener, close_listener, spawn_listener, ListenerContext, SensorListener, UNIX_PREFIX};
+use rsoc::structs::soc_structs::multithread::FileMutexes;
+use rsoc::structs::soc_structs::{AuditEventType, AuthPolicy, ConsoleSession, ConsoleSessions, LockoutPolicy, LogFiles, OperatorAuth, PasswordPolicy, RateLimits, SensorMap, SessionActivity, SessionStatus};
+use rsoc::tls::certificates::{create_ca, issue_client_certificate, CONSOLE_UNIT, SENSOR_UNIT};
+use rsoc::tls::tls_config::{server_tls_acceptor, TlsFiles};
 
 const CONFIG: &str = "server_config.txt";
 
+// time for sensors to get shutdown notice and close connections
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
     let matches = Command::new("rSOC")
@@ -39,17 +43,98 @@ async fn main() {
         .arg(Arg::new("password")
                  .short('p')
                  .long("password")
-                 .help("User's password"))
+                 .conflicts_with_all(["password_env", "password_fd"])
+                 .help("User's password. Visible to other users in process list, prompt is used if no password option is set"))
+        .arg(Arg::new("password_env")
+                 .long("password-env")
+                 .value_name("VARIABLE")
+                 .conflicts_with("password_fd")
+                 .help("Read user's password from environment variable"))
+        .arg(Arg::new("password_fd")
+                 .long("password-fd")
+                 .value_name("FD")
+                 .value_parser(value_parser!(u32))
+                 .help("Read user's password from the first line of opened file descriptor, e.g. --password-fd 3 3<password_file"))
                  .arg_required_else_help(true)
+        .subcommand(Command::new("pki")
+                 .about("Offline management of sensor certificates")
+                 .subcommand_required(true)
+                 .subcommand(Command::new("init")
+                          .about("Create local CA and management server certificate")
+                          .arg(Arg::new("san")
+                                   .long("san")
+                                   .action(ArgAction::Append)
+                                   .help("Extra DNS name or IP address of the server (localhost and 127.0.0.1 are always included)")))
+                 .subcommand(Command::new("issue")
+                          .about("Issue certificate for sensor or remote console")
+                          .arg(Arg::new("sensor_name")
+                                   .required(true)
+                                   .help("Sensor name, must be equal to 'sensor_name' in sensor config. Console name with --console"))
+                          .arg(Arg::new("console")
+                                   .long("console")
+                                   .action(ArgAction::SetTrue)
+                                   .help("Certificate of console_client: it opens remote console and can't connect as sensor"))
+                          .arg(Arg::new("out")
+                                   .short('o')
+                                   .long("out")
+                                   .default_value("pki")
+                                   .help("Directory for certificate and key"))))
+        .subcommand(Command::new("users")
+                 .about("Offline management of users and roles files")
+                 .subcommand_required(true)
+                 .subcommand(Command::new("init")
+                          .about("Create default users and roles files with integrity keys on the first start"))
+                 .subcommand(Command::new("sign")
+                          .about("Protect users and roles files of older version with integrity keys. Run it only if nobody has changed the files"))
+                 .subcommand(Command::new("roles")
+                          .about("Print roles file, or replace it with edited copy and sign it")
+                          .arg(Arg::new("import")
+                                   .long("import")
+                                   .value_name("FILE")
+                                   .help("Roles file to sign and use instead of current one, lines are role[:|:]permission, permission, ..."))))
         .get_matches();
 
     let mut user_list_file: String = String::new();
+    let mut roles_file: String = String::new();
+    let mut lockout_file: String = String::new();
+    let mut integrity_key_dir: String = String::new();
+    let mut auth_policy = AuthPolicy {
+        lockout: LockoutPolicy {
+            attempts: 5,
+            window_secs: 300,
+            duration_secs: 900,
+        },
+        password: PasswordPolicy {
+            min_length: 12,
+            classes: 3,
+            expiry_days: 90,
+            history: 5,
+        },
+        totp_required: true,
+    };
     let mut audit_log: String = String::new();
     let mut event_log: String = String::new();
     let mut rules_file: String = String::new();
+    let mut rules_history_file: String = String::new();
+    let mut registry_file: String = String::new();
+    let mut state_file: String = String::new();
+    let mut tokens_file: String = String::new();
     let mut hostname: String = String::new();
     let mut lport: String = String::new();
+    let mut bind_addresses: Vec<String> = Vec::new();
+    let mut unix_socket: String = String::new();
+    let mut console_address: String = String::new();
     let mut print_state = false;
+    let mut heartbeat_timeout: u64 = 30;
+    let mut session_idle_timeout: u64 = 900;
+    let mut rate_limits = RateLimits {
+        default: RateLimit::default(),
+        sensors: HashMap::new(),
+    };
+    let mut tls_ca_cert: String = String::new();
+    let mut tls_ca_key: String = String::new();
+    let mut tls_cert: String = String::new();
+    let mut tls_key: String = String::new();
 
     // config parcing
     {
@@ -71,19 +156,46 @@ async fn main() {
                         continue;
                     }
 
-                    let parts: Vec<&str> = line.split(':').collect();
-                    if parts.len() == 2 {
-                        let key = parts[0].trim();
-                        let value = parts[1].trim().trim_end_matches(';');
+                    // value can contain ':' itself (addresses), so split by the first one
+                    if let Some((key, value)) = line.split_once(':') {
+                        let key = key.trim();
+                        let value = value.trim().trim_end_matches(';');
 
                         match key {
                             "audit_log" => audit_log = value.to_string(),
                             "event_log" => event_log = value.to_string(),
                             "user_list_file" => user_list_file = value.to_string(),
+                            "roles_file" => roles_file = value.to_string(),
+                            "lockout_file" => lockout_file = value.to_string(),
+                            "integrity_key_dir" => integrity_key_dir = value.to_string(),
+                            "lockout_attempts" => auth_policy.lockout.attempts = value.parse().unwrap_or(5),
+                            "lockout_window" => auth_policy.lockout.window_secs = value.parse().unwrap_or(300),
+                            "lockout_duration" => auth_policy.lockout.duration_secs = value.parse().unwrap_or(900),
+                            "password_min_length" => auth_policy.password.min_length = value.parse().unwrap_or(12),
+                            "password_classes" => auth_policy.password.classes = value.parse().unwrap_or(3),
+                            "password_expiry_days" => auth_policy.password.expiry_days = value.parse().unwrap_or(90),
+                            "password_history" => auth_policy.password.history = value.parse().unwrap_or(5),
+                            "totp_required" => auth_policy.totp_required = value != "0",
                             "rules_file" => rules_file = value.to_string(),
+                            "rules_history" => rules_history_file = value.to_string(),
+                            "sensor_registry" => registry_file = value.to_string(),
+                            "sensor_state" => state_file = value.to_string(),
+                            "enrollment_tokens" => tokens_file = value.to_string(),
                             "hostname" => hostname = value.to_string(),
                             "lport" => lport = value.to_string(),
-                            "event_print" => print_state = if value == "0" { false } else { true },
+                            "bind_addresses" => bind_addresses = value.split(',').map(|address| address.trim().to_string()).filter(|address| !address.is_empty()).collect(),
+                            "unix_socket" => unix_socket = value.to_string(),
+                            "console_address" => console_address = value.to_string(),
+                            "event_print" => print_state = value != "0",
+                            "heartbeat_timeout" => heartbeat_timeout = value.parse().unwrap_or(30),
+                            "session_idle_timeout" => session_idle_timeout = value.parse().unwrap_or(900),
+                            "event_rate_limit" => rate_limits.default.events_per_sec = value.parse().unwrap_or(0),
+                            "event_burst" => rate_limits.default.burst = value.parse().unwrap_or(0),
+                            "sensor_rate_limits" => rate_limits.sensors = parse_sensor_limits(value),
+                            "tls_ca_cert" => tls_ca_cert = value.to_string(),
+                            "tls_ca_key" => tls_ca_key = value.to_string(),
+                            "tls_cert" => tls_cert = value.to_string(),
+                            "tls_key" => tls_key = value.to_string(),
                             _ => eprintln!("Неизвестный ключ: {}", key),
                         }
                     }
@@ -95,40 +207,135 @@ async fn main() {
         }
     }
 
-    let sensors: HashMap<String, (mpsc::Sender<String>, String, String, bool)> = HashMap::new();
-    let sensors_mutex = Arc::new(Mutex::new(sensors));
+    let tls_files = TlsFiles {
+        ca_cert: tls_ca_cert,
+        cert: tls_cert,
+        key: tls_key,
+    };
+
+    if let Some(("pki", pki_matches)) = matches.subcommand() {
+        pki_command(pki_matches, &tls_files, &tls_ca_key, &hostname);
+        return;
+    }
+
+    let sensors_mutex: SensorMap = Arc::new(Mutex::new(HashMap::new()));
     let sensors_mutex_clone_for_rx = Arc::clone(&sensors_mutex);
 
     let log_files = LogFiles {
         audit_file: audit_log.clone(),
         event_file: event_log.clone(),
         rules_file: rules_file.clone(),
+        rules_history_file,
+        registry_file,
+        state_file,
+        tokens_file,
+        users_file: user_list_file.clone(),
+        roles_file: roles_file.clone(),
+        lockout_file: lockout_file.clone(),
     };
 
     let file_mutexes: FileMutexes = prepare_file_mutexes(&log_files);
-    let file_mutexes_clone = FileMutexes {
-        audit_mutex: Arc::clone(&file_mutexes.audit_mutex),
-        event_mutex: Arc::clone(&file_mutexes.event_mutex),
-        rules_mutex: Arc::clone(&file_mutexes.rules_mutex),
+    let file_mutexes_clone: FileMutexes = file_mutexes.clone();
+
+    let users_command = match matches.subcommand() {
+        Some(("users", users_matches)) => users_matches.subcommand_name(),
+        _ => None,
     };
+    if let Err(reason) = set_key_dir(&integrity_key_dir, &[&user_list_file, &roles_file]) {
+        eprintln!("ALERT: {}. Management server is not started.", reason);
+        std::process::exit(1);
+    }
+    if users_command == Some("init") {
+        match init_user_store(&user_list_file, &roles_file) {
+            Ok(()) => println!(
+                "Users file {} and roles file {} are created. Log on as admin/admin and change the password",
+                user_list_file, roles_file
+            ),
+            Err(reason) => {
+                eprintln!("Users and roles files are not created: {}", reason);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let is_admin;
+    // users or roles file changed outside of the server can hide new account, password or right,
+    // so nobody logs on then. Deleted files are not recreated, admin/admin would be back
+    let sign_users = users_command == Some("sign");
+    for (kind, file) in [("Users", &user_list_file), ("Roles", &roles_file)] {
+        match check_user_store(file, sign_users) {
+            Ok(true) => println!(
+                "{} file {} is protected with integrity key {}",
+                kind,
+                file,
+                key_file_path(file).unwrap_or_default()
+            ),
+            Ok(false) if sign_users => println!("{} file {} is protected already", kind, file),
+            Ok(false) => {}
+            Err(reason) => {
+                write_audit_event(
+                    hostname.clone(),
+                    "system".to_string(),
+                    AuditEventType::UserStoreAlert,
+                    format!("{} file integrity check failed: {}. Management server is not started", kind, reason),
+                    &file_mutexes,
+                    &audit_log,
+                    true,
+                );
+                eprintln!(
+                    "ALERT: {} file {} integrity check failed: {}.\nRestore the file from backup. Management server is not started.",
+                    kind.to_lowercase(),
+                    file,
+                    reason
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    if sign_users {
+        match add_sensor_operator_role(&roles_file) {
+            Ok(true) => println!("Role sensor_operator is added to roles file {}", roles_file),
+            Ok(false) => {}
+            Err(reason) => eprintln!("Role sensor_operator is not added to roles file {}: {}", roles_file, reason),
+        }
+        return;
+    }
+    if let Some(("users", users_matches)) = matches.subcommand() {
+        if let Some(("roles", roles_matches)) = users_matches.subcommand() {
+            roles_command(roles_matches, &roles_file, &hostname, &file_mutexes, &audit_log);
+            return;
+        }
+    }
+
+    // console users and sensor operators are checked against the same files
+    let operator_auth = OperatorAuth {
+        users_file: user_list_file.clone(),
+        roles_file: roles_file.clone(),
+        lockout_file: lockout_file.clone(),
+        policy: auth_policy,
+    };
+
+    let role: String;
     let username: String;
-    let listener;
     let audit_status: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
     let audit_status_clone = Arc::clone(&audit_status);
 
     {
         let input_username = matches.get_one::<String>("user").unwrap();
-        let input_password = matches.get_one::<String>("password").unwrap();
+        let input_password = match logon_password(&matches, "Password: ") {
+            Some(password) => password,
+            None => {
+                println!("Password is not entered. Goodbye.");
+                return;
+            }
+        };
         let aud_stat = audit_status.lock().unwrap();
-        let usr_list = user_list_file.clone();
         let hstnm = hostname.clone();
         let aud_log = audit_log.clone();
         let auth_res = authenticate(
-            &input_username,
+            input_username,
             &input_password,
-            &usr_list,
+            &operator_auth,
             hstnm,
             &file_mutexes,
             aud_log,
@@ -136,7 +343,7 @@ async fn main() {
         );
         if auth_res.0 {
             username = auth_res.1;
-            is_admin = auth_res.2;
+            role = auth_res.2;
         } else {
             return;
         }
@@ -145,129 +352,329 @@ async fn main() {
     let hostname_clone = hostname.clone();
     let username_clone = username.clone();
 
-    match TcpListener::bind("127.0.0.1:".to_string() + lport.as_str()).await {
-        Ok(bind_res) => {
-            listener = bind_res;
-        }
+    let tls_acceptor = match server_tls_acceptor(&tls_files) {
+        Ok(acceptor) => acceptor,
         Err(e) => {
-            println!("Failed to bind to {} port. Try again.\n{}", lport, e);
+            println!("Failed to set up TLS: {}\nCreate certificates with 'pki init' command first.", e);
             return;
         }
+    };
+
+    // old configs have only port
+    if bind_addresses.is_empty() {
+        bind_addresses.push("127.0.0.1:".to_string() + lport.as_str());
+    }
+    if !unix_socket.is_empty() {
+        bind_addresses.push(UNIX_PREFIX.to_string() + unix_socket.as_str());
     }
 
+    let mut listeners: Vec<SensorListener> = Vec::new();
+    for address in &bind_addresses {
+        match bind_listener(address).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                println!("Failed to bind to {}. Try again.\n{}", address, e);
+                for listener in &listeners {
+                    close_listener(listener.address());
+                }
+                return;
+            }
+        }
+    }
+    let listener_addresses: Vec<String> = listeners.iter().map(|listener| listener.address().to_string()).collect();
+
+    // remote console is off without address
+    let console_listener = if console_address.is_empty() {
+        None
+    } else {
+        match bind_listener(&console_address).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                println!("Failed to bind remote console to {}. Try again.\n{}", console_address, e);
+                for address in &listener_addresses {
+                    close_listener(address);
+                }
+                return;
+            }
+        }
+    };
+    let console_listener_address = console_listener.as_ref().map(|listener| listener.address().to_string());
+
     {
         let aud_stat = audit_status_clone.lock().unwrap();
         let hst = hostname_clone.clone();
         let usr = username_clone.clone();
         write_audit_event(
-            SystemTime::now(),
             hst,
             usr,
             AuditEventType::ServOn,
-            "Management server turned on. Listener started".to_string(),
+            match &console_listener_address {
+                Some(address) => format!(
+                    "Management server turned on. Listening on {}. Remote console on {}",
+                    listener_addresses.join(", "),
+                    address
+                ),
+                None => format!("Management server turned on. Listening on {}", listener_addresses.join(", ")),
+            },
             &file_mutexes_clone,
             &audit_log,
             *aud_stat,
         );
-        println!("Start listening on {} port", lport);
+        println!("Start listening on {}", listener_addresses.join(", "));
+        if let Some(address) = &console_listener_address {
+            println!("Remote console is available on {}", address);
+        }
     }
 
-    let (tx, mut rx) = mpsc::channel::<String>(32);
+    // local console is session 0, remote ones are added by remote console service
+    let console_sessions: ConsoleSessions = Arc::new(Mutex::new(HashMap::new()));
+
+    // every output of the server is a bus subscriber, a slow one doesn't hold up the others
+    let bus = new_event_bus();
+    let subscribers = vec![
+        spawn_event_writer(&bus, Arc::clone(&sensors_mutex_clone_for_rx), file_mutexes_clone.clone(), event_log),
+        spawn_audit_writer(&bus, file_mutexes_clone.clone(), audit_log.clone(), Arc::clone(&audit_status_clone)),
+        spawn_console_printer(&bus, print_state, Arc::clone(&console_sessions)),
+    ];
+    let mut bus_rx = bus.subscribe();
 
-    // console interface
+    // console input time, the server loop locks the session when it is too old
+    let idle_timeout = Duration::from_secs(session_idle_timeout);
+    let session_activity = Arc::new(Mutex::new(SessionActivity {
+        last_input: Instant::now(),
+        locked: false,
+    }));
+    console_sessions.lock().unwrap().insert(
+        0,
+        ConsoleSession {
+            host: hostname.clone(),
+            user: username.clone(),
+            remote_addr: None,
+            activity: Arc::clone(&session_activity),
+            output: None,
+        },
+    );
+    let console_log_files = log_files.clone();
+
+    // console interface: reading stdin blocks, so it gets its own thread instead of a runtime worker
     {
-        let tx_clone = tx.clone();
-        spawn(async move {
+        let console_bus = bus.clone();
+        let activity = Arc::clone(&session_activity);
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || runtime.block_on(async move {
             let current_session: &mut SessionStatus = &mut SessionStatus {
                 host: hostname,
                 user: username,
-                is_admin: is_admin,
+                permissions: role_permissions(&log_files.roles_file, &role),
+                role,
                 sensor_list: sensors_mutex,
+                activity,
+                idle_timeout,
+                users_file: log_files.users_file.clone(),
+                lockout_file: log_files.lockout_file.clone(),
+                roles_file: log_files.roles_file.clone(),
+                lockout: auth_policy.lockout,
+                remote_addr: None,
+                session_id: 0,
             };
 
             main_menu(
                 current_session,
                 &log_files,
-                tx_clone,
+                console_bus,
                 &file_mutexes,
                 &audit_status,
+                &auth_policy.password,
             )
             .await;
-        });
+        }));
+    }
+
+    // all listeners feed the same handle_client pipeline
+    let listener_context = ListenerContext {
+        tls_acceptor,
+        rules_file: rules_file.clone(),
+        rate_limits,
+        operator_auth: operator_auth.clone(),
+        sensors: Arc::clone(&sensors_mutex_clone_for_rx),
+        bus: bus.clone(),
+        file_mutexes: file_mutexes_clone.clone(),
+        tracker: TaskTracker::new(),
+    };
+    let sensor_connections = listener_context.tracker.clone();
+    let mut listener_tasks: Vec<_> = listeners.into_iter().map(|listener| spawn_listener(listener, listener_context.clone())).collect();
+    let mut listener_addresses = listener_addresses;
+
+    // remote operators get the same menus, each in own session
+    if let Some(listener) = console_listener {
+        let console_context = ConsoleContext {
+            tls_acceptor: listener_context.tls_acceptor.clone(),
+            hostname: hostname_clone.clone(),
+            log_files: console_log_files,
+            operator_auth,
+            idle_timeout,
+            sensors: Arc::clone(&sensors_mutex_clone_for_rx),
+            sessions: Arc::clone(&console_sessions),
+            bus: bus.clone(),
+            file_mutexes: file_mutexes_clone.clone(),
+            audit_status: Arc::clone(&audit_status_clone),
+            tracker: sensor_connections.clone(),
+        };
+        listener_addresses.push(listener.address().to_string());
+        listener_tasks.push(spawn_console_listener(listener, console_context));
     }
 
+    // Ctrl-C and SIGTERM stop the server the same way as exit from console
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+
+    // sensors health check
+    let mut health_check = tokio::time::interval(Duration::from_secs(heartbeat_timeout.div_ceil(2).max(1)));
+    // session idle check, zero timeout turns it off
+    let mut idle_check = tokio::time::interval(Duration::from_secs(1));
+
     // sensors handling
-    loop {
+    let (stopped_by, reason) = loop {
         tokio::select! {
-            result = listener.accept() => match result {
-                Ok((stream, addr)) => {
-                    // check address format: port is required
-                    let addr_str = format!("{}", addr);
-                    let (client_tx, client_rx) = mpsc::channel::<String>(32);
-                    let main_tx = tx.clone();
-                    let sensors_mutex_clone_for_clients = Arc::clone(&sensors_mutex_clone_for_rx);
-                    let server_tx_clone = tx.clone();
-                    let ru_file = rules_file.clone();
-                    spawn(async move {
-                        if let Err(e) = handle_client(stream, addr_str, client_rx, &ru_file, Arc::clone(&sensors_mutex_clone_for_clients), client_tx, server_tx_clone).await {
-                            println!("Error while client processing:\n{}", e);
-                        }
-                        main_tx.send("client_disc".to_string()).await.unwrap();
-                    });
-                },
-                Err(e) => println!("Error while recieving connection:\n{}", e),
+            reason = &mut signal => {
+                publish(&bus, ServerEvent::ShutdownRequested { user: "system".to_string(), reason: reason.to_string() });
+                break ("system".to_string(), reason.to_string());
             },
-            command = rx.recv() => match command {
-                Some(ref cmd) if cmd == "stop" => {
-                    println!("Stop listening...");
-                    let aud_stat = audit_status_clone.lock().unwrap();
-                    write_audit_event(SystemTime::now(), hostname_clone, username_clone, AuditEventType::ServOff, "Management server turned off".to_string(), &file_mutexes_clone, &audit_log, *aud_stat);
-                    break;
-                },
-                Some(ref cmd) if cmd.starts_with("cl_disc") => {
-                    // parced_cmd[1] - address of client, parced_cmd[2] - name of client, parced_cmd[3] - level of client, parced_cmd[4] - client username
-                    let parced_cmd: Vec<&str> = cmd.split("[:1:]").collect();
-                    sensors_mutex_clone_for_rx.lock().unwrap().remove(parced_cmd[1]);
-                    println!("Client disconnected: {} ({})", parced_cmd[1].to_string(), parced_cmd[2].to_string());
-                    let event_type = if parced_cmd[3] == "net" { AuditEventType::NetSenDisconn } else { AuditEventType::HostSenDisconn };
-
-                    let aud_stat = audit_status_clone.lock().unwrap();
-                    write_audit_event(SystemTime::now(), parced_cmd[2].to_string(), parced_cmd[4].to_string(), event_type, "Sensor disconnected. Type - ".to_string() + parced_cmd[3], &file_mutexes_clone, &audit_log, *aud_stat);
-                }
-                Some(ref cmd) if cmd.starts_with("init") => {
-                    // parced_cmd[1] - name of client, parced_cmd[2] - client type, parced_cmd[3] - client user
-                    let init_vec: Vec<&str> = cmd.split("[:1:]").collect();
-                    let event_type = if init_vec[2] == "net" { AuditEventType::NetSenConn } else { AuditEventType::HostSenConn };
-
-                    let aud_stat = audit_status_clone.lock().unwrap();
-                    write_audit_event(SystemTime::now(), init_vec[1].to_string(), init_vec[3].to_string(), event_type, "Sensor connected. Type - ".to_string() + init_vec[2], &file_mutexes_clone, &audit_log, *aud_stat);
-                }
-                Some(ref cmd) if cmd.starts_with("update") => {
-                    // parced_cmd[1] - name of client, parced_cmd[2] - client user, parced_cmd[3] - client level
-                    let init_vec: Vec<&str> = cmd.split("[:3:]").collect();
-
-                    let aud_stat = audit_status_clone.lock().unwrap();
-                    write_audit_event(SystemTime::now(), init_vec[1].to_string(), init_vec[2].to_string(), AuditEventType::RulesUpdate, "Rules updated - ".to_string() + init_vec[3] + " level", &file_mutexes_clone, &audit_log, *aud_stat);
+            _ = health_check.tick() => {
+                save_sensors_seen(&sensors_mutex_clone_for_rx, &file_mutexes_clone);
+                for (name, user) in find_silent_sensors(&sensors_mutex_clone_for_rx, Duration::from_secs(heartbeat_timeout)) {
+                    publish(&bus, ServerEvent::SensorSilent { name, user, timeout_secs: heartbeat_timeout });
                 }
-                Some(ref cmd) if cmd.starts_with("event") => {
-                    // parced_cmd[1] - rule hash, parced_cmd[2] - UNIX-time, parced_cmd[4] - sensor name, parced_cmd[5] - level, parced_cmd[6] - sensor_status
-                    let parced_cmd: Vec<&str> = cmd.split("[:3:]").collect();
-
-                    // if parced_cmd[6] == "false" { continue; }
-                    let net_level = if parced_cmd[6] == "net" { true } else { false };
-                    let mut host_path = " ";
-                    let host_name;
-                    if !net_level { if parced_cmd[6] == "false" { continue; } host_path = parced_cmd[3]; host_name = parced_cmd[4]; } else { if parced_cmd[7] == "false" { continue; } host_name = parced_cmd[5] }
-                    let unix_time: i64 = parced_cmd[2].parse().unwrap();
-                    let datetime: DateTime<Local> = DateTime::from_timestamp(unix_time, 0).unwrap().with_timezone(&Local);
-
-                    write_security_event(datetime, host_name.to_string(), parced_cmd[1].to_string(), net_level, &file_mutexes_clone, &event_log.clone(), &host_path.to_string());
-                    if print_state {
-                        println!("Event! Time: {}, Sensor: {}", datetime.format("%d-%m-%Y %H:%M:%S").to_string(), host_name);
-                    }
+            },
+            _ = idle_check.tick(), if !idle_timeout.is_zero() => {
+                for event in lock_idle_sessions(&console_sessions, idle_timeout) {
+                    publish(&bus, event);
                 }
+            },
+            message = next_message(&mut bus_rx) => match message {
+                Some(Ok(ServerEvent::ShutdownRequested { user, reason })) => break (user, reason),
+                None => break (username_clone.clone(), "event bus closed".to_string()),
+                Some(Err(lost)) => println!("Sensor list may be out of date: {} server messages missed", lost),
                 _ => {}
             }
         }
+    };
+
+    // no new sensors, connected ones go offline and spool events until the server is back
+    for (task, address) in listener_tasks.iter().zip(&listener_addresses) {
+        task.abort();
+        close_listener(address);
+    }
+    let notified = notify_shutdown(&sensors_mutex_clone_for_rx, &reason);
+    close_remote_sessions(&console_sessions, &reason);
+    sensor_connections.close();
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, sensor_connections.wait()).await.is_err() {
+        println!("Not all of {} sensors closed connection in {} seconds", notified, SHUTDOWN_TIMEOUT.as_secs());
+    }
+    save_sensors_seen(&sensors_mutex_clone_for_rx, &file_mutexes_clone);
+
+    // everything sensors sent is on the bus before Shutdown, so subscribers write it
+    // and audit writer writes ServOff as the last record
+    publish(&bus, ServerEvent::Shutdown { host: hostname_clone, user: stopped_by, reason });
+    for subscriber in subscribers {
+        let _ = subscriber.await;
+    }
+
+    // console thread can be blocked on reading stdin, there is nothing to wait for
+    std::process::exit(0);
+}
+
+// Resolves with the name of the signal that asks the server to stop.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "Ctrl-C",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                "Ctrl-C"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+fn pki_command(pki_matches: &ArgMatches, tls_files: &TlsFiles, ca_key: &str, hostname: &str) {
+    match pki_matches.subcommand() {
+        Some(("init", init_matches)) => {
+            let mut server_names: Vec<String> = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+            if let Some(sans) = init_matches.get_many::<String>("san") {
+                server_names.extend(sans.cloned());
+            }
+
+            match create_ca(&tls_files.ca_cert, ca_key, &tls_files.cert, &tls_files.key, hostname, &server_names) {
+                Ok(_) => println!("CA created: {}\nServer certificate: {}", tls_files.ca_cert, tls_files.cert),
+                Err(e) => println!("Failed to create CA: {}", e),
+            }
+        }
+        Some(("issue", issue_matches)) => {
+            let sensor_name = issue_matches.get_one::<String>("sensor_name").unwrap();
+            let out_dir = issue_matches.get_one::<String>("out").unwrap();
+            let (unit, host) = if issue_matches.get_flag("console") {
+                (CONSOLE_UNIT, "console")
+            } else {
+                (SENSOR_UNIT, "sensor")
+            };
+
+            match issue_client_certificate(&tls_files.ca_cert, ca_key, sensor_name, unit, out_dir) {
+                Ok((cert_file, key_file)) => println!("Certificate for {} issued: {}, key: {}\nCopy them with CA certificate ({}) to the {} host.", sensor_name, cert_file, key_file, tls_files.ca_cert, host),
+                Err(e) => println!("Failed to issue certificate: {}", e),
+            }
+        }
+        _ => {}
+    }
+}
+
+// Roles file is signed, so it is edited as a copy: 'users roles > copy', then 'users roles --import copy'
+fn roles_command(roles_matches: &ArgMatches, roles_file: &str, hostname: &str, file_mutexes: &FileMutexes, audit_log: &str) {
+    let source = match roles_matches.get_one::<String>("import") {
+        Some(source) => source,
+        None => {
+            match load_protected(roles_file) {
+                Ok(content) => print!("{}", content),
+                Err(reason) => eprintln!("Roles file {} can't be read: {}", roles_file, reason),
+            }
+            return;
+        }
+    };
+
+    let content = match std::fs::read_to_string(source) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{} can't be read: {}", source, e);
+            std::process::exit(1);
+        }
+    };
+    match import_roles(roles_file, &content) {
+        Ok(count) => {
+            write_audit_event(
+                hostname.to_string(),
+                "system".to_string(),
+                AuditEventType::RolesChange,
+                format!("Roles file replaced offline with {}. Roles - {}", source, count),
+                file_mutexes,
+                audit_log,
+                true,
+            );
+            println!("Roles file {} is replaced with {} and signed, {} roles", roles_file, source, count);
+        }
+        Err(reason) => {
+            eprintln!("Roles file is not changed: {}", reason);
+            std::process::exit(1);
+        }
     }
 }
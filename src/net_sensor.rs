@@ -1,12 +1,22 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
-use crate::menu::menu::get_user_choice;
-use clap::{Arg, Command};
-use tokio::time::{sleep, Duration};
+use std::io::ErrorKind;
+use rsoc::auth::auth::logon_password;
+use rsoc::menu::menu::get_user_choice;
+use rsoc::protocol::framing::{recv_message, send_message};
+use rsoc::file_manager::file_manager::config_handler::read_config;
+use rsoc::file_manager::file_manager::credential_handler::load_sensor_credential;
+use rsoc::file_manager::file_manager::spool_handler::{EventSpool, SpillFile};
+use rsoc::protocol::messages::{RateLimit, RulesState, SensorCommand, SensorMessage, ServerMessage};
+use clap::{value_parser, Arg, Command};
 use std::fs::OpenOptions;
-use crate::sensor_handler::rule_handler::get_rules_map;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use rsoc::sensor_handler::rule_bundle::RuleReceiver;
+use rsoc::sensor_handler::rule_handler::{get_rules_map, parse_rules};
+use rsoc::sensor_handler::rate_limiter::EventThrottle;
+use rsoc::sensor_handler::sensor_link::{check_throttle, event_queue, open_session, reconnect, replay_spool, send_events, update_rules, Batching, EventSender, SensorLink, MAX_BATCH_SIZE};
+use rsoc::tls::tls_config::{sensor_tls_connector, TlsFiles};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use chrono::DateTime;
 use chrono::offset::Local;
 
@@ -14,13 +24,9 @@ use chrono::offset::Local;
 use pnet::datalink::{self, Channel::Ethernet};
 use pnet::packet::ethernet::{EthernetPacket, EtherTypes};
 
-mod menu;
-mod file_manager;
-mod structs;
-mod sensor_handler;
-mod auth;
-
 const CONFIG: &str = "net_sensor_config.txt";
+// detected events waiting in memory for the sender
+const EVENT_QUEUE_SIZE: usize = 1024;
 
 #[tokio::main]
 async fn main() {
@@ -32,181 +38,382 @@ async fn main() {
                  .short('c')
                  .long("command")
                  .help("Type 'update' to update sensor rules. (BTW now you can type anything to update)"))     
+        .arg(Arg::new("password_env")
+                 .long("password-env")
+                 .value_name("VARIABLE")
+                 .conflicts_with("password_fd")
+                 .help("Read password of sensor operator from environment variable, prompt is used if no password option is set"))
+        .arg(Arg::new("password_fd")
+                 .long("password-fd")
+                 .value_name("FD")
+                 .value_parser(value_parser!(u32))
+                 .help("Read password of sensor operator from the first line of opened file descriptor, e.g. --password-fd 3 3<password_file"))
         .get_matches();
 
     let mut sensor_name: String = String::new();
     let mut username: String = String::new();
     let mut rules_file: String = String::new();
+    let mut tls_ca_cert: String = String::new();
+    let mut tls_cert: String = String::new();
+    let mut tls_key: String = String::new();
+    let mut enrollment_token: String = String::new();
+    let mut credential_file: String = String::new();
+    let mut heartbeat_interval: u64 = 10;
+    let mut spool_file: String = String::new();
+    let mut spool_limit: usize = 10000;
+    let mut reconnect_max_delay: u64 = 60;
+    let mut ack_timeout: u64 = 30;
     let mut listen_interface: String = String::new();
+    let mut batching = Batching::none();
 
     let level: String = String::from("net");
 
     // config parcing
-    {
-        let mut conf_file = OpenOptions::new()
-            .read(true) // Позволяем чтение
-            .open(CONFIG)
-            .unwrap();
-        let buf: &mut String = &mut "".to_owned();
-
-        match (conf_file).read_to_string(buf) {
-            Ok(_) => {
-                let strings = buf.split("\n");
-
-                for line in strings {
-                    if line.trim().is_empty() {
-                        continue;
-                    }
-                    if line.starts_with("#") {
-                        continue;
-                    }
-
-                    let parts: Vec<&str> = line.split(':').collect();
-                    if parts.len() == 2 {
-                        let key = parts[0].trim();
-                        let value = parts[1].trim().trim_end_matches(';');
-
-                        match key {
-                            "sensor_name" => sensor_name = value.to_string(),
-                            "username" => username = value.to_string(),
-                            "rules_file" => rules_file = value.to_string(),
-                            "listen_interface" => listen_interface = value.to_string(),
-                            _ => println!("Weird parameter: {}", key),
-                        }
-                    }
+    match read_config(CONFIG) {
+        Ok(config) => {
+            for (key, value) in config {
+                match key.as_str() {
+                    "sensor_name" => sensor_name = value,
+                    "username" => username = value,
+                    "rules_file" => rules_file = value,
+                    "tls_ca_cert" => tls_ca_cert = value,
+                    "tls_cert" => tls_cert = value,
+                    "tls_key" => tls_key = value,
+                    "enrollment_token" => enrollment_token = value,
+                    "credential_file" => credential_file = value,
+                    "heartbeat_interval" => heartbeat_interval = value.parse().unwrap_or(10),
+                    "spool_file" => spool_file = value,
+                    "spool_limit" => spool_limit = value.parse().unwrap_or(10000),
+                    "reconnect_max_delay" => reconnect_max_delay = value.parse().unwrap_or(60),
+                    "ack_timeout" => ack_timeout = value.parse().unwrap_or(30),
+                    "listen_interface" => listen_interface = value,
+                    "batch_size" => batching.size = value.parse().unwrap_or(1),
+                    "batch_interval_ms" => batching.interval = Duration::from_millis(value.parse().unwrap_or(0)),
+                    "batch_compression" => batching.compress = value != "0",
+                    _ => println!("Weird parameter: {}", key),
                 }
             }
-            Err(e) => {
-                println!("{}", e);
-            }
+        }
+        Err(e) => {
+            println!("{}", e);
         }
     }
 
+    batching.size = batching.size.clamp(1, MAX_BATCH_SIZE);
+
     let interfaces = datalink::interfaces();
-    let interface;
+    
 
-    match interfaces.into_iter().find(|iface| iface.name == listen_interface) {
-        Some(res) => { interface = res },
+    let interface = match interfaces.into_iter().find(|iface| iface.name == listen_interface) {
+        Some(res) => { res },
         _ => { println!("Can't find such interface. Check sensor settings."); return; }
+    };
+
+    if enrollment_token.is_empty() && load_sensor_credential(&credential_file).is_none() {
+        println!("Sensor is not enrolled. Create enrollment token in management server console and put it in 'enrollment_token' field of sensor config.");
+        return;
     }
 
-    println!("Enter address (IP:port) of management server:");
+    // operator is checked by management server on every connection
+    let password = match logon_password(&matches, &format!("Password of sensor operator {}: ", username)) {
+        Some(password) => password,
+        None => {
+            println!("Password is not entered.");
+            return;
+        }
+    };
+
+    println!("Enter address (IP:port, [IPv6]:port or unix:/path/to/socket) of management server:");
     let mgmt_server = get_user_choice();
 
-    match TcpStream::connect(mgmt_server.as_str()) {
-        Ok(mut stream) => {
-            let init_message = sensor_name + "[:1:]" + &level.clone().to_string() + "[:1:]" + &username.clone().to_string();
-            let init_message_byte_fmt = init_message.as_bytes();
-            let mut buffer = [0; 1024];
-            stream.write(init_message_byte_fmt).unwrap();
-
-            let _ = sleep(Duration::from_secs(1)).await;
-            if matches.contains_id("rules_update") {
-                stream.write(b"update").unwrap();
-                let size = stream.read(&mut buffer).unwrap();
-                match size {
-                    0 => { println!("Server disconnected. Stop working..."); },
-                    _ => {
-                        let mut rules_file = OpenOptions::new()
-                        .write(true)
-                        .truncate(true)
-                        .create(true)
-                        .read(true)
-                        .open(rules_file)
-                        .unwrap();
-
-                        match write!(rules_file, "{}", String::from_utf8_lossy(&buffer[..size])) {
-                            Ok(_) => { println!("Rules updated succesfully!") },
-                            Err(e) => { println!("Error while writing to rule file: {}", e) }
-                        };
+    let tls_files = TlsFiles { ca_cert: tls_ca_cert, cert: tls_cert, key: tls_key };
+    let tls_connector = match sensor_tls_connector(&tls_files) {
+        Ok(connector) => connector,
+        Err(e) => { println!("Failed to set up TLS: {}", e); return; }
+    };
+
+    let link = SensorLink {
+        address: mgmt_server,
+        connector: tls_connector,
+        name: sensor_name,
+        level: level.clone(),
+        username,
+        password,
+        rules_file: rules_file.clone(),
+        credential_file,
+        enrollment_token,
+    };
+
+    // server may be down at start, then sensor works offline and connects later
+    let mut stream = match open_session(&link).await {
+        Ok(stream) => Some(stream),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied || matches.contains_id("rules_update") => { eprintln!("Failed to connect: {}", e); return; },
+        Err(e) => { eprintln!("Failed to connect: {}. Events will be spooled until connection is restored.", e); None }
+    };
+
+    if matches.contains_id("rules_update") {
+        let (mut stream, _, _) = stream.unwrap();
+        let mut receiver = RuleReceiver::load(&rules_file, &level);
+        match update_rules(&mut stream, &mut receiver).await {
+            Ok(rules) => { println!("Rules updated succesfully! Version {}", rules); },
+            Err(e) => { println!("Rules are not updated: {}", e); }
+        }
 
-                        return;
+        return;
+    }
+
+    let rules_mutex = Arc::new(Mutex::new(OpenOptions::new()
+    .append(true)
+    .create(true)
+    .read(true)
+    .open(rules_file)
+    .unwrap()));
+
+    let mut all_rules_map = get_rules_map(&rules_mutex);
+    let rules_vec = match all_rules_map.remove(level.as_str()) {
+        Some(data_vec) => data_vec,
+        None => { println!("Error with parcing rules. Check rules file."); return; }
+    };
+    // rules pushed by server replace this set on the fly
+    let active_rules = Arc::new(RwLock::new(rules_vec));
+    let thread_rules = Arc::clone(&active_rules);
+
+    // Packet tracer channel
+    let (_tx, mut rx) = match datalink::channel(&interface, Default::default()) {
+        Ok(Ethernet(tx, rx)) => (tx, rx),
+        _ => { println!("Failed to create channel"); return; },
+    };
+
+    // stop/start from server pauses detection, packets are still read and thrown away
+    let capturing = Arc::new(AtomicBool::new(true));
+    let thread_capturing = Arc::clone(&capturing);
+
+    // packet capture is blocking, so it lives in its own thread and hands events over.
+    // Traffic can outrun the sender, then events wait in spill file instead of memory
+    let spill = SpillFile::open(&format!("{}.spill", spool_file), spool_limit);
+    let (event_tx, mut event_rx) = event_queue(EVENT_QUEUE_SIZE, spill);
+    thread::spawn(move || {
+        loop {
+            match rx.next() {
+                Ok(_) if !thread_capturing.load(Ordering::Relaxed) => {},
+                Ok(packet) => {
+                    let ethernet_packet = EthernetPacket::new(packet).unwrap();
+
+                    if ethernet_packet.get_ethertype() == EtherTypes::Ipv4 {
+                        for rule in thread_rules.read().unwrap().iter() {
+                            for pairs_vector in rule {
+
+                                if pairs_vector.1.iter().any(|(k, v)| *v == ethernet_packet.get_source().to_string() && k == "src") {
+                                    if pairs_vector.1.iter().any(|(k, v)| *v == ethernet_packet.get_destination().to_string() && k == "dst")
+                                        || pairs_vector.1.iter().any(|(k, v)| v == " " && k == "dst") {
+                                        if !report_event(&event_tx, pairs_vector.0) { return; }
+                                        break;
+                                    }
+                                } else if pairs_vector.1.iter().any(|(k, v)| *v == ethernet_packet.get_destination().to_string() && k == "dst")
+                                    && pairs_vector.1.iter().any(|(k, v)| v == " " && k == "src") {
+                                    if !report_event(&event_tx, pairs_vector.0) { return; }
+                                    break;
+                                }
+                            }
+                        }
                     }
+                },
+                Err(e) => {
+                    eprintln!("Error receiving packet: {}", e);
                 }
             }
-            
-            let rules_mutex = Arc::new(Mutex::new(OpenOptions::new()
-            .append(true)
-            .create(true)
-            .read(true)
-            .open(rules_file)
-            .unwrap()));
-                       
-            if let Some(_) =  get_rules_map(&rules_mutex).get(level.as_str()) {
-                // rules_vec = data_vec;
-            } else {
-                println!("Error with parcing rules. Check rules file.");
-                return;
-            }
+        }
+    });
 
-            let all_rules_map = get_rules_map(&rules_mutex);
-            let rules_vec = all_rules_map.get(level.as_str()).unwrap();
-
-            // Packet tracer channel
-            let (_tx, mut rx) = match datalink::channel(&interface, Default::default()) {
-                Ok(Ethernet(tx, rx)) => (tx, rx),
-                _ => { println!("Failed to create channel"); return; },
-            };
-
-            loop {
-                match rx.next() {
-                    Ok(packet) => {
-                        let ethernet_packet = EthernetPacket::new(packet).unwrap();
-
-                        if ethernet_packet.get_ethertype() == EtherTypes::Ipv4 {
-                            for rule in rules_vec {
-                                for pairs_vector in rule {
-
-                                    if let Some((_key, _value)) = pairs_vector.1.iter().find(|(k, v)| *v == ethernet_packet.get_source().to_string() && k == "src") {
-                                        if let Some((_key, _value)) = pairs_vector.1.iter().find(|(k, v)| *v == ethernet_packet.get_destination().to_string() && k == "dst") {
-                                            let timestamp: DateTime<Local> = SystemTime::now().into();
-                                            let cmd_string = "event".to_string() + "[:3:]" + &pairs_vector.0 + "[:3:]" + &timestamp.timestamp().to_string();
-                                            let cmd_string_byte_fmt = cmd_string.as_bytes();
-                                            match stream.write(cmd_string_byte_fmt) {
-                                                Ok(_) => {},
-                                                Err(_) => { println!("Troubles with connection. Stop working..."); return; }
-                                            }
-                                            println!("Catch event! Rule hash: {} | Time: {}", pairs_vector.0, timestamp.format("%d-%m-%Y %H:%M:%S"));
-                                            break;
-                                        } else if let Some((_key, _value)) = pairs_vector.1.iter().find(|(k, v)| *v == " ".to_string() && k == "dst") {
-                                        
-                                            let timestamp: DateTime<Local> = SystemTime::now().into();
-                                            let cmd_string = "event".to_string() + "[:3:]" + &pairs_vector.0 + "[:3:]" + &timestamp.timestamp().to_string();
-                                            let cmd_string_byte_fmt = cmd_string.as_bytes();
-                                            match stream.write(cmd_string_byte_fmt) {
-                                                Ok(_) => {},
-                                                Err(_) => { println!("Troubles with connection. Stop working..."); return; }
-                                            }
-                                            println!("Catch event! Rule hash: {} | Time: {}", pairs_vector.0, timestamp.format("%d-%m-%Y %H:%M:%S"));
-                                            break;
-                                        }
-                                    } else 
-                                    if let Some((_key, _value)) = pairs_vector.1.iter().find(|(k, v)| *v == ethernet_packet.get_destination().to_string() && k == "dst") {
-                                        if let Some((_key, _value)) = pairs_vector.1.iter().find(|(k, v)| *v == " ".to_string() && k == "src") {
-                                        
-                                            let timestamp: DateTime<Local> = SystemTime::now().into();
-                                            let cmd_string = "event".to_string() + "[:3:]" + &pairs_vector.0 + "[:3:]" + &timestamp.timestamp().to_string();
-                                            let cmd_string_byte_fmt = cmd_string.as_bytes();
-                                            match stream.write(cmd_string_byte_fmt) {
-                                                Ok(_) => {},
-                                                Err(_) => { println!("Troubles with connection. Stop working..."); return; }
-                                            }
-                                            println!("Catch event! Rule hash: {} | Time: {}", pairs_vector.0, timestamp.format("%d-%m-%Y %H:%M:%S"));
-                                            break;
-                                        }
-                                    } 
-                                }
-                            }
+    // heartbeat lets the server notice a hung or silently dead sensor
+    let started = Instant::now();
+    let mut events_detected: u64 = 0;
+    let mut events_sent: u64 = 0;
+    let mut spool = EventSpool::open(&spool_file, spool_limit);
+    let mut rule_receiver = RuleReceiver::load(&link.rules_file, &level);
+    // limit comes from server, there is none until the first connection
+    let mut throttle = EventThrottle::new(RateLimit::default());
+
+    loop {
+        let (mut server, last_event_seq, rate_limit) = match stream.take() {
+            Some(session) => session,
+            None => match reconnect(&link, &mut event_rx, &mut spool, Duration::from_secs(reconnect_max_delay), &mut events_detected, &mut throttle).await {
+                Ok(session) => session,
+                Err(e) => { println!("Can't connect to management server: {}. Stop working...", e); return; }
+            },
+        };
+        spool.sync_seq(last_event_seq);
+        if throttle.limit() != rate_limit {
+            throttle.set_limit(rate_limit);
+            println!("Event rate limit: {}", rate_limit);
+        }
+
+        // events stay in spool until server confirms they are written
+        let mut awaiting_ack: Option<Instant> = None;
+        match replay_spool(&mut server, &spool, batching).await {
+            Ok(0) => {},
+            Ok(_) => awaiting_ack = Some(Instant::now()),
+            Err(_) => { println!("Troubles with connection. Reconnecting..."); continue; }
+        }
+
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_interval.max(1)));
+        // events of the batch are in spool already, lost batch is sent again from spool
+        let mut batch: Vec<SensorMessage> = Vec::new();
+        let mut batch_deadline = Instant::now();
+
+        loop {
+            tokio::select! {
+                event = event_rx.recv() => match event {
+                    Some(evt @ SensorMessage::Event { .. }) => {
+                        events_detected += 1;
+                        let admitted = throttle.admit();
+                        check_throttle(&mut throttle);
+                        if !admitted { continue; }
+                        let evt = match spool.push(evt) { Some(evt) => evt, None => continue };
+                        if batch.is_empty() {
+                            batch_deadline = Instant::now() + batching.interval;
+                        }
+                        batch.push(evt);
+                        if batch.len() < batching.size { continue; }
+
+                        spool.sync();
+                        if send_events(&mut server, &batch, batching.compress).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
                         }
+                        batch.clear();
+                        awaiting_ack.get_or_insert_with(Instant::now);
                     },
-                    Err(e) => {
-                        eprintln!("Error receiving packet: {}", e);
+                    Some(evt) => {
+                        if send_message(&mut server, &evt).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                    },
+                    None => return,
+                },
+                _ = tokio::time::sleep_until(batch_deadline.into()), if !batch.is_empty() => {
+                    spool.sync();
+                    if send_events(&mut server, &batch, batching.compress).await.is_err() {
+                        println!("Troubles with connection. Reconnecting...");
+                        break;
+                    }
+                    batch.clear();
+                    awaiting_ack.get_or_insert_with(Instant::now);
+                },
+                _ = heartbeat.tick() => {
+                    check_throttle(&mut throttle);
+                    let beat = SensorMessage::Heartbeat { uptime_secs: started.elapsed().as_secs(), events_detected, events_sent, events_dropped: throttle.over_limit, capturing: capturing.load(Ordering::Relaxed), throttled: throttle.throttled };
+                    if send_message(&mut server, &beat).await.is_err() {
+                        println!("Troubles with connection. Reconnecting...");
+                        break;
+                    }
+
+                    // no acknowledgement for too long: server could fail to write events
+                    if awaiting_ack.is_some_and(|since| since.elapsed() >= Duration::from_secs(ack_timeout)) {
+                        if replay_spool(&mut server, &spool, batching).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                        batch.clear();
+                        awaiting_ack = Some(Instant::now());
                     }
+                },
+                message = recv_message::<_, ServerMessage>(&mut server) => match message {
+                    Ok(Some(ServerMessage::Rejected { reason })) => { println!("Connection closed by server: {}. Stop working...", reason); return; },
+                    Ok(Some(ServerMessage::Shutdown { reason })) => { println!("Management server is shutting down: {}. Events will be spooled until connection is restored", reason); break; },
+                    Ok(Some(ServerMessage::EventAck { seq })) => {
+                        events_sent += spool.ack(seq);
+                        awaiting_ack = if spool.is_empty() { None } else { Some(Instant::now()) };
+                    },
+                    Ok(Some(chunk @ ServerMessage::RulesChunk { .. })) => {
+                        // rules are swapped only when the whole bundle is received and checked
+                        let answer = match rule_receiver.receive(chunk) {
+                            Ok(Some(rules)) => {
+                                *active_rules.write().unwrap() = parse_rules(&rule_receiver.rules_text()).remove(level.as_str()).unwrap_or_default();
+                                println!("Rules updated to version {}", rules);
+                                SensorMessage::RulesApplied { rules }
+                            },
+                            Ok(None) => continue,
+                            Err(reason) => {
+                                println!("Rules bundle rejected: {}. Requesting full rule set", reason);
+                                SensorMessage::RulesRequest { rules: RulesState::default() }
+                            }
+                        };
+
+                        if send_message(&mut server, &answer).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                    },
+                    Ok(Some(ServerMessage::Command { command_id, command })) => {
+                        println!("Command from server: {}", command);
+
+                        let (success, output) = match command {
+                            SensorCommand::Start => { capturing.store(true, Ordering::Relaxed); (true, "detection started".to_string()) },
+                            SensorCommand::Stop => { capturing.store(false, Ordering::Relaxed); (true, "detection stopped".to_string()) },
+                            SensorCommand::RescanNow => (false, "not supported by network sensor".to_string()),
+                            SensorCommand::ReportStatus => (true, format!(
+                                "capturing: {}, rules version: {}, uptime: {}s, events detected: {}, sent: {}, dropped: {}, not acknowledged: {}",
+                                capturing.load(Ordering::Relaxed), rule_receiver.state, started.elapsed().as_secs(), events_detected, events_sent, throttle.over_limit, spool.len()
+                            )),
+                            SensorCommand::ReloadConfig => match read_config(CONFIG) {
+                                Ok(config) => {
+                                    for (key, value) in config {
+                                        match key.as_str() {
+                                            "heartbeat_interval" => heartbeat_interval = value.parse().unwrap_or(heartbeat_interval),
+                                            "spool_limit" => spool.limit = value.parse().unwrap_or(spool.limit),
+                                            "reconnect_max_delay" => reconnect_max_delay = value.parse().unwrap_or(reconnect_max_delay),
+                                            "ack_timeout" => ack_timeout = value.parse().unwrap_or(ack_timeout),
+                                            "batch_size" => batching.size = value.parse().unwrap_or(batching.size).clamp(1, MAX_BATCH_SIZE),
+                                            "batch_interval_ms" => batching.interval = value.parse().map(Duration::from_millis).unwrap_or(batching.interval),
+                                            "batch_compression" => batching.compress = value != "0",
+                                            _ => {},
+                                        }
+                                    }
+                                    heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_interval.max(1)));
+
+                                    rule_receiver = RuleReceiver::load(&link.rules_file, &level);
+                                    *active_rules.write().unwrap() = parse_rules(&rule_receiver.rules_text()).remove(level.as_str()).unwrap_or_default();
+
+                                    (true, format!(
+                                        "heartbeat_interval: {}, spool_limit: {}, reconnect_max_delay: {}, ack_timeout: {}, batch_size: {}, batch_interval_ms: {}, batch_compression: {}, rules version: {}. Other settings are applied after restart",
+                                        heartbeat_interval, spool.limit, reconnect_max_delay, ack_timeout, batching.size, batching.interval.as_millis(), batching.compress, rule_receiver.state
+                                    ))
+                                },
+                                Err(e) => (false, format!("can't read {}: {}", CONFIG, e)),
+                            },
+                        };
+
+                        let result = SensorMessage::CommandResult { command_id, success, output };
+                        if send_message(&mut server, &result).await.is_err() {
+                            println!("Troubles with connection. Reconnecting...");
+                            break;
+                        }
+                    },
+                    Ok(Some(_)) => {},
+                    Ok(None) => { println!("Server disconnected. Reconnecting..."); break; },
+                    Err(_) => { println!("Troubles with connection. Reconnecting..."); break; }
                 }
             }
         }
-        Err(e) => {
-            eprintln!("Failed to connect: {}", e);
-        }
     }
 }
+
+// false if the connection side is gone
+fn report_event(event_tx: &EventSender, rule_hash: &str) -> bool {
+    let timestamp: DateTime<Local> = SystemTime::now().into();
+    // sequence number is given by spool
+    let event = SensorMessage::Event {
+        seq: 0,
+        rule_hash: rule_hash.to_string(),
+        timestamp: timestamp.timestamp(),
+        path: None,
+    };
+
+    if !event_tx.send(event) {
+        return false;
+    }
+    println!("Catch event! Rule hash: {} | Time: {}", rule_hash, timestamp.format("%d-%m-%Y %H:%M:%S"));
+    true
+}
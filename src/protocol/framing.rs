@@ -0,0 +1,168 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::protocol::messages::{
+    RateLimit, RulesState, SensorAuth, SensorMessage, ServerMessage, PROTOCOL_VERSION,
+};
+
+// every frame is a 4-byte big-endian length followed by bincode payload
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+pub type MessageStream<S> = Framed<S, LengthDelimitedCodec>;
+
+pub fn message_stream<S: AsyncRead + AsyncWrite>(stream: S) -> MessageStream<S> {
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .new_codec();
+    Framed::new(stream, codec)
+}
+
+pub async fn send_message<S, M>(stream: &mut MessageStream<S>, message: &M) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+    M: Serialize,
+{
+    let payload =
+        bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    stream.send(Bytes::from(payload)).await
+}
+
+// Ok(None) - connection closed by the other side.
+// Cancel safe, so it can be used as a tokio::select! branch.
+pub async fn recv_message<S, M>(stream: &mut MessageStream<S>) -> io::Result<Option<M>>
+where
+    S: AsyncRead + Unpin,
+    M: DeserializeOwned,
+{
+    match stream.next().await {
+        Some(Ok(frame)) => bincode::deserialize(&frame)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Some(Err(e)) => Err(e),
+        None => Ok(None),
+    }
+}
+
+// Puts events in one EventBatch message, one syscall and one TLS record instead of many.
+pub fn pack_events(events: &[SensorMessage], compress: bool) -> io::Result<SensorMessage> {
+    let payload =
+        bincode::serialize(events).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let data = if compress {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&payload)?;
+        encoder.finish()?
+    } else {
+        payload
+    };
+
+    Ok(SensorMessage::EventBatch {
+        compressed: compress,
+        data,
+    })
+}
+
+// Server side of EventBatch. Batch can hold only Event messages,
+// unpacked batch is not bigger than a frame.
+pub fn unpack_events(compressed: bool, data: &[u8]) -> io::Result<Vec<SensorMessage>> {
+    let payload = if compressed {
+        let mut payload: Vec<u8> = Vec::new();
+        DeflateDecoder::new(data)
+            .take(MAX_FRAME_LENGTH as u64 + 1)
+            .read_to_end(&mut payload)?;
+        if payload.len() > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unpacked event batch is too big",
+            ));
+        }
+        payload
+    } else {
+        data.to_vec()
+    };
+
+    let events: Vec<SensorMessage> = bincode::deserialize(&payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if events
+        .iter()
+        .any(|event| !matches!(event, SensorMessage::Event { .. }))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "event batch holds not only events",
+        ));
+    }
+
+    Ok(events)
+}
+
+// Sensor side of the init step: introduce itself and wait for the server verdict.
+// Returns (sensor_id, secret) if the server has just enrolled the sensor,
+// sequence number of the last event the server has written and event rate limit.
+pub async fn sensor_handshake<S>(
+    stream: &mut MessageStream<S>,
+    name: &str,
+    level: &str,
+    username: &str,
+    password: &str,
+    auth: SensorAuth,
+    rules: RulesState,
+) -> io::Result<(Option<(String, String)>, u64, RateLimit)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = SensorMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        name: name.to_string(),
+        level: level.to_string(),
+        username: username.to_string(),
+        password: password.to_string(),
+        auth,
+        rules,
+    };
+    send_message(stream, &hello).await?;
+
+    let mut enrolled: Option<(String, String)> = None;
+    let mut answer = recv_message::<_, ServerMessage>(stream).await?;
+    if let Some(ServerMessage::Enrolled { sensor_id, secret }) = answer {
+        enrolled = Some((sensor_id, secret));
+        answer = recv_message::<_, ServerMessage>(stream).await?;
+    }
+
+    match answer {
+        Some(ServerMessage::Welcome {
+            protocol_version,
+            last_event_seq,
+            rate_limit,
+        }) if protocol_version == PROTOCOL_VERSION => Ok((enrolled, last_event_seq, rate_limit)),
+        Some(ServerMessage::Welcome {
+            protocol_version, ..
+        }) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "protocol version mismatch: server {}, sensor {}",
+                protocol_version, PROTOCOL_VERSION
+            ),
+        )),
+        Some(ServerMessage::Rejected { reason }) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("connection rejected by server: {}", reason),
+        )),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected answer on init step",
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server closed connection on init step",
+        )),
+    }
+}
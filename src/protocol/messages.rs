@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// bump on any incompatible change of the message enums below
+pub const PROTOCOL_VERSION: u16 = 11;
+
+// how the sensor proves it is allowed to connect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SensorAuth {
+    // first connection: one-time token created by admin in console
+    Enrollment { token: String },
+    // every next connection: credential issued on enrollment
+    Credential { sensor_id: String, secret: String },
+}
+
+// sensor -> management server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SensorMessage {
+    // must be the first message of every connection
+    Hello {
+        protocol_version: u16,
+        name: String,
+        level: String,
+        // operator of the sensor, checked against management server users
+        username: String,
+        password: String,
+        auth: SensorAuth,
+        // rules the sensor runs now
+        rules: RulesState,
+    },
+    // server answers with bundle: differential if it knows the sensor state, full otherwise.
+    // RulesState::default() asks for full bundle.
+    RulesRequest {
+        rules: RulesState,
+    },
+    // confirmation that pushed rules are checked and in use
+    RulesApplied {
+        rules: RulesState,
+    },
+    // seq - sensor-generated sequence number, the server answers with EventAck.
+    // timestamp - UNIX-time, path - only for host-level sensors
+    Event {
+        seq: u64,
+        rule_hash: String,
+        timestamp: i64,
+        path: Option<String>,
+    },
+    // several Event messages in one, see framing::pack_events.
+    // data - bincode of the events, deflate-compressed if compressed is set
+    EventBatch {
+        compressed: bool,
+        data: Vec<u8>,
+    },
+    // sent every heartbeat interval, counters since sensor start.
+    // events_dropped - events over rate limit, throttled - the rate is over limit now
+    Heartbeat {
+        uptime_secs: u64,
+        events_detected: u64,
+        events_sent: u64,
+        events_dropped: u64,
+        capturing: bool,
+        throttled: bool,
+    },
+    // acknowledgement of ServerMessage::Command
+    CommandResult {
+        command_id: u64,
+        success: bool,
+        output: String,
+    },
+}
+
+// management server -> sensor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Welcome {
+        protocol_version: u16,
+        // last event of this sensor written to event log, 0 if none
+        last_event_seq: u64,
+        // the sensor drops events over this rate itself, the server slows it down anyway
+        rate_limit: RateLimit,
+    },
+    // answer to enrollment, sent right before Welcome
+    Enrolled {
+        sensor_id: String,
+        secret: String,
+    },
+    Rejected {
+        reason: String,
+    },
+    // server is going down, the connection is closed right after it.
+    // Sensor keeps working offline and connects again later
+    Shutdown {
+        reason: String,
+    },
+    // Part of rule bundle for sensor level. Bundle is lines "+rule" and "-rule":
+    // base_version 0 - full rule set, otherwise changes since base_version.
+    // checksum - SHA-256 of the rule set after the bundle is applied.
+    RulesChunk {
+        version: u64,
+        base_version: u64,
+        checksum: String,
+        index: u32,
+        total: u32,
+        data: String,
+    },
+    // event with this sequence number is written to event log (or it is a duplicate)
+    EventAck {
+        seq: u64,
+    },
+    // command_id is returned in CommandResult
+    Command {
+        command_id: u64,
+        command: SensorCommand,
+    },
+}
+
+// console client -> management server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConsoleRequest {
+    // must be the first message. The rest of logon (second factor, new password) is asked with prompts
+    Hello {
+        protocol_version: u16,
+        username: String,
+        password: String,
+    },
+    // answer to ConsoleOutput::Prompt
+    Input {
+        line: String,
+    },
+}
+
+// management server -> console client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConsoleOutput {
+    Text {
+        text: String,
+    },
+    // server waits for one line, secret one must not be shown
+    Prompt {
+        text: String,
+        secret: bool,
+    },
+    // session is over, the connection is closed right after it
+    Closed {
+        reason: String,
+    },
+}
+
+// version - number of rule set change on server (0 - unknown), checksum - SHA-256 of rule set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RulesState {
+    pub version: u64,
+    pub checksum: String,
+}
+
+impl fmt::Display for RulesState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:.8})", self.version, self.checksum)
+    }
+}
+
+// events per second and burst size, events_per_sec 0 - no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub events_per_sec: u32,
+    pub burst: u32,
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.events_per_sec {
+            0 => write!(f, "no limit"),
+            rate => write!(f, "{} per second, burst {}", rate, self.burst),
+        }
+    }
+}
+
+// remote control of sensor from management server console
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SensorCommand {
+    Start,
+    Stop,
+    RescanNow,
+    ReportStatus,
+    ReloadConfig,
+}
+
+impl fmt::Display for SensorCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -1,2 +1,9 @@
+pub mod rate_limiter;
+pub mod rule_bundle;
 pub mod rule_handler;
+// crate::sensor_handler::sensor_handler::... paths are used across the crate and binaries
+#[allow(clippy::module_inception)]
 pub mod sensor_handler;
+pub mod sensor_link;
+pub mod sensor_listener;
+pub mod sensor_registry;
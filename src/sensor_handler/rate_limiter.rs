@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::messages::RateLimit;
+
+pub enum ThrottleChange {
+    Started,
+    // over_limit - events over limit while throttled
+    Stopped { over_limit: u64 },
+}
+
+// Token bucket for events of one sensor. Bucket holds up to burst tokens
+// and is refilled with events_per_sec tokens every second.
+pub struct EventThrottle {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    pub throttled: bool,
+    // events over limit since start: dropped by sensor, delayed by server
+    pub over_limit: u64,
+    // over_limit when throttling started last time
+    over_limit_before: u64,
+}
+
+impl EventThrottle {
+    pub fn new(limit: RateLimit) -> EventThrottle {
+        EventThrottle {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            refilled: Instant::now(),
+            throttled: false,
+            over_limit: 0,
+            over_limit_before: 0,
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    // counters and throttling state are kept
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        self.refill();
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst.max(1) as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        self.tokens = (self.tokens + elapsed * self.limit.events_per_sec as f64)
+            .min(self.limit.burst.max(1) as f64);
+    }
+
+    // Sensor side: false - event is over limit and should be dropped.
+    pub fn admit(&mut self) -> bool {
+        if self.limit.events_per_sec == 0 {
+            return true;
+        }
+
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.over_limit += 1;
+            false
+        }
+    }
+
+    // Server side: event is taken anyway, so nothing is lost.
+    // Returns how long to wait before reading the next message from the sensor.
+    pub fn pace(&mut self) -> Duration {
+        if self.limit.events_per_sec == 0 {
+            return Duration::ZERO;
+        }
+
+        self.refill();
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            self.over_limit += 1;
+            Duration::from_secs_f64(-self.tokens / self.limit.events_per_sec as f64)
+        }
+    }
+
+    // Throttling starts with the first event over limit and stops
+    // when the rate is under limit long enough to fill the bucket again.
+    pub fn state_change(&mut self) -> Option<ThrottleChange> {
+        self.refill();
+
+        if !self.throttled && self.over_limit > self.over_limit_before {
+            self.throttled = true;
+            return Some(ThrottleChange::Started);
+        }
+        if self.throttled && self.tokens >= self.limit.burst.max(1) as f64 {
+            self.throttled = false;
+            let over_limit = self.over_limit - self.over_limit_before;
+            self.over_limit_before = self.over_limit;
+            return Some(ThrottleChange::Stopped { over_limit });
+        }
+
+        None
+    }
+}
+
+// "name=rate/burst, name=rate/burst" -> limits of particular sensors
+pub fn parse_sensor_limits(value: &str) -> HashMap<String, RateLimit> {
+    let mut limits: HashMap<String, RateLimit> = HashMap::new();
+
+    for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let parsed = item.split_once('=').and_then(|(name, limit)| {
+            let (rate, burst) = limit.split_once('/')?;
+            Some((
+                name.trim().to_string(),
+                RateLimit {
+                    events_per_sec: rate.trim().parse().ok()?,
+                    burst: burst.trim().parse().ok()?,
+                },
+            ))
+        });
+
+        match parsed {
+            Some((name, limit)) => {
+                limits.insert(name, limit);
+            }
+            None => println!("Wrong sensor rate limit format: '{}'. Expected name=rate/burst", item),
+        }
+    }
+
+    limits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(events_per_sec: u32, burst: u32) -> RateLimit {
+        RateLimit {
+            events_per_sec,
+            burst,
+        }
+    }
+
+    // bucket as if the time has passed since the last refill
+    fn wait(throttle: &mut EventThrottle, secs: u64) {
+        throttle.refilled = throttle
+            .refilled
+            .checked_sub(Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut throttle = EventThrottle::new(limit(0, 0));
+        assert!((0..1000).all(|_| throttle.admit()));
+        assert_eq!(throttle.pace(), Duration::ZERO);
+        assert_eq!(throttle.over_limit, 0);
+    }
+
+    #[test]
+    fn burst_is_admitted_then_events_are_dropped() {
+        let mut throttle = EventThrottle::new(limit(1, 3));
+        assert_eq!((0..5).filter(|_| throttle.admit()).count(), 3);
+        assert_eq!(throttle.over_limit, 2);
+
+        // one token per second, bucket never holds more than burst
+        wait(&mut throttle, 2);
+        assert_eq!((0..5).filter(|_| throttle.admit()).count(), 2);
+        wait(&mut throttle, 100);
+        assert_eq!((0..5).filter(|_| throttle.admit()).count(), 3);
+    }
+
+    #[test]
+    fn server_pace_delays_events_over_limit() {
+        let mut throttle = EventThrottle::new(limit(2, 2));
+        assert_eq!(throttle.pace(), Duration::ZERO);
+        assert_eq!(throttle.pace(), Duration::ZERO);
+
+        let delay = throttle.pace();
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+        assert_eq!(throttle.over_limit, 1);
+    }
+
+    #[test]
+    fn throttling_starts_and_stops_once() {
+        let mut throttle = EventThrottle::new(limit(1, 2));
+        assert!(throttle.state_change().is_none());
+
+        for _ in 0..4 {
+            throttle.admit();
+        }
+        assert!(matches!(throttle.state_change(), Some(ThrottleChange::Started)));
+        assert!(throttle.state_change().is_none());
+
+        wait(&mut throttle, 2);
+        assert!(matches!(
+            throttle.state_change(),
+            Some(ThrottleChange::Stopped { over_limit: 2 })
+        ));
+        assert!(throttle.state_change().is_none());
+        assert!(!throttle.throttled);
+    }
+
+    #[test]
+    fn smaller_limit_cuts_tokens() {
+        let mut throttle = EventThrottle::new(limit(10, 10));
+        throttle.set_limit(limit(1, 1));
+        assert!(throttle.admit());
+        assert!(!throttle.admit());
+    }
+
+    #[test]
+    fn sensor_limits_are_parsed() {
+        let limits = parse_sensor_limits(" web=100/200, db = 5/10,broken=5, =");
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits["web"], limit(100, 200));
+        assert_eq!(limits["db"], limit(5, 10));
+    }
+}
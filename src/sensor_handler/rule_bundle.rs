@@ -0,0 +1,697 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::messages::{RulesState, ServerMessage};
+use crate::sensor_handler::sensor_registry::oldest_rules_version;
+use crate::structs::soc_structs::multithread::FileMutexes;
+
+// bundle is split in chunks, so rule set of any size fits in protocol frames
+pub const RULES_CHUNK_SIZE: usize = 64 * 1024;
+
+// first line of sensor rules file: version of rule set received from server
+const VERSION_HEADER: &str = "#version[:1:]";
+
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+// Rules of the level as a set of lines, order of lines in file doesn't matter.
+// Lines are not trimmed: empty src/dst of net rules is stored as " " at the end of line.
+pub fn level_rules(content: &str, level: &str) -> BTreeSet<String> {
+    content
+        .lines()
+        .filter(|line| rule_level(line) == Some(level))
+        .map(String::from)
+        .collect()
+}
+
+// level[:1:]net[:2:]hash[:1:]... -> net
+fn rule_level(line: &str) -> Option<&str> {
+    line.strip_prefix("level[:1:]")?.split("[:2:]").next()
+}
+
+pub fn rules_checksum(rules: &BTreeSet<String>) -> String {
+    let mut hasher = Sha256::new();
+    for rule in rules {
+        hasher.update(rule.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// history line: version[:|:]level[:|:]+ or -[:|:]rule
+#[derive(Clone)]
+struct HistoryRecord {
+    version: u64,
+    level: String,
+    added: bool,
+    rule: String,
+}
+
+fn read_history(history_file: &str) -> Vec<HistoryRecord> {
+    let buf = match fs::read_to_string(history_file) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            println!("Error occured while reading rules history file: {}", e);
+            String::new()
+        }
+    };
+
+    buf.lines()
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.splitn(4, "[:|:]").collect();
+            if parts.len() != 4 {
+                println!("Wrong rules history string format: '{}'", line);
+                return None;
+            }
+
+            Some(HistoryRecord {
+                version: parts[0].parse().ok()?,
+                level: parts[1].to_string(),
+                added: parts[2] == "+",
+                rule: parts[3].to_string(),
+            })
+        })
+        .collect()
+}
+
+// rule set of the level after all changes up to version
+fn rules_at(history: &[HistoryRecord], level: &str, version: u64) -> BTreeSet<String> {
+    let mut rules: BTreeSet<String> = BTreeSet::new();
+    for record in history
+        .iter()
+        .filter(|r| r.level == level && r.version <= version)
+    {
+        if record.added {
+            rules.insert(record.rule.clone());
+        } else {
+            rules.remove(&record.rule);
+        }
+    }
+    rules
+}
+
+fn level_version(history: &[HistoryRecord], level: &str) -> u64 {
+    history
+        .iter()
+        .filter(|r| r.level == level)
+        .map(|r| r.version)
+        .max()
+        .unwrap_or(0)
+}
+
+// Compares rules file with history and records the difference as a new version.
+// Rules file can be changed from console or by hand, both ways end up in history.
+fn sync_history(rule_file: &str, history_file: &str) -> Vec<HistoryRecord> {
+    let mut history = read_history(history_file);
+    let content = fs::read_to_string(rule_file).unwrap_or_default();
+    let last_version = history.iter().map(|r| r.version).max().unwrap_or(0);
+
+    let mut levels: BTreeSet<String> = history.iter().map(|r| r.level.clone()).collect();
+    levels.extend(content.lines().filter_map(rule_level).map(String::from));
+
+    let mut changes: Vec<HistoryRecord> = Vec::new();
+    for level in levels {
+        let recorded = rules_at(&history, &level, last_version);
+        let current = level_rules(&content, &level);
+
+        for rule in recorded.difference(&current) {
+            changes.push(HistoryRecord {
+                version: last_version + 1,
+                level: level.clone(),
+                added: false,
+                rule: rule.clone(),
+            });
+        }
+        for rule in current.difference(&recorded) {
+            changes.push(HistoryRecord {
+                version: last_version + 1,
+                level: level.clone(),
+                added: true,
+                rule: rule.clone(),
+            });
+        }
+    }
+
+    if !changes.is_empty() {
+        let lines = history_lines(&changes);
+        if OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(history_file)
+            .and_then(|mut file| file.write_all(lines.as_bytes()).and_then(|_| file.sync_data()))
+            .is_err()
+        {
+            println!("Error while writing to rules history file");
+        }
+        history.extend(changes);
+    }
+
+    history
+}
+
+fn history_lines(history: &[HistoryRecord]) -> String {
+    history
+        .iter()
+        .map(|r| {
+            format!(
+                "{}[:|:]{}[:|:]{}[:|:]{}\n",
+                r.version,
+                r.level,
+                if r.added { "+" } else { "-" },
+                r.rule
+            )
+        })
+        .collect()
+}
+
+// Changes up to keep_version are replaced by rule set of every level at its last version
+// before it. Removals of that version stay, so versions of levels don't go back.
+// rules_at is exact from keep_version on, older versions fail the checksum and get full bundles
+fn compact_history(history: &[HistoryRecord], keep_version: u64) -> Vec<HistoryRecord> {
+    let levels: BTreeSet<String> = history.iter().map(|r| r.level.clone()).collect();
+    let mut compacted: Vec<HistoryRecord> = Vec::new();
+
+    for level in levels {
+        let base_version = history
+            .iter()
+            .filter(|r| r.level == level && r.version <= keep_version)
+            .map(|r| r.version)
+            .max();
+        let Some(base_version) = base_version else {
+            continue;
+        };
+
+        for rule in rules_at(history, &level, base_version) {
+            compacted.push(HistoryRecord {
+                version: base_version,
+                level: level.clone(),
+                added: true,
+                rule,
+            });
+        }
+        compacted.extend(
+            history
+                .iter()
+                .filter(|r| r.level == level && r.version == base_version && !r.added)
+                .cloned(),
+        );
+    }
+
+    compacted.extend(history.iter().filter(|r| r.version > keep_version).cloned());
+    compacted
+}
+
+// Drops history no registered sensor can ask a differential bundle from.
+// Called when a sensor applies rules, the oldest version held can only grow then
+pub fn prune_rules_history(file_mutexes: &FileMutexes) {
+    let oldest = oldest_rules_version(file_mutexes);
+    let history_file = file_mutexes.rules_history_mutex.lock().unwrap();
+    let history = read_history(&history_file);
+    let last_version = history.iter().map(|r| r.version).max().unwrap_or(0);
+    let records_count = history.len();
+
+    let compacted = compact_history(&history, oldest.unwrap_or(last_version).min(last_version));
+    if compacted.len() < records_count {
+        if let Err(e) = replace_file(&history_file, &history_lines(&compacted)) {
+            println!("Error while writing to rules history file: {}", e);
+        }
+    }
+}
+
+// Server side: chunks to bring sensor from its rule set to the current one.
+// Differential if the sensor runs a version the history knows, full otherwise.
+// Empty if the sensor is up to date.
+pub fn make_rule_bundle(
+    level: &str,
+    sensor_rules: &RulesState,
+    rule_file: &str,
+    file_mutexes: &FileMutexes,
+) -> Vec<ServerMessage> {
+    // rules file is read under its lock, a rule being added or deleted is never half seen
+    let history = {
+        let _rules_file = file_mutexes.rules_mutex.lock().unwrap();
+        let history_file = file_mutexes.rules_history_mutex.lock().unwrap();
+        sync_history(rule_file, &history_file)
+    };
+    history_bundle(level, sensor_rules, &history)
+}
+
+fn history_bundle(
+    level: &str,
+    sensor_rules: &RulesState,
+    history: &[HistoryRecord],
+) -> Vec<ServerMessage> {
+    let version = level_version(history, level);
+    let current = rules_at(history, level, version);
+    let checksum = rules_checksum(&current);
+
+    if sensor_rules.version == version && sensor_rules.checksum == checksum {
+        return Vec::new();
+    }
+
+    let mut base_version = 0;
+    let mut lines: Vec<String> = Vec::new();
+    if sensor_rules.version > 0 && sensor_rules.version < version {
+        let base = rules_at(history, level, sensor_rules.version);
+        if rules_checksum(&base) == sensor_rules.checksum {
+            base_version = sensor_rules.version;
+            lines.extend(base.difference(&current).map(|rule| format!("-{}", rule)));
+            lines.extend(current.difference(&base).map(|rule| format!("+{}", rule)));
+        }
+    }
+    if base_version == 0 {
+        lines.extend(current.iter().map(|rule| format!("+{}", rule)));
+    }
+
+    let chunks = split_chunks(lines);
+    let total = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| ServerMessage::RulesChunk {
+            version,
+            base_version,
+            checksum: checksum.clone(),
+            index: index as u32,
+            total,
+            data,
+        })
+        .collect()
+}
+
+// lines are not split between chunks, a chunk is bigger than RULES_CHUNK_SIZE only for a longer line
+fn split_chunks(lines: Vec<String>) -> Vec<String> {
+    let mut chunks: Vec<String> = vec![String::new()];
+    for line in lines {
+        let last = chunks.last_mut().unwrap();
+        if !last.is_empty() && last.len() + line.len() + 1 > RULES_CHUNK_SIZE {
+            chunks.push(String::new());
+        }
+        let last = chunks.last_mut().unwrap();
+        last.push_str(&line);
+        last.push('\n');
+    }
+    chunks
+}
+
+// Sensor side: collects chunks of rule bundle, checks it and replaces rules file
+pub struct RuleReceiver {
+    rules_file: String,
+    level: String,
+    pub state: RulesState,
+    rules: BTreeSet<String>,
+    // (version, base_version, checksum, total) of bundle being received
+    bundle: Option<(u64, u64, String, u32)>,
+    chunks: Vec<String>,
+}
+
+impl RuleReceiver {
+    pub fn load(rules_file: &str, level: &str) -> RuleReceiver {
+        let content = fs::read_to_string(rules_file).unwrap_or_default();
+        let version = content
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix(VERSION_HEADER))
+            .and_then(|version| version.trim().parse().ok())
+            .unwrap_or(0);
+        let rules = level_rules(&content, level);
+
+        RuleReceiver {
+            rules_file: rules_file.to_string(),
+            level: level.to_string(),
+            state: RulesState {
+                version,
+                checksum: rules_checksum(&rules),
+            },
+            rules,
+            bundle: None,
+            chunks: Vec::new(),
+        }
+    }
+
+    // rules in rules file format
+    pub fn rules_text(&self) -> String {
+        self.rules
+            .iter()
+            .map(|rule| format!("{}\n", rule))
+            .collect()
+    }
+
+    // Ok(Some) - bundle is applied, Ok(None) - more chunks expected.
+    // Err - bundle is rejected, rules are not changed and full bundle should be requested.
+    pub fn receive(&mut self, chunk: ServerMessage) -> Result<Option<RulesState>, String> {
+        let (version, base_version, checksum, index, total, data) = match chunk {
+            ServerMessage::RulesChunk {
+                version,
+                base_version,
+                checksum,
+                index,
+                total,
+                data,
+            } => (version, base_version, checksum, index, total, data),
+            _ => return Err("not a rules chunk".to_string()),
+        };
+
+        if index == 0 {
+            self.bundle = Some((version, base_version, checksum.clone(), total));
+            self.chunks.clear();
+        }
+        if self.bundle != Some((version, base_version, checksum, total))
+            || index as usize != self.chunks.len()
+        {
+            self.bundle = None;
+            self.chunks.clear();
+            return Err(format!(
+                "rules chunk {} of {} is out of order",
+                index, total
+            ));
+        }
+
+        self.chunks.push(data);
+        if self.chunks.len() < total as usize {
+            return Ok(None);
+        }
+
+        let (version, base_version, checksum, _) = self.bundle.take().unwrap();
+        let data: String = self.chunks.drain(..).collect();
+        let mut rules = match base_version {
+            0 => BTreeSet::new(),
+            base if base == self.state.version => self.rules.clone(),
+            base => {
+                return Err(format!(
+                    "changes since version {} can't be applied to version {}",
+                    base, self.state.version
+                ))
+            }
+        };
+
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            match (line.strip_prefix('+'), line.strip_prefix('-')) {
+                (Some(rule), _) => rules.insert(rule.to_string()),
+                (_, Some(rule)) => rules.remove(rule),
+                _ => return Err(format!("wrong bundle line: '{}'", line)),
+            };
+        }
+        if rules
+            .iter()
+            .any(|rule| rule_level(rule) != Some(self.level.as_str()))
+        {
+            return Err(format!("bundle has rules not of {} level", self.level));
+        }
+        if rules_checksum(&rules) != checksum {
+            return Err(format!("checksum mismatch in rules version {}", version));
+        }
+
+        self.rules = rules;
+        self.state = RulesState { version, checksum };
+
+        let content = format!("{}{}\n{}", VERSION_HEADER, version, self.rules_text());
+        if let Err(e) = replace_file(&self.rules_file, &content) {
+            println!(
+                "Rules version {} are not saved and will be lost on restart: {}",
+                version, e
+            );
+        }
+
+        Ok(Some(self.state.clone()))
+    }
+}
+
+// new content is written next to the file and renamed over it, so the file is never half-written
+fn replace_file(path: &str, content: &str) -> io::Result<()> {
+    // every write has its own temporary file, two writers never mix their content
+    let temp_path = format!(
+        "{}.{}-{}.tmp",
+        path,
+        process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(content.as_bytes())?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor_handler::sensor_registry::{
+        create_enrollment_token, enroll_sensor, record_rules_version,
+    };
+
+    static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+    fn temp_path(name: &str) -> String {
+        let number = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "rsoc_{}_test_{}_{}.txt",
+            name,
+            std::process::id(),
+            number
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn rule(hash: &str) -> String {
+        format!("level[:1:]host[:2:]hash[:1:]{}[:2:]path[:1:]/tmp", hash)
+    }
+
+    fn rule_set(hashes: &[&str]) -> BTreeSet<String> {
+        hashes.iter().map(|hash| rule(hash)).collect()
+    }
+
+    // rules file goes through versions, one per content
+    fn history_of(versions: &[&[&str]]) -> Vec<HistoryRecord> {
+        let rule_file = temp_path("rules");
+        let history_path = temp_path("rules_history");
+
+        let mut history = Vec::new();
+        for hashes in versions {
+            let content: String = hashes.iter().map(|hash| format!("{}\n", rule(hash))).collect();
+            fs::write(&rule_file, content).unwrap();
+            history = sync_history(&rule_file, &history_path);
+        }
+
+        let _ = fs::remove_file(&rule_file);
+        let _ = fs::remove_file(&history_path);
+        history
+    }
+
+    fn bundle_lines(bundle: &[ServerMessage]) -> (u64, Vec<String>) {
+        let mut base = 0;
+        let mut lines = Vec::new();
+        for chunk in bundle {
+            if let ServerMessage::RulesChunk {
+                base_version, data, ..
+            } = chunk
+            {
+                base = *base_version;
+                lines.extend(data.lines().map(String::from));
+            }
+        }
+        (base, lines)
+    }
+
+    #[test]
+    fn checksum_does_not_depend_on_rule_order() {
+        let mut reversed = BTreeSet::new();
+        reversed.insert(rule("b"));
+        reversed.insert(rule("a"));
+
+        assert_eq!(rules_checksum(&rule_set(&["a", "b"])), rules_checksum(&reversed));
+        assert_ne!(rules_checksum(&rule_set(&["a", "b"])), rules_checksum(&rule_set(&["a"])));
+        assert_eq!(
+            rules_checksum(&BTreeSet::new()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn chunks_keep_lines_whole() {
+        let line = "x".repeat(1000);
+        let lines: Vec<String> = (0..200).map(|_| line.clone()).collect();
+        let chunks = split_chunks(lines);
+
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= RULES_CHUNK_SIZE));
+        let joined: String = chunks.concat();
+        assert_eq!(joined.lines().count(), 200);
+        assert!(joined.lines().all(|l| l == line));
+
+        // empty rule set is still one chunk
+        assert_eq!(split_chunks(Vec::new()), vec![String::new()]);
+    }
+
+    #[test]
+    fn history_records_changes_as_versions() {
+        let history = history_of(&[&["a", "b"], &["a", "b"], &["b", "c"]]);
+
+        // unchanged file adds no version
+        assert_eq!(level_version(&history, "host"), 2);
+        assert_eq!(rules_at(&history, "host", 1), rule_set(&["a", "b"]));
+        assert_eq!(rules_at(&history, "host", 2), rule_set(&["b", "c"]));
+        assert_eq!(level_version(&history, "net"), 0);
+    }
+
+    #[test]
+    fn bundle_is_differential_for_known_version() {
+        let history = history_of(&[&["a", "b"], &["b", "c"]]);
+        let sensor = RulesState {
+            version: 1,
+            checksum: rules_checksum(&rule_set(&["a", "b"])),
+        };
+
+        let (base, lines) = bundle_lines(&history_bundle("host", &sensor, &history));
+        assert_eq!(base, 1);
+        assert_eq!(lines, vec![format!("-{}", rule("a")), format!("+{}", rule("c"))]);
+    }
+
+    #[test]
+    fn bundle_is_full_for_unknown_rules() {
+        let history = history_of(&[&["a", "b"], &["b", "c"]]);
+        // sensor rules were changed by hand, checksum doesn't match version 1
+        let sensor = RulesState {
+            version: 1,
+            checksum: rules_checksum(&rule_set(&["a"])),
+        };
+
+        let (base, lines) = bundle_lines(&history_bundle("host", &sensor, &history));
+        assert_eq!(base, 0);
+        assert_eq!(lines, vec![format!("+{}", rule("b")), format!("+{}", rule("c"))]);
+    }
+
+    #[test]
+    fn bundle_is_empty_for_current_rules() {
+        let history = history_of(&[&["a", "b"], &["b", "c"]]);
+        let sensor = RulesState {
+            version: 2,
+            checksum: rules_checksum(&rule_set(&["b", "c"])),
+        };
+
+        assert!(history_bundle("host", &sensor, &history).is_empty());
+    }
+
+    #[test]
+    fn receiver_applies_full_and_differential_bundles() {
+        let history = history_of(&[&["a", "b"], &["b", "c"]]);
+        let sensor_file = temp_path("sensor_rules");
+        let mut receiver = RuleReceiver::load(&sensor_file, "host");
+
+        let old = RulesState {
+            version: 1,
+            checksum: rules_checksum(&rule_set(&["a", "b"])),
+        };
+        let full = rules_at(&history, "host", 1);
+        let full_lines = full.iter().map(|rule| format!("+{}", rule)).collect();
+        for data in split_chunks(full_lines) {
+            let applied = receiver.receive(ServerMessage::RulesChunk {
+                version: 1,
+                base_version: 0,
+                checksum: old.checksum.clone(),
+                index: 0,
+                total: 1,
+                data,
+            });
+            assert_eq!(applied, Ok(Some(old.clone())));
+        }
+
+        let mut result = Ok(None);
+        for chunk in history_bundle("host", &old, &history) {
+            result = receiver.receive(chunk);
+        }
+        assert_eq!(result.unwrap().map(|state| state.version), Some(2));
+        assert_eq!(RuleReceiver::load(&sensor_file, "host").state, receiver.state);
+
+        let _ = fs::remove_file(&sensor_file);
+    }
+
+    #[test]
+    fn receiver_rejects_wrong_checksum() {
+        let sensor_file = temp_path("sensor_rules");
+        let mut receiver = RuleReceiver::load(&sensor_file, "host");
+
+        let result = receiver.receive(ServerMessage::RulesChunk {
+            version: 1,
+            base_version: 0,
+            checksum: rules_checksum(&rule_set(&["b"])),
+            index: 0,
+            total: 1,
+            data: format!("+{}\n", rule("a")),
+        });
+        assert!(result.is_err());
+        assert_eq!(receiver.state.version, 0);
+        assert!(!std::path::Path::new(&sensor_file).exists());
+    }
+
+    #[test]
+    fn compacted_history_keeps_versions_from_keep_version() {
+        let history = history_of(&[&["a", "b"], &["b", "c"], &["c"], &["c", "d"]]);
+        let compacted = compact_history(&history, 3);
+
+        assert!(compacted.len() < history.len());
+        assert_eq!(level_version(&compacted, "host"), 4);
+        for version in 3..=4 {
+            assert_eq!(rules_at(&compacted, "host", version), rules_at(&history, "host", version));
+        }
+
+        // sensor on version before compaction gets full bundle
+        let sensor = RulesState {
+            version: 2,
+            checksum: rules_checksum(&rule_set(&["b", "c"])),
+        };
+        assert_eq!(bundle_lines(&history_bundle("host", &sensor, &compacted)).0, 0);
+        let sensor = RulesState {
+            version: 3,
+            checksum: rules_checksum(&rule_set(&["c"])),
+        };
+        assert_eq!(bundle_lines(&history_bundle("host", &sensor, &compacted)).0, 3);
+    }
+
+    #[test]
+    fn compacted_history_keeps_version_of_emptied_level() {
+        let compacted = compact_history(&history_of(&[&["a"], &[]]), 2);
+
+        assert_eq!(level_version(&compacted, "host"), 2);
+        assert!(rules_at(&compacted, "host", 2).is_empty());
+    }
+
+    #[test]
+    fn history_is_pruned_to_oldest_sensor_version() {
+        let file_mutexes = FileMutexes::for_tests("rules_prune");
+        let history_file = file_mutexes.rules_history_mutex.lock().unwrap().clone();
+        let rule_file = temp_path("rules_prune");
+        for hashes in [&["a", "b"][..], &["b", "c"], &["c", "d"]] {
+            let content: String = hashes.iter().map(|hash| format!("{}\n", rule(hash))).collect();
+            fs::write(&rule_file, content).unwrap();
+            sync_history(&rule_file, &history_file);
+        }
+
+        let mut sensor_ids = Vec::new();
+        for name in ["Zarya-1", "Zarya-2"] {
+            let token = create_enrollment_token(name, 1, "admin", &file_mutexes).unwrap();
+            sensor_ids.push(enroll_sensor(&token, name, "host", &file_mutexes).unwrap().0);
+        }
+        assert!(record_rules_version(&sensor_ids[0], 2, &file_mutexes));
+        assert!(record_rules_version(&sensor_ids[1], 3, &file_mutexes));
+
+        prune_rules_history(&file_mutexes);
+        let history = read_history(&history_file);
+        assert!(history.iter().all(|r| r.version >= 2));
+        assert_eq!(rules_at(&history, "host", 2), rule_set(&["b", "c"]));
+
+        assert!(record_rules_version(&sensor_ids[0], 3, &file_mutexes));
+        prune_rules_history(&file_mutexes);
+        let history = read_history(&history_file);
+        assert!(history.iter().all(|r| r.version == 3));
+        assert_eq!(rules_at(&history, "host", 3), rule_set(&["c", "d"]));
+
+        let _ = fs::remove_file(&rule_file);
+        let _ = fs::remove_file(&history_file);
+    }
+}
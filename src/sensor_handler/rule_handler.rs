@@ -7,6 +7,7 @@ use std::io::{Read, Write};
 use std::mem;
 use std::sync::{Arc, Mutex};
 
+use crate::console_println;
 use crate::structs::soc_structs::multithread::FileMutexes;
 
 pub fn get_rules_list(rule_type: &str, file_mutexes: &FileMutexes) {
@@ -14,12 +15,12 @@ pub fn get_rules_list(rule_type: &str, file_mutexes: &FileMutexes) {
         for level_rule in rules_vec_by_level {
             let rule_hash_vec: Vec<String> = level_rule.clone().into_keys().collect();
             let rule_hash: &String = rule_hash_vec.first().unwrap();
-            println!("------------------------------------------------------------------------------------------\n\
+            console_println!("------------------------------------------------------------------------------------------\n\
                       Rule level: {}\n\
                       Rule hash: {}\n", rule_type, rule_hash);
 
             for param in level_rule.get(rule_hash).unwrap() {
-                println!("{}: {}", param.0, param.1);
+                console_println!("{}: {}", param.0, param.1);
             }
         }
     }
@@ -31,7 +32,7 @@ pub fn add_rule(
     rule_payload: String,
     rule_description: String,
     rule_fields: &HashMap<String, String>,
-    rules_file: &String,
+    rules_file: &str,
     file_mutexes: &FileMutexes,
 ) -> bool {
     let mut locked_rules_file = file_mutexes.rules_mutex.lock().unwrap();
@@ -41,13 +42,13 @@ pub fn add_rule(
     hasher.update(hashing_str);
     let raw_hash = hasher.finalize();
     let hash = format!("{:.5}", format!("{:x}", raw_hash));
-    let mut param_vec: Vec<String> = Vec::new();
-
-    param_vec.push("level".to_string() + "[:1:]" + &rule_level);
-    param_vec.push("hash".to_string() + "[:1:]" + &hash);
-    param_vec.push("name".to_string() + "[:1:]" + &rule_name);
-    param_vec.push("payload".to_string() + "[:1:]" + &rule_payload);
-    param_vec.push("description".to_string() + "[:1:]" + &rule_description);
+    let mut param_vec: Vec<String> = vec![
+        "level".to_string() + "[:1:]" + &rule_level,
+        "hash".to_string() + "[:1:]" + &hash,
+        "name".to_string() + "[:1:]" + &rule_name,
+        "payload".to_string() + "[:1:]" + &rule_payload,
+        "description".to_string() + "[:1:]" + &rule_description,
+    ];
 
     for opt_pair in rule_fields {
         if opt_pair.0.is_empty() {
@@ -76,7 +77,7 @@ pub fn add_rule(
                 .append(true)
                 .create(true)
                 .read(true)
-                .open(&rules_file)
+                .open(rules_file)
                 .unwrap(),
         );
     }
@@ -85,11 +86,11 @@ pub fn add_rule(
 }
 
 pub fn delete_rule(
-    rule_level: &String,
-    rule_hash: &String,
-    rules_file: &String,
+    rule_level: &str,
+    rule_hash: &str,
+    rules_file: &str,
     file_mutexes: &FileMutexes,
-) {
+) -> bool {
     let pattern_str = format!(
         r"level\[:1:\]{}\[:2:\]hash\[:1:\]{}\[:2:\]",
         rule_level, rule_hash
@@ -101,39 +102,39 @@ pub fn delete_rule(
 
     match locked_file.read_to_string(buf) {
         Ok(_) => {
-            if let Some(_) = pattern.find(&buf) {
+            if pattern.find(buf).is_some() {
                 lines = buf
                     .lines()
                     .filter(|line| !pattern.is_match(line))
                     .map(String::from)
                     .collect();
             } else {
-                println!("There is no rule with these level and hash parameters.");
-                return;
+                console_println!("There is no rule with these level and hash parameters.");
+                return false;
             }
         }
         Err(_e) => {
-            println!("Error while parcing rules file.");
-            return;
+            console_println!("Error while parcing rules file.");
+            return false;
         }
     }
 
-    if lines.len() > 0 {
-        let _ = mem::replace(
-            &mut *locked_file,
-            OpenOptions::new()
-                .truncate(true)
-                .write(true)
-                .read(true)
-                .open(&rules_file)
-                .unwrap(),
-        );
-    }
+    let _ = mem::replace(
+        &mut *locked_file,
+        OpenOptions::new()
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(rules_file)
+            .unwrap(),
+    );
 
-    let result = match writeln!(locked_file, "{}", lines.join("\n")) {
-        Ok(_) => true,
-        Err(_e) => false,
-    };
+    // the last rule is deleted by truncation alone, no empty line is left behind
+    let result = lines.is_empty()
+        || match writeln!(locked_file, "{}", lines.join("\n")) {
+            Ok(_) => true,
+            Err(_e) => false,
+        };
 
     if result {
         let _ = mem::replace(
@@ -142,65 +143,130 @@ pub fn delete_rule(
                 .append(true)
                 .create(true)
                 .read(true)
-                .open(&rules_file)
+                .open(rules_file)
                 .unwrap(),
         );
     }
 
-    println!("Rule deleted successfully.");
+    console_println!("Rule deleted successfully.");
+    true
 }
 
-pub fn get_rules_map(
-    rules_mutex: &Arc<Mutex<std::fs::File>>,
-) -> HashMap<String, Vec<HashMap<String, Vec<(String, String)>>>> {
+// rules of one level: rule hash -> rule parameters
+pub type LevelRules = Vec<HashMap<String, Vec<(String, String)>>>;
+
+pub fn get_rules_map(rules_mutex: &Arc<Mutex<std::fs::File>>) -> HashMap<String, LevelRules> {
     let mut file = rules_mutex.lock().unwrap();
     let buf: &mut String = &mut "".to_owned();
-    let mut result: HashMap<String, Vec<HashMap<String, Vec<(String, String)>>>> = HashMap::new();
+    let mut result: HashMap<String, LevelRules> = HashMap::new();
 
     match (file).read_to_string(buf) {
-        Ok(_) => {
-            let strings = buf.split("\n");
-            result.insert("net".to_string(), Vec::new());
-            result.insert("host".to_string(), Vec::new());
-
-            for string in strings {
-                if string == "" {
-                    continue;
-                }
-
-                let mut temp_hashmap: HashMap<String, String> = HashMap::new();
-                // level[:1:]net[:2:]hash[:1:]252fe[:2:]name[:1:]beb[:2:]description[:1:]ra[:2:]other_parameters...
-                let high_level_parsed_rule: Vec<&str> = string.split("[:2:]").collect();
-
-                for parameter in high_level_parsed_rule {
-                    let param_pair: Vec<&str> = parameter.split("[:1:]").collect();
-                    temp_hashmap.insert(param_pair[0].to_string(), param_pair[1].to_string());
-                }
-
-                let level = temp_hashmap["level"].to_string();
-                let hash = temp_hashmap["hash"].to_string();
-                let mut temp_vec: Vec<(String, String)> = Vec::new();
-
-                for param in temp_hashmap {
-                    if param.0 == "level" || param.0 == "hash" {
-                        continue;
-                    }
-                    temp_vec.push((param.0, param.1));
-                }
-
-                if let Some(result_empty_rules_vector) = result.get_mut(&level.to_string()) {
-                    let m: HashMap<String, Vec<(String, String)>> =
-                        vec![(hash, temp_vec)].into_iter().collect();
-                    result_empty_rules_vector.push(m);
-                }
+        Ok(_) => result = parse_rules(buf),
+        Err(e) => console_println!("Error occured while reading from audit file: {}", e),
+    }
+
+    let _ = file.rewind();
+    result
+}
+
+pub fn parse_rules(buf: &str) -> HashMap<String, LevelRules> {
+    let mut result: HashMap<String, LevelRules> = HashMap::new();
+    let strings = buf.split("\n");
+    result.insert("net".to_string(), Vec::new());
+    result.insert("host".to_string(), Vec::new());
+
+    for string in strings {
+        // sensor rules file starts with version header
+        if string.is_empty() || string.starts_with('#') {
+            continue;
+        }
+
+        let mut temp_hashmap: HashMap<String, String> = HashMap::new();
+        // level[:1:]net[:2:]hash[:1:]252fe[:2:]name[:1:]beb[:2:]description[:1:]ra[:2:]other_parameters...
+        let high_level_parsed_rule: Vec<&str> = string.split("[:2:]").collect();
+
+        for parameter in high_level_parsed_rule {
+            let param_pair: Vec<&str> = parameter.split("[:1:]").collect();
+            temp_hashmap.insert(param_pair[0].to_string(), param_pair[1].to_string());
+        }
+
+        let level = temp_hashmap["level"].to_string();
+        let hash = temp_hashmap["hash"].to_string();
+        let mut temp_vec: Vec<(String, String)> = Vec::new();
+
+        for param in temp_hashmap {
+            if param.0 == "level" || param.0 == "hash" {
+                continue;
             }
+            temp_vec.push((param.0, param.1));
+        }
 
-            let _ = file.rewind();
-            return result;
+        if let Some(result_empty_rules_vector) = result.get_mut(&level.to_string()) {
+            let m: HashMap<String, Vec<(String, String)>> =
+                vec![(hash, temp_vec)].into_iter().collect();
+            result_empty_rules_vector.push(m);
         }
-        Err(e) => println!("Error occured while reading from audit file: {}", e),
     }
 
-    let _ = file.rewind();
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn rules_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("rsoc_{}_rules_{}.txt", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn add_host_rule(name: &str, rules_file: &str, file_mutexes: &FileMutexes) -> String {
+        assert!(add_rule(
+            "host".to_string(),
+            name.to_string(),
+            "/tmp".to_string(),
+            "test rule".to_string(),
+            &HashMap::new(),
+            rules_file,
+            file_mutexes,
+        ));
+        let name_param = ("name".to_string(), name.to_string());
+        get_rules_map(&file_mutexes.rules_mutex)["host"]
+            .iter()
+            .flat_map(|rule| rule.iter())
+            .find(|(_, params)| params.contains(&name_param))
+            .map(|(hash, _)| hash.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn delete_keeps_other_rules() {
+        let file_mutexes = FileMutexes::for_tests("delete_rule_other");
+        let rules_file = rules_path("delete_rule_other");
+        let first = add_host_rule("first", &rules_file, &file_mutexes);
+        let second = add_host_rule("second", &rules_file, &file_mutexes);
+
+        assert!(delete_rule("host", &first, &rules_file, &file_mutexes));
+
+        let rules = get_rules_map(&file_mutexes.rules_mutex);
+        assert_eq!(rules["host"].len(), 1);
+        assert!(rules["host"][0].contains_key(&second));
+        let _ = fs::remove_file(&rules_file);
+    }
+
+    #[test]
+    fn delete_of_only_rule_empties_file() {
+        let file_mutexes = FileMutexes::for_tests("delete_rule_only");
+        let rules_file = rules_path("delete_rule_only");
+        let hash = add_host_rule("only", &rules_file, &file_mutexes);
+
+        assert!(delete_rule("host", &hash, &rules_file, &file_mutexes));
+
+        assert_eq!(fs::read_to_string(&rules_file).unwrap(), "");
+        assert!(get_rules_map(&file_mutexes.rules_mutex)["host"].is_empty());
+        let _ = fs::remove_file(&rules_file);
+    }
+}
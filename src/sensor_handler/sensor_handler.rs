@@ -1,194 +1,802 @@
+use crate::console_println;
+use crate::event_bus::event_bus::{publish, ServerEvent};
 use crate::file_manager::file_manager::audit_handler::write_audit_event;
+use crate::protocol::framing::{message_stream, recv_message, send_message, unpack_events};
+use crate::protocol::messages::{
+    RulesState, SensorAuth, SensorCommand, SensorMessage, ServerMessage, PROTOCOL_VERSION,
+};
+use crate::sensor_handler::rate_limiter::{EventThrottle, ThrottleChange};
+use crate::sensor_handler::rule_bundle::{make_rule_bundle, prune_rules_history};
+use crate::sensor_handler::sensor_listener::ListenerContext;
+use crate::sensor_handler::sensor_registry::{
+    enroll_sensor, record_rules_version, record_sensor_connect, record_sensors_seen, revoke_sensor,
+    set_desired_capture, verify_sensor,
+};
 use crate::structs::soc_structs::multithread::FileMutexes;
-use crate::structs::soc_structs::{AuditEventType, SessionStatus};
-use regex::Regex;
+use crate::auth::auth::authenticate_operator;
+use crate::auth::lockout::lock_message;
+use crate::structs::soc_structs::{
+    AuditEventType, OperatorAuth, PendingCommand, SensorInfo, SensorMap, SessionStatus,
+};
+use chrono::offset::Local;
+use chrono::DateTime;
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{self, BufRead};
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::time::{sleep_until, Instant};
+
+static NEXT_COMMAND_ID: AtomicU64 = AtomicU64::new(1);
 
 pub fn get_sensor_list(session_status: &mut SessionStatus) {
     let sensors_map = session_status.sensor_list.lock().unwrap();
-    println!("----------------------------------------------------------------------------------------------\n\
-             || ----- IP address ----- || ----- Hostname ----- || ----- Level ----- || ----- Status ----- ||\n\
-             -----------------------------------------------------------------------------------------------");
+    console_println!("---------------------------------------------------------------------------------------------------------------------------------------------------------------------------------\n\
+             || ----- Sensor ID ----- || ----- IP address ----- || ----- Hostname ----- || ----- Level ----- || ----- Status ----- || ----- Last seen ----- || ----- Health ----- || ----- Rules ----- || ----- Uptime / events (detected, sent, dropped) ----- ||\n\
+             ---------------------------------------------------------------------------------------------------------------------------------------------------------------------------------");
 
-    for (ip, info) in sensors_map.iter() {
-        let status = if info.3 == true {
+    for (sensor_id, info) in sensors_map.iter() {
+        let status = if info.capturing {
             "capturing"
         } else {
             "stopped"
         };
-        let output_string =
-            "|| ".to_string() + ip + " || " + &info.1 + " || " + &info.2 + " || " + status + " ||";
-        println!("{}", output_string);
+        let health = if info.silent {
+            "silent"
+        } else if info.throttled || info.self_throttled {
+            "throttled"
+        } else {
+            "ok"
+        };
+        let last_seen: DateTime<Local> = info.last_seen.into();
+        let output_string = format!(
+            "|| {} || {} || {} || {} || {} || {} || {} || {} || {}s / {}, {}, {} ||",
+            sensor_id,
+            info.addr,
+            info.name,
+            info.level,
+            status,
+            last_seen.format("%d-%m-%Y %H:%M:%S"),
+            health,
+            info.rules,
+            info.uptime_secs,
+            info.events_detected,
+            info.events_sent,
+            info.events_dropped
+        );
+        console_println!("{}", output_string);
+    }
+
+    console_println!("---------------------------------------------------------------------------------------------------------------------------------------------------------------------------------");
+}
+
+// target - sensor name, ID or current address
+fn find_sensor_id(sensors_map: &HashMap<String, SensorInfo>, target: &str) -> Option<String> {
+    sensors_map
+        .iter()
+        .find(|(sensor_id, info)| {
+            *sensor_id == target || info.name == target || info.addr == target
+        })
+        .map(|(sensor_id, _)| sensor_id.clone())
+}
+
+// Sends rule changes to every connected sensor of this level.
+// A bundle goes to a sensor whole or not at all: when its queue has no room
+// for every chunk, nothing is sent and the sensor keeps its rules until it reconnects.
+// Returns number of sensors the rules were sent to and names of sensors they were not.
+pub fn push_rules(
+    level: &str,
+    rule_file: &str,
+    file_mutexes: &FileMutexes,
+    sensors_map: &SensorMap,
+) -> (usize, Vec<String>) {
+    let mut count = 0;
+    let mut failed = Vec::new();
+
+    // bundles read rule files, so they are built after the sensors lock is released
+    let targets: Vec<(String, RulesState, mpsc::Sender<ServerMessage>)> = sensors_map
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|info| info.level == level)
+        .map(|info| (info.name.clone(), info.rules.clone(), info.tx.clone()))
+        .collect();
+
+    for (name, rules, tx) in targets {
+        // bundle depends on rules the sensor runs now
+        let bundle = make_rule_bundle(level, &rules, rule_file, file_mutexes);
+        if bundle.is_empty() {
+            continue;
+        }
+        match tx.try_reserve_many(bundle.len()) {
+            Ok(permits) => {
+                for (permit, chunk) in permits.zip(bundle) {
+                    permit.send(chunk);
+                }
+                count += 1;
+            }
+            Err(_) => failed.push(name),
+        }
+    }
+
+    (count, failed)
+}
+
+// Tells every connected sensor the server is going down, they close connections
+// and spool events until the server is back. Returns number of notified sensors.
+pub fn notify_shutdown(sensors_map: &SensorMap, reason: &str) -> usize {
+    sensors_map
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|info| {
+            info.tx
+                .try_send(ServerMessage::Shutdown {
+                    reason: reason.to_string(),
+                })
+                .is_ok()
+        })
+        .count()
+}
+
+// Marks sensors with overdue heartbeat as silent.
+// Returns (name, user) of sensors that have just gone silent.
+pub fn find_silent_sensors(sensors_map: &SensorMap, timeout: Duration) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = Vec::new();
+    let now = SystemTime::now();
+
+    for info in sensors_map.lock().unwrap().values_mut() {
+        let overdue = match now.duration_since(info.last_seen) {
+            Ok(elapsed) => elapsed > timeout,
+            Err(_) => false,
+        };
+
+        if overdue && !info.silent {
+            info.silent = true;
+            result.push((info.name.clone(), info.user.clone()));
+        }
+    }
+
+    result
+}
+
+// Saves last seen time of connected sensors in registry.
+pub fn save_sensors_seen(sensors_map: &SensorMap, file_mutexes: &FileMutexes) -> bool {
+    let seen: Vec<(String, i64)> = sensors_map
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(sensor_id, info)| {
+            let last_seen: DateTime<Local> = info.last_seen.into();
+            (sensor_id.clone(), last_seen.timestamp())
+        })
+        .collect();
+
+    seen.is_empty() || record_sensors_seen(&seen, file_mutexes)
+}
+
+// Tells the sensor its event is written. Lost ack only makes the sensor send the event again.
+pub fn ack_event(sensors_map: &SensorMap, sensor_id: &str, seq: u64) {
+    if let Some(info) = sensors_map.lock().unwrap().get(sensor_id) {
+        let _ = info.tx.try_send(ServerMessage::EventAck { seq });
     }
+}
 
-    println!("---------------------------------------------------------------------------------------------");
+// Updates last seen time of sensor. Returns true if the sensor was silent before.
+fn touch_sensor(sensors_map: &SensorMap, sensor_id: &str) -> bool {
+    match sensors_map.lock().unwrap().get_mut(sensor_id) {
+        Some(info) => {
+            info.last_seen = SystemTime::now();
+            let was_silent = info.silent;
+            info.silent = false;
+            was_silent
+        }
+        None => false,
+    }
 }
 
+// Asks sensor to stop detection if it is capturing and to start otherwise.
+// Returns (start requested, audit record written, sensor found).
+// The state is kept in registry and restored on every connection of the sensor.
 pub fn change_sensor_state(
-    sensor_ip: &String,
+    target: &str,
     session_status: &mut SessionStatus,
     file_mutexes: &FileMutexes,
-    log_file: &String,
+    log_file: &str,
     audit_status: bool,
 ) -> (bool, bool, bool) {
+    let (sensor_id, command) = {
+        let sensors_map = session_status.sensor_list.lock().unwrap();
+        let sensor_id = match find_sensor_id(&sensors_map, target) {
+            Some(sensor_id) => sensor_id,
+            None => return (false, false, false),
+        };
+        let command = if sensors_map[&sensor_id].capturing {
+            SensorCommand::Stop
+        } else {
+            SensorCommand::Start
+        };
+        (sensor_id, command)
+    };
+
+    let (sent, audited) = send_sensor_command(
+        &sensor_id,
+        command,
+        session_status,
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+
+    if sent {
+        set_desired_capture(&sensor_id, command == SensorCommand::Start, file_mutexes);
+    }
+
+    (command == SensorCommand::Start, audited, sent)
+}
+
+// Sends command to connected sensor. The result comes later in CommandResult
+// and is written to audit log by the main loop.
+// Returns (command sent, audit record written).
+pub fn send_sensor_command(
+    target: &str,
+    command: SensorCommand,
+    session_status: &mut SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> (bool, bool) {
     let mut sensors_map = session_status.sensor_list.lock().unwrap();
+    let info = match find_sensor_id(&sensors_map, target) {
+        Some(sensor_id) => sensors_map.get_mut(&sensor_id).unwrap(),
+        None => return (false, false),
+    };
 
-    for (ip, info) in sensors_map.iter_mut() {
-        if sensor_ip == ip {
-            info.3 = !info.3;
-
-            if info.3 {
-                return (
-                    true,
-                    write_audit_event(
-                        SystemTime::now(),
-                        (*info.1).to_string(),
-                        (*session_status.user).to_string(),
-                        AuditEventType::SenEnable,
-                        "Event logging enabled".to_string(),
-                        file_mutexes,
-                        log_file,
-                        audit_status,
-                    ),
-                    true,
-                );
-            } else {
-                return (
-                    false,
-                    write_audit_event(
-                        SystemTime::now(),
-                        (*info.1).to_string(),
-                        (*session_status.user).to_string(),
-                        AuditEventType::SenDisable,
-                        "Event logging disabled".to_string(),
-                        file_mutexes,
-                        log_file,
-                        audit_status,
-                    ),
-                    true,
-                );
-            }
+    let command_id = NEXT_COMMAND_ID.fetch_add(1, Ordering::Relaxed);
+    if info
+        .tx
+        .try_send(ServerMessage::Command {
+            command_id,
+            command,
+        })
+        .is_err()
+    {
+        return (false, false);
+    }
+    info.pending_commands.insert(
+        command_id,
+        PendingCommand {
+            command,
+            operator: session_status.user.clone(),
+            session_id: Some(session_status.session_id),
+        },
+    );
+
+    let audited = write_audit_event(
+        info.name.clone(),
+        session_status.user.clone(),
+        AuditEventType::SenCommand,
+        format!("Command {} sent. Command ID - {}", command, command_id),
+        file_mutexes,
+        log_file,
+        audit_status,
+    );
+
+    (true, audited)
+}
+
+// Revokes sensor in registry and drops its connection if it is online.
+// Returns (sensor found, audit record written).
+pub fn revoke_registered_sensor(
+    target: &str,
+    session_status: &mut SessionStatus,
+    file_mutexes: &FileMutexes,
+    log_file: &str,
+    audit_status: bool,
+) -> (bool, bool) {
+    let sensor_name = match revoke_sensor(target, file_mutexes) {
+        Some(name) => name,
+        None => return (false, false),
+    };
+
+    for info in session_status.sensor_list.lock().unwrap().values() {
+        if info.name == sensor_name {
+            let _ = info.tx.try_send(ServerMessage::Rejected {
+                reason: "sensor revoked".to_string(),
+            });
         }
     }
 
-    (false, false, false)
+    (
+        true,
+        write_audit_event(
+            sensor_name,
+            (*session_status.user).to_string(),
+            AuditEventType::SenRevoke,
+            "Sensor revoked".to_string(),
+            file_mutexes,
+            log_file,
+            audit_status,
+        ),
+    )
 }
 
-fn get_rules_string_by_level(level: String, rule_file: &String) -> String {
-    let mut tx_string = String::new();
-    let opened_rules_file = fs::File::open(rule_file).unwrap();
-    let reader = io::BufReader::new(opened_rules_file);
-    let pattern = Regex::new(format!(r"level\[:1:\]{}\[:2:\]", level).as_str()).unwrap();
+struct AcceptedSensor {
+    id: String,
+    name: String,
+    level: String,
+    user: String,
+    rules: RulesState,
+    // (sensor_id, secret) if the sensor has just been enrolled
+    enrolled: Option<(String, String)>,
+}
 
-    for line in reader.lines() {
-        if let Ok(l) = line {
-            if pattern.is_match(&l) {
-                tx_string.push_str(&l);
-                tx_string.push('\n');
-            }
+enum InitRejection {
+    // (claimed name, claimed user, reject reason)
+    Sensor(String, String, String),
+    // lock - audit message if the operator account has just been locked
+    Operator {
+        name: String,
+        user: String,
+        reason: String,
+        lock: Option<String>,
+    },
+}
+
+fn check_init_message(
+    message: SensorMessage,
+    cert_name: &str,
+    cert_subject: &str,
+    operator_auth: &OperatorAuth,
+    file_mutexes: &FileMutexes,
+) -> Result<AcceptedSensor, InitRejection> {
+    let (protocol_version, name, level, username, password, auth, rules) = match message {
+        SensorMessage::Hello {
+            protocol_version,
+            name,
+            level,
+            username,
+            password,
+            auth,
+            rules,
+        } => (protocol_version, name, level, username, password, auth, rules),
+        _ => {
+            return Err(InitRejection::Sensor(
+                cert_name.to_string(),
+                " ".to_string(),
+                "init message expected".to_string(),
+            ))
         }
+    };
+
+    if protocol_version != PROTOCOL_VERSION {
+        let reason = format!(
+            "unsupported protocol version {} (server speaks {})",
+            protocol_version, PROTOCOL_VERSION
+        );
+        return Err(InitRejection::Sensor(name, username, reason));
     }
+    if name != cert_name {
+        let reason = format!(
+            "sensor name {} doesn't match certificate ({})",
+            name, cert_subject
+        );
+        return Err(InitRejection::Sensor(name, username, reason));
+    }
+    if level != "net" && level != "host" {
+        let reason = format!("unknown sensor level {}", level);
+        return Err(InitRejection::Sensor(name, username, reason));
+    }
+
+    // before sensor credential: enrollment token is not spent by unknown operator
+    if let Err((reason, locked_until)) = authenticate_operator(&username, &password, operator_auth, file_mutexes) {
+        let lock = locked_until
+            .map(|locked_until| lock_message(&operator_auth.policy.lockout, locked_until, &username));
+        return Err(InitRejection::Operator {
+            name,
+            user: username,
+            reason,
+            lock,
+        });
+    }
+
+    let (id, enrolled) = match auth {
+        SensorAuth::Enrollment { token } => {
+            match enroll_sensor(&token, &name, &level, file_mutexes) {
+                Ok(credential) => (credential.0.clone(), Some(credential)),
+                Err(reason) => return Err(InitRejection::Sensor(name, username, reason)),
+            }
+        }
+        SensorAuth::Credential { sensor_id, secret } => {
+            if let Err(reason) = verify_sensor(&sensor_id, &secret, &name, &level, file_mutexes) {
+                return Err(InitRejection::Sensor(name, username, reason));
+            }
+            (sensor_id, None)
+        }
+    };
 
-    tx_string
+    Ok(AcceptedSensor {
+        id,
+        name,
+        level,
+        user: username,
+        rules,
+        enrolled,
+    })
 }
 
-pub async fn handle_client<'a>(
-    mut stream: TcpStream,
+// Removes the sensor from the list when its connection ends, whatever way it ends.
+// SensorDisconnected on the bus is only a notification, the bus can lose it.
+struct ConnectedSensor {
+    sensors: SensorMap,
+    sensor_id: String,
+    addr: String,
+    file_mutexes: FileMutexes,
+}
+
+impl Drop for ConnectedSensor {
+    fn drop(&mut self) {
+        if let Ok(mut sensors_map) = self.sensors.lock() {
+            // entry can already belong to the new connection of the same sensor
+            if sensors_map
+                .get(&self.sensor_id)
+                .is_some_and(|info| info.addr == self.addr)
+            {
+                sensors_map.remove(&self.sensor_id);
+            }
+        }
+        record_sensors_seen(
+            &[(self.sensor_id.clone(), Local::now().timestamp())],
+            &self.file_mutexes,
+        );
+    }
+}
+
+// cert_name - common name from client certificate, cert_subject - full certificate subject
+pub async fn handle_client<S>(
+    stream: S,
     addr_str: String,
-    mut client_rx: mpsc::Receiver<String>,
-    rule_file: &String,
-    sensors_mutex_clone: Arc<Mutex<HashMap<String, (mpsc::Sender<String>, String, String, bool)>>>,
-    client_tx: mpsc::Sender<String>,
-    server_tx: mpsc::Sender<String>,
-) -> io::Result<()> {
-    let mut init_buffer = [0; 1024];
-
-    // Init string from client
-    let n = stream.read(&mut init_buffer).await?;
-    if n == 0 {
-        return Ok(());
-    }
-
-    let raw_init_string = String::from_utf8_lossy(&init_buffer[..n]);
-    // init_vec[0] - sensor_name, 1 - sensor_level, 2 - sensor user
-    let init_vec: Vec<&str> = raw_init_string.split("[:1:]").collect();
-    sensors_mutex_clone.lock().unwrap().insert(
-        addr_str.clone(),
-        (
-            client_tx,
-            init_vec[0].to_string(),
-            init_vec[1].to_string(),
-            true,
-        ),
+    (cert_name, cert_subject): (String, String),
+    (client_tx, mut client_rx): (mpsc::Sender<ServerMessage>, mpsc::Receiver<ServerMessage>),
+    context: &ListenerContext,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (rule_file, rate_limits, operator_auth) =
+        (&context.rules_file, &context.rate_limits, &context.operator_auth);
+    let sensors_mutex_clone = Arc::clone(&context.sensors);
+    let (bus, file_mutexes) = (context.bus.clone(), context.file_mutexes.clone());
+    let mut messages = message_stream(stream);
+
+    // Init message from client
+    let hello = match recv_message::<_, SensorMessage>(&mut messages).await? {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+
+    // password hash of the operator is slow on purpose, so the check runs off the runtime threads
+    let checked = {
+        let (cert_name, cert_subject) = (cert_name.clone(), cert_subject.clone());
+        let (operator_auth, file_mutexes) = (operator_auth.clone(), file_mutexes.clone());
+        tokio::task::spawn_blocking(move || {
+            check_init_message(hello, &cert_name, &cert_subject, &operator_auth, &file_mutexes)
+        })
+        .await
+        .map_err(io::Error::other)?
+    };
+    let accepted = match checked {
+        Ok(accepted) => accepted,
+        Err(InitRejection::Sensor(claimed_name, claimed_user, reason)) => {
+            publish(
+                &bus,
+                ServerEvent::SensorRejected {
+                    addr: addr_str,
+                    name: claimed_name,
+                    user: claimed_user,
+                    reason: reason.clone(),
+                },
+            );
+            send_message(&mut messages, &ServerMessage::Rejected { reason }).await?;
+            return Ok(());
+        }
+        Err(InitRejection::Operator {
+            name,
+            user,
+            reason,
+            lock,
+        }) => {
+            publish(
+                &bus,
+                ServerEvent::OperatorRejected {
+                    addr: addr_str,
+                    name: name.clone(),
+                    user: user.clone(),
+                    reason,
+                },
+            );
+            if let Some(text) = lock {
+                publish(&bus, ServerEvent::OperatorLocked { name, user, text });
+            }
+            // sensor is not told which check failed
+            let reason = "operator is not authenticated".to_string();
+            send_message(&mut messages, &ServerMessage::Rejected { reason }).await?;
+            return Ok(());
+        }
+    };
+    let (sensor_id, sensor_name, sensor_level, sensor_user) =
+        (accepted.id, accepted.name, accepted.level, accepted.user);
+
+    if let Some((enrolled_id, secret)) = accepted.enrolled {
+        send_message(
+            &mut messages,
+            &ServerMessage::Enrolled {
+                sensor_id: enrolled_id,
+                secret,
+            },
+        )
+        .await?;
+        publish(
+            &bus,
+            ServerEvent::SensorEnrolled {
+                sensor_id: sensor_id.clone(),
+                name: sensor_name.clone(),
+                user: sensor_user.clone(),
+            },
+        );
+    }
+
+    // sensor stopped by operator stays stopped after reconnect or server restart
+    let (capture, last_event_seq) =
+        record_sensor_connect(&sensor_id, &sensor_user, &addr_str, &file_mutexes);
+    let rate_limit = rate_limits.for_sensor(&sensor_name);
+
+    send_message(
+        &mut messages,
+        &ServerMessage::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            last_event_seq,
+            rate_limit,
+        },
+    )
+    .await?;
+
+    // rules history is kept from the oldest version a sensor holds
+    if accepted.rules.version > 0 {
+        record_rules_version(&sensor_id, accepted.rules.version, &file_mutexes);
+    }
+
+    // sensor that missed rule changes while offline gets them right away
+    for chunk in make_rule_bundle(&sensor_level, &accepted.rules, rule_file, &file_mutexes) {
+        send_message(&mut messages, &chunk).await?;
+    }
+
+    let mut pending_commands: HashMap<u64, PendingCommand> = HashMap::new();
+    if !capture {
+        let command_id = NEXT_COMMAND_ID.fetch_add(1, Ordering::Relaxed);
+        if client_tx
+            .try_send(ServerMessage::Command {
+                command_id,
+                command: SensorCommand::Stop,
+            })
+            .is_ok()
+        {
+            pending_commands.insert(
+                command_id,
+                PendingCommand {
+                    command: SensorCommand::Stop,
+                    operator: "system".to_string(),
+                    session_id: None,
+                },
+            );
+        }
+    }
+
+    // previous connection of the same sensor is closed when its channel is dropped
+    let previous = sensors_mutex_clone.lock().unwrap().insert(
+        sensor_id.clone(),
+        SensorInfo {
+            tx: client_tx,
+            addr: addr_str.clone(),
+            name: sensor_name.clone(),
+            level: sensor_level.clone(),
+            user: sensor_user.clone(),
+            capturing: true,
+            rules: accepted.rules.clone(),
+            last_seen: SystemTime::now(),
+            silent: false,
+            uptime_secs: 0,
+            events_detected: 0,
+            events_sent: 0,
+            events_dropped: 0,
+            throttled: false,
+            self_throttled: false,
+            pending_commands,
+        },
     );
+    if let Some(previous) = previous {
+        console_println!(
+            "Sensor {} connected again from {}, connection from {} is closed",
+            sensor_name, addr_str, previous.addr
+        );
+    }
+    let _connected = ConnectedSensor {
+        sensors: sensors_mutex_clone.clone(),
+        sensor_id: sensor_id.clone(),
+        addr: addr_str.clone(),
+        file_mutexes: file_mutexes.clone(),
+    };
 
-    println!(
-        "Client connected! IP: {}, Name: {}, Level: {}, User: {}",
-        addr_str, init_vec[0], init_vec[1], init_vec[2]
+    publish(
+        &bus,
+        ServerEvent::SensorConnected {
+            addr: addr_str.clone(),
+            name: sensor_name.clone(),
+            level: sensor_level.clone(),
+            user: sensor_user.clone(),
+            cert_subject,
+        },
     );
-    server_tx
-        .send("init[:1:]".to_string() + &raw_init_string)
-        .await
-        .unwrap();
 
-    let mut buffer = [0; 1024];
+    let disconnect_event = ServerEvent::SensorDisconnected {
+        sensor_id: sensor_id.clone(),
+        addr: addr_str.clone(),
+        name: sensor_name.clone(),
+        level: sensor_level.clone(),
+        user: sensor_user.clone(),
+    };
+
+    let recover_event = ServerEvent::SensorRecovered {
+        addr: addr_str.clone(),
+        name: sensor_name.clone(),
+        user: sensor_user.clone(),
+    };
+
+    // sensor over its rate limit is read slower, so it can't hold up other sensors
+    let mut throttle = EventThrottle::new(rate_limit);
+    let mut paused_until: Option<Instant> = None;
+    // events dropped by sensor before it started throttling itself last time
+    let mut dropped_before: u64 = 0;
+
     loop {
         tokio::select! {
+            _ = sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                paused_until = None;
+            },
             // data stream from sensor
-            result = stream.read(&mut buffer) => match result {
-                Ok(n) if n == 0 => {
-                    server_tx.send("cl_disc[:1:]".to_string() + &addr_str + "[:1:]" + &init_vec[0] + "[:1:]" + &init_vec[1] + "[:1:]" + &init_vec[2]).await.unwrap();
-                    break;
-                },
-                Ok(n) => {
-                    // getting some data from client to server
-                    let raw_string = String::from_utf8_lossy(&buffer[..n]);
-                    // cmd_vec[0] - command
-                    let cmd_vec: Vec<&str> = raw_string.split("[:3:]").collect();
-
-                    match cmd_vec[0] {
-                        // has no any additional fields in splitted vector
-                        "update" => {
-                            let rules_str = get_rules_string_by_level(init_vec[1].to_string(), rule_file);
-                            if let Err(e) = stream.write_all(rules_str.as_bytes()).await {
-                                println!("Error while sending rules to client {}: {}", addr_str, e);
-                                continue;
+            result = recv_message::<_, SensorMessage>(&mut messages), if paused_until.is_none() => match result {
+                Ok(Some(message)) => {
+                    if touch_sensor(&sensors_mutex_clone, &sensor_id) {
+                        publish(&bus, recover_event.clone());
+                    }
+
+                    match message {
+                        SensorMessage::RulesRequest { rules } => {
+                            let bundle = make_rule_bundle(&sensor_level, &rules, rule_file, &file_mutexes);
+                            let chunks_count = bundle.len();
+                            for chunk in bundle {
+                                send_message(&mut messages, &chunk).await?;
                             }
 
-                            println!("Sended rules to {}", addr_str);
-                            server_tx.send(raw_string.to_string() + "[:3:]" + init_vec[0] + "[:3:]" + init_vec[2] + "[:3:]" + init_vec[1]).await.unwrap();
+                            console_println!("Sended rules to {} in {} chunks", addr_str, chunks_count);
                         },
-                        // cmd_vec[1] - rule hash, cmd_vec[2] - UNIX-time, cmd_vec[3] - path (if host)
-                        "event" => {
-                            let status = if sensors_mutex_clone.lock().unwrap().get(&addr_str).unwrap().3 { "true" } else { "false" };
-                            if init_vec[1] == "host" {
-                                server_tx.send(raw_string.to_string() + "[:3:]" + init_vec[0] + "[:3:]" + init_vec[1]+ "[:3:]" + status).await.unwrap();
+                        SensorMessage::RulesApplied { rules } => {
+                            if let Some(info) = sensors_mutex_clone.lock().unwrap().get_mut(&sensor_id) {
+                                info.rules = rules.clone();
+                            }
+                            record_rules_version(&sensor_id, rules.version, &file_mutexes);
+                            prune_rules_history(&file_mutexes);
+
+                            publish(&bus, ServerEvent::RulesApplied { name: sensor_name.clone(), user: sensor_user.clone(), level: sensor_level.clone(), rules });
+                        },
+                        SensorMessage::Event { .. } | SensorMessage::EventBatch { .. } => {
+                            // batch is unpacked, so the rest of the server sees only single events
+                            let events = match message {
+                                SensorMessage::EventBatch { compressed, data } => match unpack_events(compressed, &data) {
+                                    Ok(events) => events,
+                                    Err(e) => {
+                                        publish(&bus, disconnect_event);
+                                        return Err(io::Error::new(e.kind(), format!("broken event batch from {}: {}", addr_str, e)));
+                                    },
+                                },
+                                event => vec![event],
+                            };
+
+                            let capturing = sensors_mutex_clone.lock().unwrap().get(&sensor_id).map(|info| info.capturing).unwrap_or(true);
+                            for event in events {
+                                if let SensorMessage::Event { seq, rule_hash, timestamp, path } = event {
+                                    let delay = throttle.pace();
+                                    if !delay.is_zero() {
+                                        paused_until = Some(Instant::now() + delay);
+                                    }
+
+                                    publish(&bus, ServerEvent::SensorEvent { sensor_id: sensor_id.clone(), name: sensor_name.clone(), level: sensor_level.clone(), capturing, seq, rule_hash, timestamp, path });
+                                }
                             }
-                            else {
-                                server_tx.send(raw_string.to_string() + "[:3:] [:3:]" + "[:3:]" + init_vec[0] + "[:3:]" + init_vec[1]+ "[:3:]" + status).await.unwrap();
+                        },
+                        SensorMessage::Heartbeat { uptime_secs, events_detected, events_sent, events_dropped, capturing, throttled } => {
+                            let (was_throttled, dropped_earlier) = match sensors_mutex_clone.lock().unwrap().get_mut(&sensor_id) {
+                                Some(info) => {
+                                    info.uptime_secs = uptime_secs;
+                                    info.events_detected = events_detected;
+                                    info.events_sent = events_sent;
+                                    info.capturing = capturing;
+                                    (std::mem::replace(&mut info.self_throttled, throttled), std::mem::replace(&mut info.events_dropped, events_dropped))
+                                },
+                                None => (throttled, events_dropped),
+                            };
+
+                            if throttled && !was_throttled {
+                                dropped_before = dropped_earlier;
+                                publish(&bus, ServerEvent::SensorThrottled { name: sensor_name.clone(), user: sensor_user.clone(), by_sensor: true, limit: rate_limit });
+                            } else if !throttled && was_throttled {
+                                publish(&bus, ServerEvent::SensorUnthrottled { name: sensor_name.clone(), user: sensor_user.clone(), by_sensor: true, over_limit: events_dropped.saturating_sub(dropped_before) });
                             }
+                        },
+                        SensorMessage::CommandResult { command_id, success, output } => {
+                            let pending = match sensors_mutex_clone.lock().unwrap().get_mut(&sensor_id) {
+                                Some(info) => {
+                                    let pending = info.pending_commands.remove(&command_id);
+                                    match pending.as_ref().map(|pending| pending.command) {
+                                        Some(SensorCommand::Start) if success => info.capturing = true,
+                                        Some(SensorCommand::Stop) if success => info.capturing = false,
+                                        _ => {},
+                                    }
+                                    pending
+                                },
+                                None => None,
+                            };
+                            let (command, operator, session_id) = match pending {
+                                Some(pending) => (pending.command.to_string(), pending.operator, pending.session_id),
+                                None => ("Unknown".to_string(), " ".to_string(), None),
+                            };
+
+                            publish(&bus, ServerEvent::CommandDone { name: sensor_name.clone(), operator, session_id, command, success, output });
+                        },
+                        SensorMessage::Hello { .. } => {
+                            console_println!("Repeated init message from client {}. Ignored.", addr_str);
+                        },
+                    }
+
+                    if let Some(change) = throttle.state_change() {
+                        if let Some(info) = sensors_mutex_clone.lock().unwrap().get_mut(&sensor_id) {
+                            info.throttled = throttle.throttled;
                         }
-                        _ => {}
+
+                        let event = match change {
+                            ThrottleChange::Started => ServerEvent::SensorThrottled { name: sensor_name.clone(), user: sensor_user.clone(), by_sensor: false, limit: rate_limit },
+                            ThrottleChange::Stopped { over_limit } => ServerEvent::SensorUnthrottled { name: sensor_name.clone(), user: sensor_user.clone(), by_sensor: false, over_limit },
+                        };
+                        publish(&bus, event);
                     }
-                }
-                Err(e) => return Err(e),
+                },
+                Ok(None) => {
+                    publish(&bus, disconnect_event);
+                    break;
+                },
+                Err(e) => {
+                    publish(&bus, disconnect_event);
+                    return Err(e);
+                },
             },
             // data stream from server interface to sensor
             message = client_rx.recv() => match message {
                 Some(msg) => {
+                    // sensor revoked from console or server is going down
+                    let closing = matches!(msg, ServerMessage::Rejected { .. } | ServerMessage::Shutdown { .. });
+
                     // sending some data from server interface to client
-                    if let Err(e) = stream.write_all(msg.as_bytes()).await {
-                        println!("Error while sending message to client {}: {}", addr_str, e);
-                        continue;
+                    if let Err(e) = send_message(&mut messages, &msg).await {
+                        console_println!("Error while sending message to client {}: {}", addr_str, e);
+                        if !closing {
+                            continue;
+                        }
+                    }
+                    if closing {
+                        publish(&bus, disconnect_event);
+                        break;
                     }
                 },
                 None => break,
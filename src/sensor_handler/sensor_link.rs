@@ -0,0 +1,410 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::file_manager::file_manager::credential_handler::{
+    load_sensor_credential, save_sensor_credential,
+};
+use crate::file_manager::file_manager::spool_handler::{EventSpool, SpillFile};
+use crate::protocol::framing::{
+    message_stream, pack_events, recv_message, send_message, sensor_handshake, MessageStream,
+};
+use crate::protocol::messages::{
+    RateLimit, RulesState, SensorAuth, SensorMessage, ServerMessage,
+};
+use crate::sensor_handler::rate_limiter::{EventThrottle, ThrottleChange};
+use crate::sensor_handler::rule_bundle::RuleReceiver;
+use crate::tls::tls_config::{connect_to_server, ServerTransport};
+
+pub type ServerStream = MessageStream<TlsStream<ServerTransport>>;
+
+// server unpacks a batch at once, bigger batches would flood it
+pub const MAX_BATCH_SIZE: usize = 256;
+
+// events are sent by up to size at once, the first one waits for the others no longer than interval
+#[derive(Clone, Copy)]
+pub struct Batching {
+    pub size: usize,
+    pub interval: Duration,
+    pub compress: bool,
+}
+
+impl Batching {
+    // every event in its own message
+    pub fn none() -> Batching {
+        Batching {
+            size: 1,
+            interval: Duration::ZERO,
+            compress: false,
+        }
+    }
+}
+
+// everything sensor needs to (re)connect to management server
+pub struct SensorLink {
+    pub address: String,
+    pub connector: TlsConnector,
+    pub name: String,
+    pub level: String,
+    pub username: String,
+    // operator password is sent on every connection, so it is kept for reconnects
+    pub password: String,
+    pub rules_file: String,
+    pub credential_file: String,
+    pub enrollment_token: String,
+}
+
+// Connect and pass init step. Credential issued on enrollment is saved,
+// so next connections use it instead of the token.
+// Returns stream, sequence number of the last event the server has written and event rate limit.
+// PermissionDenied - sensor is rejected, it makes sense to try again only after a while.
+pub async fn open_session(link: &SensorLink) -> io::Result<(ServerStream, u64, RateLimit)> {
+    let auth = match load_sensor_credential(&link.credential_file) {
+        Some((sensor_id, secret)) => SensorAuth::Credential { sensor_id, secret },
+        None if !link.enrollment_token.is_empty() => SensorAuth::Enrollment {
+            token: link.enrollment_token.clone(),
+        },
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "sensor is not enrolled. Create enrollment token in management server console and put it in 'enrollment_token' field of sensor config",
+            ))
+        }
+    };
+
+    let rules = RuleReceiver::load(&link.rules_file, &link.level).state;
+    let mut stream = message_stream(connect_to_server(&link.address, &link.connector).await?);
+
+    let (enrolled, last_event_seq, rate_limit) = sensor_handshake(
+        &mut stream,
+        &link.name,
+        &link.level,
+        &link.username,
+        &link.password,
+        auth,
+        rules,
+    )
+    .await?;
+
+    if let Some((sensor_id, secret)) = enrolled {
+        if !save_sensor_credential(&link.credential_file, &sensor_id, &secret) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "sensor enrolled, but credential can't be saved. Ask admin to revoke sensor {} and enroll it again",
+                    sensor_id
+                ),
+            ));
+        }
+        println!(
+            "Sensor enrolled. ID: {}. Credential saved to {}, enrollment token can be removed from config.",
+            sensor_id, link.credential_file
+        );
+    }
+
+    Ok((stream, last_event_seq, rate_limit))
+}
+
+// Where the sending side of a sensor takes detected events from.
+pub trait EventSource {
+    // None when detection is stopped
+    fn recv(&mut self) -> impl Future<Output = Option<SensorMessage>> + Send;
+}
+
+impl EventSource for mpsc::UnboundedReceiver<SensorMessage> {
+    fn recv(&mut self) -> impl Future<Output = Option<SensorMessage>> + Send {
+        mpsc::UnboundedReceiver::recv(self)
+    }
+}
+
+// (sending into the file, spilling started) shared by both ends of the queue
+type Spill = Arc<Mutex<(SpillFile, bool)>>;
+
+// Detection side of event queue. Events that don't fit in the queue go to spill file,
+// and so do all the next ones until the sending side takes them, order is kept.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<SensorMessage>,
+    spill: Spill,
+}
+
+impl EventSender {
+    // false if the sending side is gone
+    pub fn send(&self, event: SensorMessage) -> bool {
+        let mut spill = self.spill.lock().unwrap();
+        let (spill_file, spilling) = &mut *spill;
+
+        let event = if *spilling {
+            event
+        } else {
+            match self.tx.try_send(event) {
+                Ok(_) => return true,
+                Err(mpsc::error::TrySendError::Closed(_)) => return false,
+                Err(mpsc::error::TrySendError::Full(event)) => {
+                    println!("Event queue is full, events are spilled to {}", spill_file.spill_file);
+                    *spilling = true;
+                    event
+                }
+            }
+        };
+
+        if !spill_file.append(&event) && spill_file.dropped.is_power_of_two() {
+            println!(
+                "Spill file is full ({} events), event dropped. Dropped total: {}",
+                spill_file.limit, spill_file.dropped
+            );
+        }
+        true
+    }
+}
+
+// Sending side of event queue: events of the queue first, spilled ones after them.
+pub struct EventQueue {
+    rx: mpsc::Receiver<SensorMessage>,
+    spill: Spill,
+    // spilled events taken from the file, not received yet
+    taken: VecDeque<SensorMessage>,
+}
+
+// Memory is held only for capacity events, the rest waits on disk.
+pub fn event_queue(capacity: usize, spill_file: SpillFile) -> (EventSender, EventQueue) {
+    let (tx, rx) = mpsc::channel(capacity.max(1));
+    // events spilled before restart go before new ones
+    let spilling = !spill_file.is_empty();
+    let spill = Arc::new(Mutex::new((spill_file, spilling)));
+
+    (
+        EventSender {
+            tx,
+            spill: Arc::clone(&spill),
+        },
+        EventQueue {
+            rx,
+            spill,
+            taken: VecDeque::new(),
+        },
+    )
+}
+
+impl EventQueue {
+    // Cancel safe: taken events stay in the queue if the future is dropped
+    pub async fn recv(&mut self) -> Option<SensorMessage> {
+        if let Some(event) = self.taken.pop_front() {
+            return Some(event);
+        }
+        match self.rx.try_recv() {
+            Ok(event) => return Some(event),
+            Err(mpsc::error::TryRecvError::Disconnected) => return None,
+            Err(mpsc::error::TryRecvError::Empty) => {}
+        }
+
+        // spilled events are younger than everything in the queue, so they are taken only
+        // when it is empty. Detection side sends under the same lock, the queue can't fill meanwhile
+        {
+            let mut spill = self.spill.lock().unwrap();
+            let (spill_file, spilling) = &mut *spill;
+            if *spilling && self.rx.is_empty() {
+                self.taken.extend(spill_file.take());
+                *spilling = false;
+            }
+        }
+        match self.taken.pop_front() {
+            Some(event) => Some(event),
+            None => self.rx.recv().await,
+        }
+    }
+}
+
+impl EventSource for EventQueue {
+    fn recv(&mut self) -> impl Future<Output = Option<SensorMessage>> + Send {
+        EventQueue::recv(self)
+    }
+}
+
+// Try to connect again with growing delay. Detection goes on meanwhile:
+// every event from event_rx under rate limit is put in spool.
+pub async fn reconnect(
+    link: &SensorLink,
+    event_rx: &mut impl EventSource,
+    spool: &mut EventSpool,
+    max_delay: Duration,
+    events_detected: &mut u64,
+    throttle: &mut EventThrottle,
+) -> io::Result<(ServerStream, u64, RateLimit)> {
+    let mut delay = Duration::from_secs(1).min(max_delay);
+    let mut next_attempt = Instant::now() + delay;
+
+    loop {
+        tokio::select! {
+            event = event_rx.recv() => match event {
+                // command results are of no use after reconnect
+                Some(evt) => {
+                    if let SensorMessage::Event { .. } = evt {
+                        *events_detected += 1;
+                        if throttle.admit() {
+                            spool.push(evt);
+                        }
+                        check_throttle(throttle);
+                    }
+                },
+                None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "detection stopped")),
+            },
+            _ = sleep_until(next_attempt) => {
+                // spooled events are on disk at least once per attempt
+                spool.sync();
+                match open_session(link).await {
+                    Ok(session) => {
+                        println!("Connection with management server restored");
+                        return Ok(session);
+                    },
+                    // operator can be locked or sensor revoked for a while, server isn't asked often then
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        delay = max_delay;
+                        next_attempt = Instant::now() + delay;
+                        println!("Connection rejected: {}. Next attempt in {} seconds", e, delay.as_secs());
+                    },
+                    Err(e) => {
+                        delay = (delay * 2).min(max_delay);
+                        next_attempt = Instant::now() + delay;
+                        println!("Reconnect failed: {}. Next attempt in {} seconds", e, delay.as_secs());
+                    },
+                }
+            }
+        }
+    }
+}
+
+// Prints when the sensor starts and stops dropping events over rate limit.
+// Server learns it from heartbeats.
+pub fn check_throttle(throttle: &mut EventThrottle) {
+    match throttle.state_change() {
+        Some(ThrottleChange::Started) => println!(
+            "Event rate is over limit ({}). Events over limit are dropped",
+            throttle.limit()
+        ),
+        Some(ThrottleChange::Stopped { over_limit }) => println!(
+            "Event rate is under limit again. {} events dropped",
+            over_limit
+        ),
+        None => {}
+    }
+}
+
+// Single event goes as it is, several ones in EventBatch.
+pub async fn send_events(
+    stream: &mut ServerStream,
+    events: &[SensorMessage],
+    compress: bool,
+) -> io::Result<()> {
+    match events {
+        [] => Ok(()),
+        [event] => send_message(stream, event).await,
+        events => send_message(stream, &pack_events(events, compress)?).await,
+    }
+}
+
+// Send events not acknowledged yet in order they were raised, with original timestamps.
+// They stay in spool until EventAck comes. Returns number of sent events.
+pub async fn replay_spool(
+    stream: &mut ServerStream,
+    spool: &EventSpool,
+    batching: Batching,
+) -> io::Result<u64> {
+    for events in spool.events().chunks(batching.size.clamp(1, MAX_BATCH_SIZE)) {
+        send_events(stream, events, batching.compress).await?;
+    }
+
+    if !spool.is_empty() {
+        println!("Sent {} unacknowledged events again", spool.len());
+    }
+    Ok(spool.len() as u64)
+}
+
+// "-c update" mode: ask for full rule set and wait until it is checked and saved
+pub async fn update_rules(
+    stream: &mut ServerStream,
+    receiver: &mut RuleReceiver,
+) -> io::Result<RulesState> {
+    let request = SensorMessage::RulesRequest {
+        rules: RulesState::default(),
+    };
+    send_message(stream, &request).await?;
+
+    loop {
+        match recv_message::<_, ServerMessage>(stream).await? {
+            Some(chunk @ ServerMessage::RulesChunk { .. }) => match receiver.receive(chunk) {
+                Ok(Some(rules)) => {
+                    let applied = SensorMessage::RulesApplied {
+                        rules: rules.clone(),
+                    };
+                    send_message(stream, &applied).await?;
+                    return Ok(rules);
+                }
+                Ok(None) => {}
+                Err(reason) => return Err(io::Error::new(io::ErrorKind::InvalidData, reason)),
+            },
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed connection",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(rule_hash: &str) -> SensorMessage {
+        SensorMessage::Event {
+            seq: 0,
+            rule_hash: rule_hash.to_string(),
+            timestamp: 1_700_000_000,
+            path: None,
+        }
+    }
+
+    async fn next_hash(queue: &mut EventQueue) -> Option<String> {
+        match queue.recv().await {
+            Some(SensorMessage::Event { rule_hash, .. }) => Some(rule_hash),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn spilled_events_keep_their_order() {
+        let spill_path = std::env::temp_dir()
+            .join(format!("rsoc_spill_test_{}.txt", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_file(&spill_path);
+        let (sender, mut queue) = event_queue(2, SpillFile::open(&spill_path, 100));
+
+        for hash in ["a", "b", "c", "d"] {
+            assert!(sender.send(event(hash)));
+        }
+        assert_eq!(next_hash(&mut queue).await.as_deref(), Some("a"));
+        // spilling goes on until the queue is empty
+        assert!(sender.send(event("e")));
+        for hash in ["b", "c", "d", "e"] {
+            assert_eq!(next_hash(&mut queue).await.as_deref(), Some(hash));
+        }
+
+        // the queue is used again
+        assert!(sender.send(event("f")));
+        assert_eq!(next_hash(&mut queue).await.as_deref(), Some("f"));
+        assert!(!std::path::Path::new(&spill_path).exists());
+
+        drop(sender);
+        assert!(queue.recv().await.is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+
+use crate::event_bus::event_bus::{publish, EventBus, ServerEvent};
+use crate::protocol::messages::ServerMessage;
+use crate::sensor_handler::sensor_handler::handle_client;
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{OperatorAuth, RateLimits, SensorMap};
+use crate::tls::certificates::SENSOR_UNIT;
+use crate::tls::tls_config::peer_identity;
+
+// prefix of unix socket path in bind and server addresses
+pub const UNIX_PREFIX: &str = "unix:";
+
+// unix socket peers have no address, so connections are numbered
+static NEXT_LOCAL_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+// everything listeners share with handle_client
+#[derive(Clone)]
+pub struct ListenerContext {
+    pub tls_acceptor: TlsAcceptor,
+    pub rules_file: String,
+    pub rate_limits: RateLimits,
+    pub operator_auth: OperatorAuth,
+    pub sensors: SensorMap,
+    pub bus: EventBus,
+    pub file_mutexes: FileMutexes,
+    // connections of sensors, server waits for them on shutdown
+    pub tracker: TaskTracker,
+}
+
+pub enum SensorListener {
+    Tcp(TcpListener, String),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl SensorListener {
+    pub fn address(&self) -> &str {
+        match self {
+            SensorListener::Tcp(_, address) => address,
+            #[cfg(unix)]
+            SensorListener::Unix(_, address) => address,
+        }
+    }
+}
+
+// address - "IP:port", "[IPv6]:port" or "unix:/path/to/socket"
+pub async fn bind_listener(address: &str) -> io::Result<SensorListener> {
+    match address.strip_prefix(UNIX_PREFIX) {
+        Some(path) => bind_unix(path),
+        None => {
+            let listener = TcpListener::bind(address).await?;
+            let local_address = listener.local_addr()?.to_string();
+            Ok(SensorListener::Tcp(listener, local_address))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<SensorListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // socket left by previous run
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and it is not a socket", path),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+    Ok(SensorListener::Unix(
+        listener,
+        UNIX_PREFIX.to_string() + path,
+    ))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str) -> io::Result<SensorListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("unix socket {} is not supported on this platform", path),
+    ))
+}
+
+// Removes socket file of unix listener. Nothing to do for TCP.
+pub fn close_listener(listener_address: &str) {
+    if let Some(path) = listener_address.strip_prefix(UNIX_PREFIX) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// Accepts sensors until the task is aborted. Every connection goes to handle_client.
+pub fn spawn_listener(listener: SensorListener, context: ListenerContext) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match listener {
+                SensorListener::Tcp(ref tcp_listener, _) => match tcp_listener.accept().await {
+                    Ok((stream, addr)) => {
+                        context.tracker.spawn(serve_sensor(stream, addr.to_string(), context.clone()));
+                    }
+                    Err(e) => println!("Error while recieving connection:\n{}", e),
+                },
+                #[cfg(unix)]
+                SensorListener::Unix(ref unix_listener, ref address) => {
+                    match unix_listener.accept().await {
+                        Ok((stream, _)) => {
+                            let number = NEXT_LOCAL_CONNECTION.fetch_add(1, Ordering::Relaxed);
+                            let addr_str = format!("{}#{}", address, number);
+                            context.tracker.spawn(serve_sensor(stream, addr_str, context.clone()));
+                        }
+                        Err(e) => println!("Error while recieving connection:\n{}", e),
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn serve_sensor<S>(stream: S, addr_str: String, context: ListenerContext)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tls_stream = match context.tls_acceptor.accept(stream).await {
+        Ok(tls_stream) => tls_stream,
+        Err(e) => {
+            publish(
+                &context.bus,
+                ServerEvent::SensorRejected {
+                    addr: addr_str,
+                    name: " ".to_string(),
+                    user: " ".to_string(),
+                    reason: format!("TLS handshake failed: {}", e),
+                },
+            );
+            return;
+        }
+    };
+    let identity = match peer_identity(tls_stream.get_ref().1.peer_certificates(), SENSOR_UNIT) {
+        Ok(identity) => identity,
+        Err(reason) => {
+            publish(
+                &context.bus,
+                ServerEvent::SensorRejected {
+                    addr: addr_str,
+                    name: " ".to_string(),
+                    user: " ".to_string(),
+                    reason,
+                },
+            );
+            return;
+        }
+    };
+
+    let channel = mpsc::channel::<ServerMessage>(32);
+    if let Err(e) = handle_client(tls_stream, addr_str, identity, channel, &context).await {
+        println!("Error while client processing:\n{}", e);
+    }
+}
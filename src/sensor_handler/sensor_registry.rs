@@ -0,0 +1,563 @@
+use chrono::{DateTime, Local};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::console_println;
+use crate::file_manager::file_manager::user_file_handler::replace_private_file;
+use crate::structs::soc_structs::multithread::FileMutexes;
+use crate::structs::soc_structs::{EnrollmentToken, RegisteredSensor};
+
+fn random_hex(bytes_count: usize) -> String {
+    let mut bytes = vec![0u8; bytes_count];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(data: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+// missing file has no lines yet
+fn read_lines(file_path: &str) -> Vec<String> {
+    let buf = match fs::read_to_string(file_path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            console_println!("Error occured while reading registry file: {}", e);
+            String::new()
+        }
+    };
+
+    buf.lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// file is replaced, crash during the write leaves the previous content
+fn write_lines(file_path: &str, lines: &[String]) -> bool {
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+
+    replace_private_file(file_path, &content)
+}
+
+pub fn get_registered_sensors(file_mutexes: &FileMutexes) -> Vec<RegisteredSensor> {
+    let registry_file = file_mutexes.registry_mutex.lock().unwrap();
+    parse_registry(&registry_file, file_mutexes)
+}
+
+// values of the sensor that change while it works
+#[derive(Clone, Copy, Default)]
+struct SensorState {
+    last_seen: i64,
+    last_event_seq: u64,
+    // version of rule set the sensor applied last, 0 if unknown
+    rules_version: u64,
+}
+
+// state line: id[:|:]last_seen[:|:]last_event_seq[:|:]rules_version
+// It is rewritten on every event batch, so registry with sensor credentials is not.
+// Lines of 3 fields are left from older versions and have no rules version
+fn parse_state(state_file: &str) -> HashMap<String, SensorState> {
+    let mut result: HashMap<String, SensorState> = HashMap::new();
+
+    for line in read_lines(state_file) {
+        let parts: Vec<&str> = line.split("[:|:]").collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            console_println!("Wrong sensor state string format: '{}'", &line);
+            continue;
+        }
+        result.insert(
+            parts[0].to_string(),
+            SensorState {
+                last_seen: parts[1].parse().unwrap_or(0),
+                last_event_seq: parts[2].parse().unwrap_or(0),
+                rules_version: parts.get(3).and_then(|v| v.parse().ok()).unwrap_or(0),
+            },
+        );
+    }
+
+    result
+}
+
+fn save_state(state_file: &str, state: &HashMap<String, SensorState>) -> bool {
+    let mut lines: Vec<String> = state
+        .iter()
+        .map(|(id, s)| {
+            format!(
+                "{}[:|:]{}[:|:]{}[:|:]{}",
+                id, s.last_seen, s.last_event_seq, s.rules_version
+            )
+        })
+        .collect();
+    lines.sort();
+
+    write_lines(state_file, &lines)
+}
+
+// change gets state of the sensor, zeroes if it has no state yet
+fn update_state(
+    file_mutexes: &FileMutexes,
+    sensor_ids: &[&str],
+    mut change: impl FnMut(&str, &mut SensorState),
+) -> bool {
+    let state_file = file_mutexes.state_mutex.lock().unwrap();
+    let mut state = parse_state(&state_file);
+    for sensor_id in sensor_ids {
+        change(sensor_id, state.entry(sensor_id.to_string()).or_default());
+    }
+
+    save_state(&state_file, &state)
+}
+
+// registry line: id[:|:]name[:|:]level[:|:]secret_hash[:|:]revoked (0/1)[:|:]owner[:|:]
+// first_seen[:|:]last_seen[:|:]last_address[:|:]capture (0/1)[:|:]last_event_seq
+// lines of 5 and 10 fields are left from older versions and get default values.
+// last_seen and last_event_seq of registry are copies, state file has newer ones. Both only grow,
+// so the greater is used: registry of older version has them before state file exists
+fn parse_registry(registry_file: &str, file_mutexes: &FileMutexes) -> Vec<RegisteredSensor> {
+    let mut sensors = parse_registry_lines(registry_file);
+    let state = parse_state(&file_mutexes.state_mutex.lock().unwrap());
+    for sensor in sensors.iter_mut() {
+        if let Some(sensor_state) = state.get(&sensor.id) {
+            sensor.last_seen = sensor.last_seen.max(sensor_state.last_seen);
+            sensor.last_event_seq = sensor.last_event_seq.max(sensor_state.last_event_seq);
+        }
+    }
+
+    sensors
+}
+
+fn parse_registry_lines(registry_file: &str) -> Vec<RegisteredSensor> {
+    let mut result: Vec<RegisteredSensor> = Vec::new();
+
+    for line in read_lines(registry_file) {
+        let parts: Vec<&str> = line.split("[:|:]").collect();
+        if parts.len() != 5 && parts.len() != 10 && parts.len() != 11 {
+            console_println!("Wrong registry string format: '{}'", &line);
+            continue;
+        }
+
+        let mut sensor = RegisteredSensor {
+            id: parts[0].to_string(),
+            name: parts[1].to_string(),
+            level: parts[2].to_string(),
+            secret_hash: parts[3].to_string(),
+            revoked: parts[4] == "1",
+            owner: " ".to_string(),
+            first_seen: 0,
+            last_seen: 0,
+            last_address: " ".to_string(),
+            capture: true,
+            last_event_seq: 0,
+        };
+        if parts.len() >= 10 {
+            sensor.owner = parts[5].to_string();
+            sensor.first_seen = parts[6].parse().unwrap_or(0);
+            sensor.last_seen = parts[7].parse().unwrap_or(0);
+            sensor.last_address = parts[8].to_string();
+            sensor.capture = parts[9] == "1";
+        }
+        if parts.len() == 11 {
+            sensor.last_event_seq = parts[10].parse().unwrap_or(0);
+        }
+
+        result.push(sensor);
+    }
+
+    result
+}
+
+fn save_registry(registry_file: &str, sensors: &[RegisteredSensor]) -> bool {
+    let lines: Vec<String> = sensors
+        .iter()
+        .map(|sensor| {
+            [
+                sensor.id.clone(),
+                sensor.name.clone(),
+                sensor.level.clone(),
+                sensor.secret_hash.clone(),
+                if sensor.revoked { "1" } else { "0" }.to_string(),
+                sensor.owner.clone(),
+                sensor.first_seen.to_string(),
+                sensor.last_seen.to_string(),
+                sensor.last_address.clone(),
+                if sensor.capture { "1" } else { "0" }.to_string(),
+                sensor.last_event_seq.to_string(),
+            ]
+            .join("[:|:]")
+        })
+        .collect();
+
+    write_lines(registry_file, &lines)
+}
+
+// token line: token_hash[:|:]sensor_name[:|:]expires (UNIX-time)[:|:]created_by
+fn parse_tokens(tokens_file: &str) -> Vec<EnrollmentToken> {
+    let mut result: Vec<EnrollmentToken> = Vec::new();
+
+    for line in read_lines(tokens_file) {
+        let parts: Vec<&str> = line.split("[:|:]").collect();
+        if parts.len() != 4 {
+            console_println!("Wrong token string format: '{}'", &line);
+            continue;
+        }
+
+        result.push(EnrollmentToken {
+            token_hash: parts[0].to_string(),
+            sensor_name: parts[1].to_string(),
+            expires: parts[2].parse().unwrap_or(0),
+            created_by: parts[3].to_string(),
+        });
+    }
+
+    result
+}
+
+fn save_tokens(tokens_file: &str, tokens: &[EnrollmentToken]) -> bool {
+    let lines: Vec<String> = tokens
+        .iter()
+        .map(|token| {
+            [
+                token.token_hash.clone(),
+                token.sensor_name.clone(),
+                token.expires.to_string(),
+                token.created_by.clone(),
+            ]
+            .join("[:|:]")
+        })
+        .collect();
+
+    write_lines(tokens_file, &lines)
+}
+
+// Returns plain token to show to admin once. Only its hash is stored.
+pub fn create_enrollment_token(
+    sensor_name: &str,
+    validity_hours: i64,
+    created_by: &str,
+    file_mutexes: &FileMutexes,
+) -> Option<String> {
+    let token = random_hex(24);
+    let now = Local::now().timestamp();
+    let tokens_file = file_mutexes.tokens_mutex.lock().unwrap();
+    let mut tokens: Vec<EnrollmentToken> = parse_tokens(&tokens_file)
+        .into_iter()
+        .filter(|t| t.expires > now)
+        .collect();
+
+    tokens.push(EnrollmentToken {
+        token_hash: sha256_hex(&token),
+        sensor_name: sensor_name.to_string(),
+        expires: now + validity_hours * 3600,
+        created_by: created_by.to_string(),
+    });
+
+    if save_tokens(&tokens_file, &tokens) {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+// Consumes one-time token and registers sensor. Returns (sensor_id, secret) or reject reason.
+pub fn enroll_sensor(
+    token: &str,
+    sensor_name: &str,
+    sensor_level: &str,
+    file_mutexes: &FileMutexes,
+) -> Result<(String, String), String> {
+    let token_hash = sha256_hex(token);
+    let now = Local::now().timestamp();
+    let tokens_file = file_mutexes.tokens_mutex.lock().unwrap();
+    let mut tokens = parse_tokens(&tokens_file);
+
+    let position = match tokens.iter().position(|t| t.token_hash == token_hash) {
+        Some(position) => position,
+        None => return Err("unknown enrollment token".to_string()),
+    };
+    // token is used up only by successful enrollment, a wrong name must not burn it
+    if tokens[position].expires <= now {
+        tokens.retain(|t| t.expires > now);
+        save_tokens(&tokens_file, &tokens);
+        return Err("enrollment token expired".to_string());
+    }
+    if tokens[position].sensor_name != sensor_name {
+        return Err(format!(
+            "enrollment token was created for sensor {}",
+            tokens[position].sensor_name
+        ));
+    }
+
+    let registry_file = file_mutexes.registry_mutex.lock().unwrap();
+    let mut sensors = parse_registry(&registry_file, file_mutexes);
+    if sensors.iter().any(|s| s.name == sensor_name && !s.revoked) {
+        return Err("sensor with this name is already enrolled".to_string());
+    }
+
+    let sensor_id = random_hex(8);
+    let secret = random_hex(32);
+    sensors.push(RegisteredSensor {
+        id: sensor_id.clone(),
+        name: sensor_name.to_string(),
+        level: sensor_level.to_string(),
+        secret_hash: sha256_hex(&secret),
+        revoked: false,
+        owner: " ".to_string(),
+        first_seen: now,
+        last_seen: 0,
+        last_address: " ".to_string(),
+        capture: true,
+        last_event_seq: 0,
+    });
+
+    if !save_registry(&registry_file, &sensors) {
+        return Err("registry is not available".to_string());
+    }
+    tokens.remove(position);
+    tokens.retain(|t| t.expires > now);
+    save_tokens(&tokens_file, &tokens);
+
+    Ok((sensor_id, secret))
+}
+
+// Ok(()) if sensor is enrolled, not revoked and presents its own credential
+pub fn verify_sensor(
+    sensor_id: &str,
+    secret: &str,
+    sensor_name: &str,
+    sensor_level: &str,
+    file_mutexes: &FileMutexes,
+) -> Result<(), String> {
+    let sensors = get_registered_sensors(file_mutexes);
+    let sensor = match sensors.iter().find(|s| s.id == sensor_id) {
+        Some(sensor) => sensor,
+        None => return Err("unknown sensor".to_string()),
+    };
+
+    if sensor.secret_hash != sha256_hex(secret) {
+        return Err("wrong sensor credential".to_string());
+    }
+    if sensor.revoked {
+        return Err("sensor is revoked".to_string());
+    }
+    if sensor.name != sensor_name || sensor.level != sensor_level {
+        return Err(format!(
+            "credential belongs to sensor {} ({} level)",
+            sensor.name, sensor.level
+        ));
+    }
+
+    Ok(())
+}
+
+// target - sensor name or ID. Returns name of revoked sensor.
+pub fn revoke_sensor(target: &str, file_mutexes: &FileMutexes) -> Option<String> {
+    let registry_file = file_mutexes.registry_mutex.lock().unwrap();
+    let mut sensors = parse_registry(&registry_file, file_mutexes);
+    let sensor = sensors
+        .iter_mut()
+        .find(|s| !s.revoked && (s.id == target || s.name == target))?;
+    sensor.revoked = true;
+    let name = sensor.name.clone();
+
+    if save_registry(&registry_file, &sensors) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// Stores who and from where connected the sensor.
+// Returns desired capture state of the sensor and its last written event.
+pub fn record_sensor_connect(
+    sensor_id: &str,
+    owner: &str,
+    address: &str,
+    file_mutexes: &FileMutexes,
+) -> (bool, u64) {
+    let now = Local::now().timestamp();
+    let registry_file = file_mutexes.registry_mutex.lock().unwrap();
+    let mut sensors = parse_registry(&registry_file, file_mutexes);
+    let sensor = match sensors.iter_mut().find(|s| s.id == sensor_id) {
+        Some(sensor) => sensor,
+        None => return (true, 0),
+    };
+
+    if sensor.first_seen == 0 {
+        sensor.first_seen = now;
+    }
+    sensor.last_seen = now;
+    sensor.owner = owner.to_string();
+    sensor.last_address = address.to_string();
+    let result = (sensor.capture, sensor.last_event_seq);
+
+    save_registry(&registry_file, &sensors);
+    result
+}
+
+pub fn get_last_event_seq(sensor_id: &str, file_mutexes: &FileMutexes) -> u64 {
+    get_registered_sensors(file_mutexes)
+        .iter()
+        .find(|s| s.id == sensor_id)
+        .map(|s| s.last_event_seq)
+        .unwrap_or(0)
+}
+
+// seqs - (sensor ID, last written event). Called after events are durably written,
+// so duplicates can be dropped after restart. Only state file is replaced
+pub fn set_last_event_seqs(seqs: &[(String, u64)], file_mutexes: &FileMutexes) -> bool {
+    if seqs.is_empty() {
+        return true;
+    }
+
+    let sensor_ids: Vec<&str> = seqs.iter().map(|(id, _)| id.as_str()).collect();
+    update_state(file_mutexes, &sensor_ids, |sensor_id, sensor_state| {
+        if let Some((_, seq)) = seqs.iter().find(|(id, _)| id == sensor_id) {
+            sensor_state.last_event_seq = *seq;
+        }
+    })
+}
+
+// seen - (sensor ID, last seen UNIX-time)
+pub fn record_sensors_seen(seen: &[(String, i64)], file_mutexes: &FileMutexes) -> bool {
+    if seen.is_empty() {
+        return true;
+    }
+
+    let sensor_ids: Vec<&str> = seen.iter().map(|(id, _)| id.as_str()).collect();
+    update_state(file_mutexes, &sensor_ids, |sensor_id, sensor_state| {
+        if let Some((_, time)) = seen.iter().find(|(id, _)| id == sensor_id) {
+            sensor_state.last_seen = *time;
+        }
+    })
+}
+
+// version of rules the sensor reported on connection or after applying a bundle
+pub fn record_rules_version(sensor_id: &str, version: u64, file_mutexes: &FileMutexes) -> bool {
+    update_state(file_mutexes, &[sensor_id], |_, sensor_state| {
+        sensor_state.rules_version = version;
+    })
+}
+
+// Oldest rule set version a registered sensor holds, None if no sensor reported one.
+// Rules history before it is not needed for differential bundles
+pub fn oldest_rules_version(file_mutexes: &FileMutexes) -> Option<u64> {
+    let sensors = parse_registry_lines(&file_mutexes.registry_mutex.lock().unwrap());
+    let state = parse_state(&file_mutexes.state_mutex.lock().unwrap());
+
+    sensors
+        .iter()
+        .filter(|sensor| !sensor.revoked)
+        .filter_map(|sensor| state.get(&sensor.id))
+        .map(|sensor_state| sensor_state.rules_version)
+        .filter(|version| *version > 0)
+        .min()
+}
+
+// capture state the sensor is put in on every connection
+pub fn set_desired_capture(sensor_id: &str, capture: bool, file_mutexes: &FileMutexes) -> bool {
+    let registry_file = file_mutexes.registry_mutex.lock().unwrap();
+    let mut sensors = parse_registry(&registry_file, file_mutexes);
+    match sensors.iter_mut().find(|s| s.id == sensor_id) {
+        Some(sensor) => sensor.capture = capture,
+        None => return false,
+    }
+
+    save_registry(&registry_file, &sensors)
+}
+
+fn format_time(timestamp: i64) -> String {
+    if timestamp == 0 {
+        return "never".to_string();
+    }
+
+    match DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time
+            .with_timezone(&Local)
+            .format("%d-%m-%Y %H:%M:%S")
+            .to_string(),
+        None => "never".to_string(),
+    }
+}
+
+pub fn get_registry_list(file_mutexes: &FileMutexes) {
+    console_println!("---------------------------------------------------------------------------------------------------------------------------------------------------------------\n\
+             || ----- Sensor ID ----- || ----- Name ----- || ----- Level ----- || ----- State ----- || ----- Owner ----- || ----- First seen ----- || ----- Last seen ----- || ----- Last address ----- || ----- Capture ----- ||\n\
+             ---------------------------------------------------------------------------------------------------------------------------------------------------------------");
+
+    for sensor in get_registered_sensors(file_mutexes) {
+        let state = if sensor.revoked {
+            "revoked"
+        } else {
+            "enrolled"
+        };
+        let capture = if sensor.capture { "on" } else { "off" };
+        console_println!(
+            "|| {} || {} || {} || {} || {} || {} || {} || {} || {} ||",
+            sensor.id,
+            sensor.name,
+            sensor.level,
+            state,
+            sensor.owner,
+            format_time(sensor.first_seen),
+            format_time(sensor.last_seen),
+            sensor.last_address,
+            capture
+        );
+    }
+
+    console_println!("---------------------------------------------------------------------------------------------------------------------------------------------------------------");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_seqs_do_not_rewrite_registry() {
+        let file_mutexes = FileMutexes::for_tests("registry_state");
+        let token = create_enrollment_token("Zarya-1", 1, "admin", &file_mutexes).unwrap();
+        let (sensor_id, secret) = enroll_sensor(&token, "Zarya-1", "host", &file_mutexes).unwrap();
+        let registry_file = file_mutexes.registry_mutex.lock().unwrap().clone();
+        let registry = fs::read_to_string(&registry_file).unwrap();
+
+        assert!(set_last_event_seqs(&[(sensor_id.clone(), 42)], &file_mutexes));
+        assert!(record_sensors_seen(&[(sensor_id.clone(), 1000)], &file_mutexes));
+        assert_eq!(fs::read_to_string(&registry_file).unwrap(), registry);
+        assert_eq!(get_last_event_seq(&sensor_id, &file_mutexes), 42);
+        assert!(verify_sensor(&sensor_id, &secret, "Zarya-1", "host", &file_mutexes).is_ok());
+
+        // registry saved later keeps the copy, state file stays newer
+        assert!(set_desired_capture(&sensor_id, false, &file_mutexes));
+        assert!(set_last_event_seqs(&[(sensor_id.clone(), 43)], &file_mutexes));
+        let sensor = get_registered_sensors(&file_mutexes).remove(0);
+        assert_eq!((sensor.last_event_seq, sensor.capture), (43, false));
+    }
+
+    #[test]
+    fn registry_of_older_version_keeps_event_seq() {
+        let file_mutexes = FileMutexes::for_tests("registry_old");
+        let registry_file = file_mutexes.registry_mutex.lock().unwrap().clone();
+        fs::write(
+            &registry_file,
+            "0123[:|:]Zakat-1[:|:]net[:|:]hash[:|:]0[:|:]op[:|:]1[:|:]2[:|:]addr[:|:]1[:|:]77\n",
+        )
+        .unwrap();
+
+        assert_eq!(get_last_event_seq("0123", &file_mutexes), 77);
+        assert!(record_sensors_seen(&[("0123".to_string(), 5)], &file_mutexes));
+        assert_eq!(get_last_event_seq("0123", &file_mutexes), 77);
+        assert!(set_last_event_seqs(&[("0123".to_string(), 80)], &file_mutexes));
+        assert_eq!(get_last_event_seq("0123", &file_mutexes), 80);
+    }
+}
@@ -1,19 +1,211 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc;
 
+use crate::protocol::messages::{ConsoleOutput, RateLimit, RulesState, SensorCommand, ServerMessage};
+
+// connected sensor, health data comes from heartbeats
+pub struct SensorInfo {
+    pub tx: mpsc::Sender<ServerMessage>,
+    pub addr: String,
+    pub name: String,
+    pub level: String,
+    pub user: String,
+    pub capturing: bool,
+    pub rules: RulesState,
+    pub last_seen: SystemTime,
+    pub silent: bool,
+    pub uptime_secs: u64,
+    pub events_detected: u64,
+    pub events_sent: u64,
+    // events over rate limit dropped by sensor itself
+    pub events_dropped: u64,
+    // server slows down reading from the sensor
+    pub throttled: bool,
+    // sensor drops events over its rate limit
+    pub self_throttled: bool,
+    // command ID -> command sent to sensor, until sensor acknowledges it
+    pub pending_commands: HashMap<u64, PendingCommand>,
+}
+
+pub struct PendingCommand {
+    pub command: SensorCommand,
+    // operator who sent it, "system" for commands sent on connection
+    pub operator: String,
+    // console session the result is shown in, None - server console
+    pub session_id: Option<u64>,
+}
+
+// sensor ID (from registry) -> connected sensor
+pub type SensorMap = Arc<Mutex<HashMap<String, SensorInfo>>>;
+
 pub struct SessionStatus {
     pub host: String,
     pub user: String,
-    pub is_admin: bool,
-    pub sensor_list: Arc<Mutex<HashMap<String, (mpsc::Sender<String>, String, String, bool)>>>,
+    pub role: String,
+    // permissions of the role, checked again before every privileged action
+    pub permissions: HashSet<Permission>,
+    pub sensor_list: SensorMap,
+    pub activity: Arc<Mutex<SessionActivity>>,
+    // session is locked after this time without input, zero - never
+    pub idle_timeout: Duration,
+    // password is checked there on unlock and before sensitive actions
+    pub users_file: String,
+    pub lockout_file: String,
+    // role of the user can be changed while the session goes on
+    pub roles_file: String,
+    pub lockout: LockoutPolicy,
+    // client address of remote console, None - local console
+    pub remote_addr: Option<String>,
+    // key in ConsoleSessions, results of sensor commands are shown in this session
+    pub session_id: u64,
+}
+
+// shared with management server loop, which locks the session when nobody types
+pub struct SessionActivity {
+    pub last_input: Instant,
+    pub locked: bool,
+}
+
+// console session seen by management server loop: local one and every remote one
+pub struct ConsoleSession {
+    pub host: String,
+    pub user: String,
+    pub remote_addr: Option<String>,
+    pub activity: Arc<Mutex<SessionActivity>>,
+    // remote client, lock notice is sent there. Local console gets it from console printer
+    pub output: Option<mpsc::UnboundedSender<ConsoleOutput>>,
 }
 
+// session ID -> console session, 0 - local console
+pub type ConsoleSessions = Arc<Mutex<HashMap<u64, ConsoleSession>>>;
+
+// event rate limits: default one and for particular sensors (by name)
+#[derive(Clone)]
+pub struct RateLimits {
+    pub default: RateLimit,
+    pub sensors: HashMap<String, RateLimit>,
+}
+
+impl RateLimits {
+    pub fn for_sensor(&self, name: &str) -> RateLimit {
+        *self.sensors.get(name).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Clone)]
 pub struct LogFiles {
     pub audit_file: String,
     pub event_file: String,
     pub rules_file: String,
+    pub rules_history_file: String,
+    pub registry_file: String,
+    pub state_file: String,
+    pub tokens_file: String,
+    pub users_file: String,
+    pub roles_file: String,
+    pub lockout_file: String,
+}
+
+// console user, password is stored as Argon2id (unsalted SHA-256 in old files).
+// must_change_password - password is temporary, user sets own one on the next logon
+pub struct UserAccount {
+    pub name: String,
+    pub pass_hash: String,
+    pub role: String,
+    pub disabled: bool,
+    pub must_change_password: bool,
+    // UNIX-time, 0 - unknown (older users file)
+    pub password_changed: i64,
+    // hashes of previous passwords, the latest first
+    pub password_history: Vec<String>,
+    // base32 TOTP secret, empty - second factor is not set up
+    pub totp_secret: String,
+    // time step of the last accepted code, the same code is not accepted twice
+    pub totp_last_step: u64,
+    // SHA-256 of unused recovery codes
+    pub recovery_codes: Vec<String>,
+    // UNIX-time, 0 - unknown (older users file) or never
+    pub created: i64,
+    pub last_logon: i64,
+    pub last_failed_logon: i64,
+    // UNIX-time when the account stops working, 0 - never
+    pub expires: i64,
+}
+
+// checked for passwords set by users themselves.
+// classes - how many of lowercase, uppercase, digits and other symbols are required.
+// expiry_days 0 - password doesn't expire, history - number of previous passwords which can't be used again
+#[derive(Clone, Copy)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub classes: usize,
+    pub expiry_days: i64,
+    pub history: usize,
+}
+
+// settings of console logon from server config
+#[derive(Clone, Copy)]
+pub struct AuthPolicy {
+    pub lockout: LockoutPolicy,
+    pub password: PasswordPolicy,
+    // administrators without second factor set it up at logon
+    pub totp_required: bool,
+}
+
+// sensor operators from init messages are checked against console users
+#[derive(Clone)]
+pub struct OperatorAuth {
+    pub users_file: String,
+    pub roles_file: String,
+    pub lockout_file: String,
+    pub policy: AuthPolicy,
+}
+
+// attempts failed logons within window_secs lock the account for duration_secs.
+// attempts 0 - no lockout, duration_secs 0 - until admin unlocks
+#[derive(Clone, Copy)]
+pub struct LockoutPolicy {
+    pub attempts: u32,
+    pub window_secs: i64,
+    pub duration_secs: i64,
+}
+
+// failed logons of a user, first_failure - UNIX-time when counting started.
+// locked_until - UNIX-time, 0 - not locked, -1 - until admin unlocks
+pub struct LogonFailures {
+    pub user: String,
+    pub failures: u32,
+    pub first_failure: i64,
+    pub locked_until: i64,
+}
+
+// enrolled sensor, secret is stored only as SHA-256
+// first_seen, last_seen - UNIX-time (0 - never), capture - desired capture state.
+// last_seen and last_event_seq are kept in sensor state file
+pub struct RegisteredSensor {
+    pub id: String,
+    pub name: String,
+    pub level: String,
+    pub secret_hash: String,
+    pub revoked: bool,
+    pub owner: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub last_address: String,
+    pub capture: bool,
+    // sequence number of the last event written to event log
+    pub last_event_seq: u64,
+}
+
+// one-time enrollment token, expires - UNIX-time
+pub struct EnrollmentToken {
+    pub token_hash: String,
+    pub sensor_name: String,
+    pub expires: i64,
+    pub created_by: String,
 }
 
 #[derive(Debug)]
@@ -33,15 +225,106 @@ pub enum AuditEventType {
     EvtLogAccess,
     ServOff,
     ServOn,
+    TokenCreate,
+    SenEnroll,
+    SenRevoke,
+    SenReject,
+    SenSilent,
+    SenRecover,
+    SenCommand,
+    SenCommandAck,
+    SenThrottle,
+    SenUnthrottle,
+    PassChange,
+    PassReset,
+    UserCreate,
+    UserDelete,
+    UserDisable,
+    UserEnable,
+    PrivChange,
+    AccessDenied,
+    UserLock,
+    UserUnlock,
+    SessionLock,
+    SessionUnlock,
+    ReAuth,
+    MfaSuccess,
+    MfaFail,
+    MfaEnroll,
+    MfaReset,
+    ConsoleConn,
+    ConsoleDisconn,
+    ConsoleReject,
+    UserExpiry,
+    UserStoreAlert,
+    RolesChange,
 }
 
 pub mod multithread {
     use std::sync::{Arc, Mutex};
 
+    #[derive(Clone)]
     pub struct FileMutexes {
         pub audit_mutex: Arc<Mutex<std::fs::File>>,
         pub event_mutex: Arc<Mutex<std::fs::File>>,
         pub rules_mutex: Arc<Mutex<std::fs::File>>,
+        // history is appended and compacted by replacing, the mutex keeps the path.
+        // Taken after rules_mutex when both are needed
+        pub rules_history_mutex: Arc<Mutex<String>>,
+        // registry and tokens files are replaced as a whole, the mutex keeps the path
+        pub registry_mutex: Arc<Mutex<String>>,
+        pub tokens_mutex: Arc<Mutex<String>>,
+        // last seen time, last written event and rules version of sensors, changed on every event batch.
+        // Taken after registry_mutex when both are needed
+        pub state_mutex: Arc<Mutex<String>>,
+        // users and lockout files are replaced as a whole, the lock is held from reading to saving
+        pub users_mutex: Arc<Mutex<()>>,
+        pub lockout_mutex: Arc<Mutex<()>>,
+    }
+
+    #[cfg(test)]
+    impl FileMutexes {
+        // every file is a new one in temp directory, name keeps tests apart
+        pub fn for_tests(name: &str) -> FileMutexes {
+            let open = |file: &str| {
+                let path = std::env::temp_dir().join(format!(
+                    "rsoc_{}_{}_{}.txt",
+                    name,
+                    file,
+                    std::process::id()
+                ));
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .unwrap();
+                Arc::new(Mutex::new(file))
+            };
+            let replaced = |file: &str| {
+                let path = std::env::temp_dir().join(format!(
+                    "rsoc_{}_{}_{}.txt",
+                    name,
+                    file,
+                    std::process::id()
+                ));
+                let _ = std::fs::remove_file(&path);
+                Arc::new(Mutex::new(path.to_string_lossy().to_string()))
+            };
+
+            FileMutexes {
+                audit_mutex: open("audit"),
+                event_mutex: open("events"),
+                rules_mutex: open("rules"),
+                rules_history_mutex: replaced("rules_history"),
+                registry_mutex: replaced("sensors"),
+                tokens_mutex: replaced("tokens"),
+                state_mutex: replaced("sensor_state"),
+                users_mutex: Arc::new(Mutex::new(())),
+                lockout_mutex: Arc::new(Mutex::new(())),
+            }
+        }
     }
 }
 
@@ -50,3 +333,80 @@ impl fmt::Display for AuditEventType {
         write!(f, "{:?}", self)
     }
 }
+
+// console actions allowed to a role, role -> permissions matrix is kept in roles file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    EventsView,
+    SensorsView,
+    SensorsControl,
+    SensorsEnroll,
+    AuditView,
+    AuditControl,
+    RulesView,
+    RulesEdit,
+    UsersView,
+    UsersManage,
+    // run sensors: operator of sensor config is checked on connection
+    SensorOperator,
+}
+
+impl Permission {
+    // sensor_operator is not among them: sensors log on without second factor,
+    // so console administrators must not be sensor operators by default
+    pub const CONSOLE: [Permission; 10] = [
+        Permission::EventsView,
+        Permission::SensorsView,
+        Permission::SensorsControl,
+        Permission::SensorsEnroll,
+        Permission::AuditView,
+        Permission::AuditControl,
+        Permission::RulesView,
+        Permission::RulesEdit,
+        Permission::UsersView,
+        Permission::UsersManage,
+    ];
+
+    pub const ALL: [Permission; 11] = [
+        Permission::EventsView,
+        Permission::SensorsView,
+        Permission::SensorsControl,
+        Permission::SensorsEnroll,
+        Permission::AuditView,
+        Permission::AuditControl,
+        Permission::RulesView,
+        Permission::RulesEdit,
+        Permission::UsersView,
+        Permission::UsersManage,
+        Permission::SensorOperator,
+    ];
+
+    // name in roles file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::EventsView => "events_view",
+            Permission::SensorsView => "sensors_view",
+            Permission::SensorsControl => "sensors_control",
+            Permission::SensorsEnroll => "sensors_enroll",
+            Permission::AuditView => "audit_view",
+            Permission::AuditControl => "audit_control",
+            Permission::RulesView => "rules_view",
+            Permission::RulesEdit => "rules_edit",
+            Permission::UsersView => "users_view",
+            Permission::UsersManage => "users_manage",
+            Permission::SensorOperator => "sensor_operator",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Permission> {
+        Permission::ALL
+            .into_iter()
+            .find(|permission| permission.name() == name)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
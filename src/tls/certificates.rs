@@ -0,0 +1,153 @@
+use chrono::{Datelike, Local};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+const CA_COMMON_NAME: &str = "rSOC Certificate Authority";
+const CA_VALIDITY_YEARS: i32 = 10;
+const CERT_VALIDITY_YEARS: i32 = 2;
+
+// organizational unit of client certificates, each listener accepts only its own:
+// sensor certificate can't open console and console certificate can't connect as sensor
+pub const SENSOR_UNIT: &str = "rSOC sensor";
+pub const CONSOLE_UNIT: &str = "rSOC console";
+
+fn to_io_error(e: rcgen::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn set_validity(params: &mut CertificateParams, years: i32) {
+    let today = Local::now().date_naive();
+    params.not_before = date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+    // day is capped to avoid February 29 in a non-leap year
+    params.not_after = date_time_ymd(
+        today.year() + years,
+        today.month() as u8,
+        today.day().min(28) as u8,
+    );
+}
+
+fn write_pem(path: &str, pem: &str, private: bool) -> io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(path)?;
+    file.write_all(pem.as_bytes())
+}
+
+fn load_ca(ca_cert_file: &str, ca_key_file: &str) -> io::Result<(Certificate, KeyPair)> {
+    let ca_cert_pem = fs::read_to_string(ca_cert_file)?;
+    let ca_key_pem = fs::read_to_string(ca_key_file)?;
+    let ca_key = KeyPair::from_pem(&ca_key_pem).map_err(to_io_error)?;
+    let ca_params = CertificateParams::from_ca_cert_pem(&ca_cert_pem).map_err(to_io_error)?;
+    // re-signing gives the same subject and key, which is all that is needed to issue certificates
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(to_io_error)?;
+
+    Ok((ca_cert, ca_key))
+}
+
+// Creates local CA and the management server certificate signed by it.
+// server_names - DNS names and IP addresses sensors use to reach the server.
+pub fn create_ca(
+    ca_cert_file: &str,
+    ca_key_file: &str,
+    server_cert_file: &str,
+    server_key_file: &str,
+    server_hostname: &str,
+    server_names: &[String],
+) -> io::Result<()> {
+    if Path::new(ca_cert_file).exists() || Path::new(ca_key_file).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "CA already exists ({}). Remove it manually to create a new one",
+                ca_cert_file
+            ),
+        ));
+    }
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).map_err(to_io_error)?;
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, CA_COMMON_NAME);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    set_validity(&mut ca_params, CA_VALIDITY_YEARS);
+
+    let ca_key = KeyPair::generate().map_err(to_io_error)?;
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(to_io_error)?;
+
+    let mut server_params = CertificateParams::new(server_names.to_vec()).map_err(to_io_error)?;
+    server_params
+        .distinguished_name
+        .push(DnType::CommonName, server_hostname);
+    server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    set_validity(&mut server_params, CERT_VALIDITY_YEARS);
+
+    let server_key = KeyPair::generate().map_err(to_io_error)?;
+    let server_cert = server_params
+        .signed_by(&server_key, &ca_cert, &ca_key)
+        .map_err(to_io_error)?;
+
+    write_pem(ca_key_file, &ca_key.serialize_pem(), true)?;
+    write_pem(ca_cert_file, &ca_cert.pem(), false)?;
+    write_pem(server_key_file, &server_key.serialize_pem(), true)?;
+    write_pem(server_cert_file, &server_cert.pem(), false)?;
+
+    Ok(())
+}
+
+// Issues client certificate for sensor or remote console. Common name of certificate is the sensor
+// or console name, unit - SENSOR_UNIT or CONSOLE_UNIT. Returns paths of certificate and key files.
+pub fn issue_client_certificate(
+    ca_cert_file: &str,
+    ca_key_file: &str,
+    client_name: &str,
+    unit: &str,
+    out_dir: &str,
+) -> io::Result<(String, String)> {
+    let (ca_cert, ca_key) = load_ca(ca_cert_file, ca_key_file)?;
+
+    let mut params = CertificateParams::new(Vec::<String>::new()).map_err(to_io_error)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, client_name);
+    params
+        .distinguished_name
+        .push(DnType::OrganizationalUnitName, unit);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    set_validity(&mut params, CERT_VALIDITY_YEARS);
+
+    let key = KeyPair::generate().map_err(to_io_error)?;
+    let cert = params
+        .signed_by(&key, &ca_cert, &ca_key)
+        .map_err(to_io_error)?;
+
+    let cert_file = format!("{}/{}.crt", out_dir, client_name);
+    let key_file = format!("{}/{}.key", out_dir, client_name);
+    write_pem(&key_file, &key.serialize_pem(), true)?;
+    write_pem(&cert_file, &cert.pem(), false)?;
+
+    Ok((cert_file, key_file))
+}
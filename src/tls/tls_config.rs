@@ -0,0 +1,182 @@
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::sensor_handler::sensor_listener::UNIX_PREFIX;
+
+// certificate files in PEM format
+pub struct TlsFiles {
+    pub ca_cert: String,
+    pub cert: String,
+    pub key: String,
+}
+
+fn pem_error(path: &str, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Can't load {}: {}", path, e),
+    )
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .map_err(|e| pem_error(path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| pem_error(path, e))
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+// Server side: every sensor must show a certificate issued by our CA
+pub fn server_tls_acceptor(files: &TlsFiles) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(default_provider());
+    let roots = Arc::new(load_roots(&files.ca_cert)?);
+    let client_verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(load_certs(&files.cert)?, load_key(&files.key)?)
+        .map_err(tls_error)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Sensor side: trust only our CA and present own certificate
+pub fn sensor_tls_connector(files: &TlsFiles) -> io::Result<TlsConnector> {
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(load_roots(&files.ca_cert)?)
+        .with_client_auth_cert(load_certs(&files.cert)?, load_key(&files.key)?)
+        .map_err(tls_error)?;
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// TCP or unix socket connection under TLS
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type ServerTransport = Box<dyn Transport>;
+
+// address - "host:port" or "[IPv6]:port", host must be present in server certificate.
+// "unix:/path/to/socket" - local server, its certificate must contain "localhost".
+pub async fn connect_to_server(
+    address: &str,
+    connector: &TlsConnector,
+) -> io::Result<TlsStream<ServerTransport>> {
+    let (host, transport): (&str, ServerTransport) = match address.strip_prefix(UNIX_PREFIX) {
+        Some(path) => ("localhost", connect_unix(path).await?),
+        None => {
+            let host = match address.rsplit_once(':') {
+                Some((host, _port)) => host.trim_start_matches('[').trim_end_matches(']'),
+                None => address,
+            };
+            (host, Box::new(TcpStream::connect(address).await?))
+        }
+    };
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    connector.connect(server_name, transport).await
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<ServerTransport> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &str) -> io::Result<ServerTransport> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("unix socket {} is not supported on this platform", path),
+    ))
+}
+
+// (common name, full subject) of the first peer certificate.
+// unit - organizational unit the certificate must be issued for, SENSOR_UNIT or CONSOLE_UNIT
+pub fn peer_identity(certs: Option<&[CertificateDer<'_>]>, unit: &str) -> Result<(String, String), String> {
+    let cert_der = certs
+        .and_then(|certs| certs.first())
+        .ok_or("no client certificate".to_string())?;
+    let (_, cert) = X509Certificate::from_der(cert_der.as_ref())
+        .map_err(|_| "client certificate can't be parsed".to_string())?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .ok_or("no valid certificate subject".to_string())?
+        .to_string();
+    let issued_for = cert
+        .subject()
+        .iter_organizational_unit()
+        .next()
+        .and_then(|ou| ou.as_str().ok());
+    if issued_for != Some(unit) {
+        return Err(format!(
+            "certificate is issued for {}, not for {}",
+            issued_for.unwrap_or("unknown unit"),
+            unit
+        ));
+    }
+
+    Ok((common_name, cert.subject().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::certificates::{create_ca, issue_client_certificate, CONSOLE_UNIT, SENSOR_UNIT};
+
+    #[test]
+    fn client_certificate_is_accepted_only_for_its_unit() {
+        let dir = std::env::temp_dir().join(format!("rsoc_pki_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = |file: &str| dir.join(file).to_string_lossy().to_string();
+        create_ca(&path("ca.crt"), &path("ca.key"), &path("server.crt"), &path("server.key"), "server", &[]).unwrap();
+
+        let out_dir = dir.to_string_lossy().to_string();
+        let (sensor_cert, _) =
+            issue_client_certificate(&path("ca.crt"), &path("ca.key"), "Zarya-1", SENSOR_UNIT, &out_dir).unwrap();
+        let (console_cert, _) =
+            issue_client_certificate(&path("ca.crt"), &path("ca.key"), "console-1", CONSOLE_UNIT, &out_dir).unwrap();
+        let sensor_cert = load_certs(&sensor_cert).unwrap();
+        let console_cert = load_certs(&console_cert).unwrap();
+
+        let (name, _) = peer_identity(Some(&sensor_cert), SENSOR_UNIT).unwrap();
+        assert_eq!(name, "Zarya-1");
+        assert!(peer_identity(Some(&sensor_cert), CONSOLE_UNIT).is_err());
+        assert!(peer_identity(Some(&console_cert), SENSOR_UNIT).is_err());
+        assert!(peer_identity(Some(&console_cert), CONSOLE_UNIT).is_ok());
+        assert!(peer_identity(None, SENSOR_UNIT).is_err());
+    }
+}